@@ -1,42 +1,810 @@
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures::{Async, Future, Poll, Stream};
+use ring::rand::SystemRandom;
 use tokio_core::net::UdpSocket;
-use tokio_core::reactor::{Core, Handle};
+use tokio_core::reactor::{Core, Handle, Interval};
 
-use super::{Client, State, new_buf};
+use super::{Client, ClientId, ClientToken, State, new_buf, new_token};
 use super::configuration::ClientConfiguration;
+use super::packet::{AKARIN_DATA_OFFSET, AkarinPacket, Message, PROTOCOL_VERSION, Padding};
+use super::pmtu::{MtuSearch, PROBE_INTERVAL, reject_packet, tun_mtu};
+use super::session::{DEFAULT_REPLAY_WINDOW, HANDSHAKE_PROLOGUE, RekeyPolicy, Session, handshake_psk};
+use super::user::{DEFAULT_USER, UserId};
+use common::buf::PacketBuf;
 use common::error::*;
-use crypto::Crypto;
+use crypto::{Ciphers, Crypto, HANDSHAKE_CIPHER};
+use crypto::noise::{Handshake, Pattern};
+use transport::{Endpoint, Transport, TransportMode};
+use transport::mtu::{DEFAULT_PATH_MTU, is_too_large, path_mtu, set_dont_fragment};
+use transport::network::clamp_mss;
+use transport::tcp::{TcpClientTransport, is_closed};
+use transport::udp::UdpTransport;
+use tun::{self, Tun};
 use tun::os::tokio::Device;
 
+const REGISTER_INTERVAL: u64 = 1;
+const REGISTER_ATTEMPTS: u32 = 5;
+/// Seconds between two keepalives, short enough for the server and most NATs not to forget the client.
+const DEFAULT_KEEPALIVE_INTERVAL: u64 = 25;
+const DEFAULT_KEEPALIVE_MISSES: u32 = 3;
+
 #[derive(Debug)]
 pub struct AkarinClient<'a> {
+    crypto: &'a Crypto,
+
+    configuration: ClientConfiguration,
+    tun_configuration: tun::Configuration,
+}
+
+impl<'a> AkarinClient<'a> {
+    pub fn new<'d>(crypto: &'a Crypto, configuration: &'d ClientConfiguration,
+                   tun_configuration: &'d tun::Configuration)
+                   -> Self {
+        AkarinClient {
+            crypto,
+            configuration: configuration.clone(),
+            tun_configuration: tun_configuration.clone(),
+        }
+    }
+}
+
+impl<'a> Client for AkarinClient<'a> {
+    fn connect(self, mut core: Core, handle: Handle) -> Result<()> {
+        let server_address = match self.configuration.server_address {
+            Some(addr) => addr,
+            None => return Err(ErrorKind::MissingServerAddress.into()),
+        };
+
+        let tun = Device::new(tun::create(&self.tun_configuration)?, &handle)?;
+
+        let tunnel = ClientTunnel::new(tun, self.crypto, server_address, &self.configuration, &handle)?;
+        info!("Connecting to server: {}", tunnel.server);
+        match core.run(tunnel) {
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => Err(ErrorKind::ServerUnreachable.into()),
+            Err(e) => Err(e.into()),
+            Ok(()) => Ok(()),
+        }
+    }
+}
+
+/// A UDP socket for the server. It is left unconnected so that its source address follows the routes of the host when
+/// it moves to another network, and the server follows the client as it does.
+///
+/// Datagrams are not fragmented when the MTU of the path is probed, so that the probes are as large as they look.
+fn connect_udp(server: SocketAddr, dont_fragment: bool, handle: &Handle) -> io::Result<(Box<Transport>, Endpoint)> {
+    let local_address = match server {
+        SocketAddr::V4(_) => SocketAddr::from_str("0.0.0.0:0").unwrap(),
+        SocketAddr::V6(_) => SocketAddr::from_str("[::]:0").unwrap(),
+    };
+    let udp = UdpSocket::bind(&local_address, handle)?;
+    if dont_fragment {
+        set_dont_fragment(udp.as_raw_fd(), server.is_ipv6())?;
+    }
+    Ok((Box::new(UdpTransport::new(udp)), Endpoint::Udp(server)))
+}
+
+fn connect_tcp(server: SocketAddr, handle: &Handle) -> io::Result<(Box<Transport>, Endpoint)> {
+    Ok((Box::new(TcpClientTransport::connect(&server, handle)), Endpoint::Tcp(server)))
+}
+
+/// Start a Noise handshake with the server, returns our end of it and its first message.
+fn start_handshake(crypto: &Crypto, configuration: &ClientConfiguration) -> Result<(Handshake, Vec<u8>)> {
+    // A client which knows the key of its server authenticates it, and itself by its own key.
+    let psk = handshake_psk(crypto);
+    let mut initiator = match configuration.server_key {
+        Some(server_key) => {
+            Handshake::initiator(Pattern::IKpsk2, &psk, HANDSHAKE_PROLOGUE, configuration.private_key.clone(),
+                                 Some(server_key))?
+        }
+        None => Handshake::initiator(Pattern::NNpsk0, &psk, HANDSHAKE_PROLOGUE, None, None)?,
+    };
+    let mut message = vec![initiator.pattern().id()];
+    message.extend(initiator.write_message(&SystemRandom::new())?);
+    Ok((initiator, message))
+}
+
+struct ClientTunnel<'a> {
     tun: Device,
-    udp: UdpSocket,
+    transport: Box<Transport>,
+    server: Endpoint,
+    // Whether to try TCP once the server does not answer over UDP.
+    fallback: bool,
+    handle: Handle,
 
     crypto: &'a Crypto,
+    configuration: ClientConfiguration,
+
+    user: UserId,
+    client_id: ClientId,
+    token: ClientToken,
+    cookie: ClientToken,
+    // Started once the server has assigned us an id.
+    session: Option<Session>,
+    window_size: u64,
+    rekey_policy: RekeyPolicy,
+    padding: Padding,
+    // Ids of the ciphers offered to the server, in order of preference.
+    ciphers: Vec<u8>,
+    // Our end of the Noise handshake, and its first message which every `Hello` carries.
+    handshake: Option<Handshake>,
+    handshake_message: Vec<u8>,
+    // Whether the server must answer the handshake, either to be forward secret or to prove its key.
+    handshake_required: bool,
 
-    tun_buf: Vec<u8>,
-    udp_buf: Vec<u8>,
+    // The MTU of the tun, and the MTU of the path it is derived from unless it is configured.
+    mtu: usize,
+    path_mtu: Option<usize>,
+    mtu_probing: bool,
+    mss_clamping: bool,
+    // Started once registered to a server which answers probes.
+    mtu_search: Option<MtuSearch>,
+    probe_timer: Option<Interval>,
+
+    register_timer: Interval,
+    register_attempts: u32,
+
+    // Started once registered to a server which answers keepalives.
+    keepalive_timer: Option<Interval>,
+    keepalive_interval: u64,
+    keepalive_misses: u32,
+    // Keepalives sent since the server has last been heard from.
+    missed_keepalives: u32,
+
+    // Pending datagrams and packets are kept in the buffer they have been read into, until they are written out.
+    tun_buf: PacketBuf,
+    transport_buf: PacketBuf,
+
+    to_transport: bool,
+    to_tun: bool,
 
     state: State,
 }
 
-impl<'a> AkarinClient<'a> {
-    fn new<'d>(tun: Device, crypto: &'a Crypto, udp: UdpSocket, configuration: &'d ClientConfiguration) -> Self {
-        AkarinClient {
-            tun,
-            crypto,
-            udp,
+impl<'a> ClientTunnel<'a> {
+    fn new<'d>(mut tun: Device, crypto: &'a Crypto, server_address: SocketAddr,
+               configuration: &'d ClientConfiguration, handle: &Handle)
+               -> Result<Self> {
+        let mode = configuration.transport.unwrap_or(TransportMode::Udp);
+        let mtu_probing = configuration.mtu_probing.unwrap_or(true);
+        let (transport, server) = match mode {
+            TransportMode::Tcp => connect_tcp(server_address, handle)?,
+            TransportMode::Udp | TransportMode::Auto => connect_udp(server_address, mtu_probing, handle)?,
+        };
+
+        // Until a cipher is agreed on, the tun fits the packets of any of those offered.
+        let (mtu, path_mtu) = match configuration.mtu {
+            Some(mtu) => (mtu as usize, None),
+            None => {
+                let path_mtu = path_mtu(&server_address).unwrap_or_else(|e| {
+                    warn!("Failed to get the MTU of the path to {}, assuming {}: {}", server_address, DEFAULT_PATH_MTU,
+                          e);
+                    DEFAULT_PATH_MTU
+                });
+                let mtu = tun_mtu(path_mtu, configuration.supported_ciphers(), &server);
+                info!("MTU of the tun: {}", mtu);
+                tun.get_mut().set_mtu(mtu as i32)?;
+                (mtu, Some(path_mtu))
+            }
+        };
+
+        let (initiator, handshake_message) = start_handshake(crypto, configuration)?;
+        Ok(ClientTunnel {
+               tun,
+               transport,
+               server,
+               fallback: mode == TransportMode::Auto,
+               handle: handle.clone(),
+               crypto,
+               configuration: configuration.clone(),
+
+               user: configuration.user.unwrap_or(DEFAULT_USER),
+               client_id: 0,
+               token: 0,
+               cookie: new_token(&SystemRandom::new())?,
+               session: None,
+               window_size: configuration.replay_window.unwrap_or(DEFAULT_REPLAY_WINDOW),
+               rekey_policy: configuration.rekey_policy(),
+               padding: configuration.padding.unwrap_or_default(),
+               ciphers: configuration.supported_ciphers().iter().map(|cipher| cipher.id()).collect(),
+               handshake: Some(initiator),
+               handshake_message,
+               handshake_required: configuration.forward_secrecy.unwrap_or(false) ||
+                                   configuration.server_key.is_some(),
+
+               mtu,
+               path_mtu,
+               mtu_probing,
+               mss_clamping: configuration.mss_clamping.unwrap_or(false),
+               mtu_search: None,
+               probe_timer: None,
+
+               register_timer: Interval::new(Duration::from_secs(REGISTER_INTERVAL), handle)?,
+               register_attempts: 0,
+
+               keepalive_timer: None,
+               keepalive_interval: configuration.keepalive_interval.unwrap_or(DEFAULT_KEEPALIVE_INTERVAL),
+               keepalive_misses: configuration.keepalive_misses.unwrap_or(DEFAULT_KEEPALIVE_MISSES),
+               missed_keepalives: 0,
+
+               tun_buf: new_buf(mtu),
+               transport_buf: new_buf(mtu),
+
+               to_transport: false,
+               to_tun: false,
+
+               state: State::Down,
+           })
+    }
+
+    fn send_register(&mut self) -> io::Result<()> {
+        if self.register_attempts >= REGISTER_ATTEMPTS {
+            if !self.fallback {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no reply from server"));
+            }
+            // Start over with a new cookie, a server which has heard us over UDP registers us again.
+            info!("No reply from server over UDP, trying TCP");
+            let (transport, server) = connect_tcp(self.server.address(), &self.handle)?;
+            self.transport = transport;
+            self.server = server;
+            self.fallback = false;
+            self.register_attempts = 0;
+            let cookie = new_token(&SystemRandom::new());
+            self.cookie = cookie.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        }
+        self.register_attempts += 1;
+
+        // Until the server assigns us an id, the token tells the server which user we are.
+        let hello = Message::Hello {
+                        cookie: self.cookie,
+                        version: PROTOCOL_VERSION,
+                        ciphers: &self.ciphers,
+                        handshake: &self.handshake_message,
+                    }
+                    .encode();
+        let datagram = AkarinPacket::seal(0, self.user as ClientToken, 0, 0, &hello, self.crypto)
+                           .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        let mut sent = self.transport.send_to(&datagram, &self.server);
+        let closed = match sent {
+            Err(ref e) => e.kind() == io::ErrorKind::ConnectionRefused || is_closed(e),
+            Ok(()) => false,
+        };
+        // A connection the server has closed or refused is opened again, the `Hello` waits in it until it is accepted.
+        if let (Endpoint::Tcp(_), true) = (self.server, closed) {
+            self.reconnect()?;
+            sent = self.transport.send_to(&datagram, &self.server);
+        }
+        match sent {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            // A server not listening yet, or not over UDP, is asked again until we run out of attempts.
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused || is_closed(e) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Keep asking the server for an id until it replies or we run out of attempts.
+    fn poll_register(&mut self) -> io::Result<()> {
+        if self.register_attempts == 0 {
+            self.send_register()?;
+        }
+        while let Async::Ready(Some(())) = self.register_timer.poll()? {
+            self.send_register()?;
+        }
+
+        loop {
+            self.transport_buf.clear(0);
+            let n = match self.transport.recv_from(self.transport_buf.tail_mut()) {
+                Ok((n, endpoint)) if endpoint == self.server => n,
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                // The next attempt connects again.
+                Err(ref e) if is_closed(e) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            self.transport_buf.extend(n).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+            let (client_id, token, version, cipher, address, handshake) = {
+                let packet = match AkarinPacket::decode(self.transport_buf.as_slice(), self.crypto.nonce_len(),
+                                                        self.crypto.tag_len()) {
+                    Ok(packet) => packet,
+                    Err(_) => continue,
+                };
+                let (_, message) = match packet.open(self.crypto) {
+                    Ok(opened) => opened,
+                    Err(_) => continue,
+                };
+                match Message::decode(&message) {
+                    Ok(Message::Welcome {
+                           cookie,
+                           version,
+                           cipher,
+                           address,
+                           handshake,
+                           address_v6,
+                       }) if cookie == self.cookie => {
+                        let cipher = match Ciphers::from_id(cipher) {
+                            Some(cipher) if self.ciphers.contains(&cipher.id()) && version <= PROTOCOL_VERSION => {
+                                cipher
+                            }
+                            _ => {
+                                let reason = format!("server chose version {} and cipher {:#04x}", version, cipher);
+                                return Err(io::Error::new(io::ErrorKind::Other, reason));
+                            }
+                        };
+                        (packet.client_id, packet.token, version, cipher, (address, address_v6), handshake.to_vec())
+                    }
+                    Ok(Message::Reject {
+                           cookie,
+                           code,
+                           min_version,
+                           max_version,
+                       }) if cookie == self.cookie => {
+                        let reason = format!("rejected by server: {}, the server speaks versions {} to {}",
+                                             code,
+                                             min_version,
+                                             max_version);
+                        return Err(io::Error::new(io::ErrorKind::Other, reason));
+                    }
+                    _ => continue,
+                }
+            };
+
+            let session = if handshake.is_empty() {
+                if self.handshake_required {
+                    let reason = "server does not support handshakes, upgrade it or unset `forward_secrecy` and \
+                                  `server_key`";
+                    return Err(io::Error::new(io::ErrorKind::Other, reason));
+                }
+                warn!("Server does not support forward secrecy, the session is derived from the password");
+                Session::start(self.crypto, cipher, client_id, token, self.window_size, self.rekey_policy)
+            } else {
+                // Only the first `Welcome` is read, the handshake is over once it is.
+                let mut initiator = self.handshake.take().expect("handshake finished twice");
+                initiator.read_message(&handshake)
+                         .and_then(|_| initiator.split())
+                         .and_then(|key| HANDSHAKE_CIPHER.with_key(key))
+                         .and_then(|root| {
+                                       Session::start(&*root, cipher, client_id, token, self.window_size,
+                                                      self.rekey_policy)
+                                   })
+            };
+            let mut session = session.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            // Older servers do not understand padded messages.
+            if version >= 3 {
+                session.set_padding(self.padding);
+            }
+
+            info!("Registered to server as {}, address: {}, cipher: {}", client_id, address.0, cipher.name());
+            self.tun
+                .get_mut()
+                .set_address(address.0)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            if let Some(address_v6) = address.1 {
+                info!("Registered IPv6 address: {}", address_v6);
+                // The tun keeps the address of a previous registration to the same server.
+                let tun = self.tun.get_mut();
+                let known = tun.addresses_v6().map(|addresses| addresses.contains(&address_v6));
+                if !known.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))? {
+                    tun.add_address_v6(address_v6).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                }
+            }
 
-            tun_buf: new_buf(configuration.mtu.unwrap_or(1432) as usize),
-            udp_buf: new_buf(configuration.mtu.unwrap_or(1432) as usize),
+            // Older servers do not answer keepalives, they would look dead.
+            if version >= 4 && self.keepalive_interval > 0 {
+                let interval = Duration::from_secs(self.keepalive_interval);
+                self.keepalive_timer = Some(Interval::new(interval, &self.handle)?);
+            }
+            if let Some(path_mtu) = self.path_mtu {
+                let mtu = tun_mtu(path_mtu, &[cipher], &self.server);
+                self.resize_tun(mtu)?;
+            }
+            // Probes are no use over TCP, which carries packets of any size.
+            if let (true, Endpoint::Udp(_)) = (version >= 5 && self.mtu_probing, self.server) {
+                self.mtu_search = Some(MtuSearch::new(self.mtu));
+                self.probe_timer = Some(Interval::new(Duration::from_secs(PROBE_INTERVAL), &self.handle)?);
+            }
+            self.missed_keepalives = 0;
+            self.session = Some(session);
+            self.client_id = client_id;
+            self.token = token;
+            self.state = State::Running;
+            return Ok(());
+        }
+    }
+
+    /// Send a keepalive every interval, and register again once the server has missed `keepalive_misses` of them in
+    /// a row: it has restarted and forgotten us, or it is gone and registering fails.
+    fn poll_keepalive(&mut self) -> io::Result<()> {
+        loop {
+            match self.keepalive_timer {
+                Some(ref mut timer) => {
+                    if let Async::NotReady = timer.poll()? {
+                        return Ok(());
+                    }
+                }
+                None => return Ok(()),
+            }
+
+            if self.missed_keepalives >= self.keepalive_misses {
+                warn!("No reply from server to {} keepalives, registering again", self.missed_keepalives);
+                return self.restart();
+            }
+            self.missed_keepalives += 1;
+
+            let datagram = match self.session {
+                Some(ref mut session) => session.seal_message(self.client_id, self.token, &Message::Keepalive),
+                None => return Ok(()),
+            };
+            let datagram = datagram.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            match self.transport.send_to(&datagram, &self.server) {
+                Ok(()) => {}
+                // A keepalive which can not be sent is as good as one lost on the way.
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::ConnectionRefused || is_closed(e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Probe the MTU of the path every `PROBE_INTERVAL`, lowering the MTU of the tun when probes get lost.
+    fn poll_probe(&mut self) -> io::Result<()> {
+        loop {
+            match self.probe_timer {
+                Some(ref mut timer) => {
+                    if let Async::NotReady = timer.poll()? {
+                        return Ok(());
+                    }
+                }
+                None => return Ok(()),
+            }
+
+            let (probe, mtu) = match self.mtu_search {
+                Some(ref mut search) => (search.next_probe(), search.mtu()),
+                None => return Ok(()),
+            };
+            self.resize_tun(mtu)?;
+
+            let datagram = match self.session {
+                Some(ref mut session) => {
+                    session.seal_message(self.client_id, self.token, &Message::Probe { mtu: probe as u16 })
+                }
+                None => return Ok(()),
+            };
+            let datagram = datagram.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            match self.transport.send_to(&datagram, &self.server) {
+                Ok(()) => {}
+                // A probe too large for the host is as good as one lost on the way.
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::ConnectionRefused || is_closed(e) ||
+                              is_too_large(e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// The host has refused to send a packet larger than the MTU of the path to the server, which it has learned
+    /// from the network.
+    fn lower_mtu(&mut self) -> io::Result<()> {
+        let cipher = match self.session {
+            Some(ref session) => session.crypto().cipher(),
+            None => return Ok(()),
+        };
+        let mtu = match path_mtu(&self.server.address()) {
+            Ok(path_mtu) => tun_mtu(path_mtu, &[cipher], &self.server),
+            Err(e) => {
+                warn!("Failed to get the MTU of the path to {}: {}", self.server, e);
+                return Ok(());
+            }
+        };
+        if let Some(ref mut search) = self.mtu_search {
+            search.lower(mtu);
+        }
+        if mtu < self.mtu {
+            self.resize_tun(mtu)?;
+        }
+        Ok(())
+    }
+
+    fn resize_tun(&mut self, mtu: usize) -> io::Result<()> {
+        if mtu == self.mtu {
+            return Ok(());
+        }
+        info!("MTU of the tun: {}, was {}", mtu, self.mtu);
+        self.tun.get_mut().set_mtu(mtu as i32).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    /// Forget the registration and go `Down`, to register as a new client.
+    fn restart(&mut self) -> io::Result<()> {
+        self.reconnect()?;
+        let started = start_handshake(self.crypto, &self.configuration);
+        let (initiator, handshake_message) = started.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        let cookie = new_token(&SystemRandom::new());
+        self.cookie = cookie.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        self.handshake = Some(initiator);
+        self.handshake_message = handshake_message;
+        self.session = None;
+        self.client_id = 0;
+        self.token = 0;
+        self.register_timer = Interval::new(Duration::from_secs(REGISTER_INTERVAL), &self.handle)?;
+        self.register_attempts = 0;
+        self.keepalive_timer = None;
+        self.mtu_search = None;
+        self.probe_timer = None;
+        self.to_transport = false;
+        self.to_tun = false;
+        self.state = State::Down;
+        Ok(())
+    }
+
+    /// Open a new connection to a server reached over TCP, the one before fails every call once it has closed.
+    fn reconnect(&mut self) -> io::Result<()> {
+        if let Endpoint::Tcp(address) = self.server {
+            let (transport, server) = connect_tcp(address, &self.handle)?;
+            self.transport = transport;
+            self.server = server;
+        }
+        Ok(())
+    }
+
+    /// Send packets read from the tun to the server.
+    ///
+    /// Returns `Ok(true)` if any progress has been made.
+    fn poll_tun(&mut self) -> io::Result<bool> {
+        if self.to_transport {
+            match self.transport.send_to(self.tun_buf.as_slice(), &self.server) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused || is_closed(e) => {
+                    warn!("Failed to send to server: {}", e);
+                }
+                Err(ref e) if is_too_large(e) => {
+                    debug!("Dropping datagram of {} bytes, larger than the path to the server", self.tun_buf.len());
+                    self.lower_mtu()?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.to_transport = false;
+
+        // The packet lands after the room of its header, so that it is sealed where it is.
+        self.tun_buf.clear(AKARIN_DATA_OFFSET);
+        let n = match self.tun.read(self.tun_buf.tail_mut()) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        self.tun_buf.extend(n).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        // Packets read before the MTU of the tun has been lowered may not fit the path any more.
+        if n > self.mtu {
+            reject_packet(&mut self.tun, self.tun_buf.as_slice(), self.mtu)?;
+            return Ok(true);
+        }
+        if self.mss_clamping {
+            clamp_mss(self.tun_buf.as_mut_slice(), self.mtu);
+        }
+
+        let session = match self.session {
+            Some(ref mut session) => session,
+            None => return Ok(true),
+        };
+        let (client_id, token, tun_buf) = (self.client_id, self.token, &mut self.tun_buf);
+        let padding = session.padding();
+        match Message::wrap_data(tun_buf, padding).and_then(|_| session.seal_in_place(client_id, token, tun_buf)) {
+            Ok(()) => self.to_transport = true,
+            Err(e) => warn!("Failed to encrypt packet: {}", e),
+        }
+        Ok(true)
+    }
+
+    /// Write packets received from the server to the tun.
+    ///
+    /// Returns `Ok(true)` if any progress has been made.
+    fn poll_transport(&mut self) -> io::Result<bool> {
+        if self.to_tun {
+            match self.tun.write(self.transport_buf.as_slice()) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        self.to_tun = false;
 
-            state: State::Down,
+        self.transport_buf.clear(0);
+        let n = match self.transport.recv_from(self.transport_buf.tail_mut()) {
+            Ok((n, endpoint)) if endpoint == self.server => n,
+            Ok((_, endpoint)) => {
+                debug!("Dropping datagram from {}, not the server", endpoint);
+                return Ok(true);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                warn!("Failed to receive from server: {}", e);
+                return Ok(true);
+            }
+            // The server has restarted or forgotten us, it is only heard from again over a new connection.
+            Err(ref e) if is_closed(e) => {
+                warn!("Connection to server closed, registering again: {}", e);
+                self.restart()?;
+                return Ok(true);
+            }
+            Err(e) => return Err(e),
+        };
+        self.transport_buf.extend(n).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+        match AkarinPacket::peek(self.transport_buf.as_slice()) {
+            Ok((client_id, token, _)) if client_id == self.client_id && token == self.token => {}
+            Ok((client_id, _, _)) => {
+                debug!("Dropping datagram for client {}", client_id);
+                return Ok(true);
+            }
+            Err(e) => {
+                debug!("Dropping datagram: {}", e);
+                return Ok(true);
+            }
+        }
+
+        let session = match self.session {
+            Some(ref mut session) => session,
+            None => return Ok(true),
+        };
+        if let Err(e) = session.open_in_place(&mut self.transport_buf) {
+            debug!("Dropping datagram: {}", e);
+            return Ok(true);
+        }
+        // Any authentic packet tells that the server is alive, a keepalive tells nothing else.
+        self.missed_keepalives = 0;
+        match Message::decode(self.transport_buf.as_slice()) {
+            Ok(Message::Keepalive) => return Ok(true),
+            Ok(Message::ProbeAck { mtu }) => {
+                let mtu = match self.mtu_search {
+                    Some(ref mut search) => {
+                        search.acknowledge(mtu as usize);
+                        search.mtu()
+                    }
+                    None => return Ok(true),
+                };
+                self.resize_tun(mtu)?;
+                return Ok(true);
+            }
+            _ => {}
         }
+
+        match Message::unwrap_data(&mut self.transport_buf) {
+            Ok(()) => self.to_tun = true,
+            Err(e) => debug!("Dropping datagram: {}", e),
+        }
+        // Both ends of a connection through the tunnel offer an MSS which fits it, whichever end has clamping on.
+        if self.to_tun && self.mss_clamping {
+            clamp_mss(self.transport_buf.as_mut_slice(), self.mtu);
+        }
+        Ok(true)
     }
 }
 
-impl<'a> Client for AkarinClient<'a> {
-    fn connect(self, mut core: Core, handle: Handle) -> Result<()> {
-        unimplemented!()
+impl<'a> Future for ClientTunnel<'a> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let State::Down = self.state {
+                self.poll_register()?;
+                if let State::Down = self.state {
+                    return Ok(Async::NotReady);
+                }
+            }
+
+            self.poll_keepalive()?;
+            if let State::Down = self.state {
+                continue;
+            }
+            self.poll_probe()?;
+
+            loop {
+                self.transport.flush()?;
+                // Poll both directions in turn so that neither of them can starve the other.
+                let tun_progress = self.poll_tun()?;
+                let transport_progress = self.poll_transport()?;
+                // The connection to the server has closed, we register again.
+                if let State::Down = self.state {
+                    break;
+                }
+
+                if !tun_progress && !transport_progress {
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(all(test, feature = "tun-test"))]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, TcpListener, TcpStream};
+    use std::thread;
+    use byteorder::{BigEndian, ByteOrder};
+    use futures::sync::oneshot;
+    use ring::aead;
+    use tokio_core::reactor::Timeout;
+    use crypto::aead_crypto::AeadCrypto;
+    use crypto::kdf::Kdf;
+    use transport::tcp::encode_frame;
+
+    /// Read the `Hello` framed on `stream`, returns its cookie and the first cipher it offers.
+    fn read_hello(stream: &mut TcpStream, crypto: &Crypto) -> (ClientToken, u8) {
+        let mut header = [0u8; 2];
+        stream.read_exact(&mut header).unwrap();
+        let mut datagram = vec![0u8; BigEndian::read_u16(&header) as usize];
+        stream.read_exact(&mut datagram).unwrap();
+        let packet = AkarinPacket::decode(&datagram, crypto.nonce_len(), crypto.tag_len()).unwrap();
+        assert_eq!(packet.client_id, 0);
+        let (_, message) = packet.open(crypto).unwrap();
+        match Message::decode(&message).unwrap() {
+            Message::Hello { cookie, ciphers, .. } => (cookie, ciphers[0]),
+            _ => panic!("expected a Hello"),
+        }
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let crypto = AeadCrypto::new(&aead::CHACHA20_POLY1305, b"realityone", &Kdf::Legacy).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // The server welcomes the client, then drops its connection as a server which restarts does.
+        let (registered, cookies) = oneshot::channel();
+        let server_crypto = AeadCrypto::new(&aead::CHACHA20_POLY1305, b"realityone", &Kdf::Legacy).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (cookie, cipher) = read_hello(&mut stream, &server_crypto);
+            let welcome = Message::Welcome {
+                cookie,
+                version: PROTOCOL_VERSION,
+                cipher,
+                address: Ipv4Addr::new(10, 8, 0, 2),
+                handshake: &[],
+                address_v6: None,
+            };
+            let datagram = AkarinPacket::seal(2, 123, 0, 0, &welcome.encode(), &server_crypto).unwrap();
+            let mut frame = Vec::new();
+            encode_frame(&mut frame, &datagram).unwrap();
+            stream.write_all(&frame).unwrap();
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let (again, _) = read_hello(&mut stream, &server_crypto);
+            registered.send((cookie, again)).unwrap();
+        });
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let mut tun_configuration = tun::Configuration::default();
+        tun_configuration.name("akarin-test").address(Ipv4Addr::new(10, 8, 0, 2)).up();
+        let tun = Device::new(tun::create(&tun_configuration).unwrap(), &handle).unwrap();
+        let mut configuration = ClientConfiguration::default();
+        configuration.server_address(address).transport(TransportMode::Tcp).mtu(1400);
+        let tunnel = ClientTunnel::new(tun, &crypto, address, &configuration, &handle).unwrap();
+
+        let registered = cookies.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()));
+        let timeout = Timeout::new(Duration::from_secs(10), &handle).unwrap().and_then(|_| {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "client did not register again"))
+        });
+        let tunnel = tunnel.map(|_| panic!("client stopped"));
+        let done = registered.select(tunnel).map(|(cookies, _)| cookies).map_err(|(e, _)| e);
+        let (first, again) = core.run(done.select(timeout).map(|(cookies, _)| cookies).map_err(|(e, _)| e)).unwrap();
+        // The client registers as a new client, over a new connection.
+        assert!(first != again);
+        server.join().unwrap();
     }
 }
@@ -1,38 +1,661 @@
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::packet::Padding;
+use super::session::{MAX_REPLAY_WINDOW, MIN_REPLAY_WINDOW, RekeyPolicy};
+use super::user::UserId;
+use common::error::*;
+use common::settings::Settings;
+use common::subnet::{Subnet, Subnet6};
+use crypto::Ciphers;
+use crypto::kdf::{DEFAULT_KDF_ITERATIONS, Kdf, MIN_KDF_ITERATIONS, MIN_KDF_SALT_LEN};
+use crypto::x25519::{PrivateKey, PublicKey};
+use transport::TransportMode;
+use tun::configuration::check_mtu;
+
+/// Subnet of the tunnel when none is configured.
+pub const DEFAULT_SUBNET: &str = "10.8.0.1/24";
+/// Seconds a client stays registered without sending anything.
+pub const DEFAULT_CLIENT_TIMEOUT: u32 = 60;
+
+/// Keys accepted in the `client` section of a configuration file.
+pub const CLIENT_KEYS: &[&str] = &["server", "transport", "user", "password", "kdf_salt", "kdf_iterations",
+                                   "kdf_legacy", "ciphers", "forward_secrecy", "private_key", "server_key", "padding",
+                                   "replay_window", "rekey_packets", "rekey_bytes", "rekey_interval",
+                                   "keepalive_interval", "keepalive_misses", "tun", "mtu", "mtu_probing",
+                                   "mss_clamping"];
+/// Keys accepted in the `server` section of a configuration file.
+pub const SERVER_KEYS: &[&str] = &["listen", "tcp", "tcp_max_connections", "subnet", "address", "subnet6", "timeout",
+                                   "users", "password", "kdf_salt", "kdf_iterations", "kdf_legacy", "ciphers",
+                                   "forward_secrecy", "private_key", "public_keys", "padding", "replay_window",
+                                   "rekey_packets", "rekey_bytes", "rekey_interval", "tun", "mtu", "mss_clamping"];
 
 #[derive(Clone, Default, Debug)]
 pub struct ClientConfiguration {
     pub server_address: Option<SocketAddr>,
+    pub transport: Option<TransportMode>,
+    pub user: Option<UserId>,
     pub mtu: Option<i32>,
+    pub mtu_probing: Option<bool>,
+    pub mss_clamping: Option<bool>,
+    pub replay_window: Option<u64>,
+    pub rekey_packets: Option<u64>,
+    pub rekey_bytes: Option<u64>,
+    pub rekey_interval: Option<u64>,
+    pub keepalive_interval: Option<u64>,
+    pub keepalive_misses: Option<u32>,
+    pub ciphers: Option<Vec<Ciphers>>,
+    pub forward_secrecy: Option<bool>,
+    pub private_key: Option<PrivateKey>,
+    pub server_key: Option<PublicKey>,
+    pub padding: Option<Padding>,
+    pub password: Option<String>,
+    pub kdf_salt: Option<String>,
+    pub kdf_iterations: Option<u32>,
+    pub kdf_legacy: Option<bool>,
 }
 
 
 #[derive(Clone, Default, Debug)]
 pub struct ServerConfiguration {
+    pub listen_address: Option<SocketAddr>,
+    pub tcp: Option<bool>,
+    pub tcp_max_connections: Option<usize>,
+    pub subnet: Option<Subnet>,
+    pub address: Option<Ipv4Addr>,
+    pub subnet_v6: Option<Subnet6>,
     pub mtu: Option<i32>,
+    pub mss_clamping: Option<bool>,
+    pub replay_window: Option<u64>,
+    pub rekey_packets: Option<u64>,
+    pub rekey_bytes: Option<u64>,
+    pub rekey_interval: Option<u64>,
     pub client_timeout: Option<u32>,
+    pub users: Option<PathBuf>,
+    pub ciphers: Option<Vec<Ciphers>>,
+    pub forward_secrecy: Option<bool>,
+    pub private_key: Option<PrivateKey>,
+    pub public_keys: Option<Vec<PublicKey>>,
+    pub padding: Option<Padding>,
+    pub password: Option<String>,
+    pub kdf_salt: Option<String>,
+    pub kdf_iterations: Option<u32>,
+    pub kdf_legacy: Option<bool>,
 }
 
 impl ClientConfiguration {
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let mut configuration = ClientConfiguration::default();
+
+        if let Some(value) = settings.get("server")? {
+            configuration.server_address(value);
+        }
+        if let Some(value) = settings.get("transport")? {
+            configuration.transport(value);
+        }
+        if let Some(value) = settings.get("user")? {
+            configuration.user(value);
+        }
+        if let Some(value) = settings.get("mtu")? {
+            check_mtu(settings, value)?;
+            configuration.mtu(value);
+        }
+        if let Some(value) = settings.get("mtu_probing")? {
+            configuration.mtu_probing(value);
+        }
+        if let Some(value) = settings.get("mss_clamping")? {
+            configuration.mss_clamping(value);
+        }
+        if let Some(value) = settings.get("replay_window")? {
+            check_replay_window(settings, value)?;
+            configuration.replay_window(value);
+        }
+        if let Some(value) = settings.get("rekey_packets")? {
+            check_positive(settings, "rekey_packets", value)?;
+            configuration.rekey_packets(value);
+        }
+        if let Some(value) = settings.get("rekey_bytes")? {
+            check_positive(settings, "rekey_bytes", value)?;
+            configuration.rekey_bytes(value);
+        }
+        if let Some(value) = settings.get("rekey_interval")? {
+            check_positive(settings, "rekey_interval", value)?;
+            configuration.rekey_interval(value);
+        }
+        if let Some(value) = settings.get("keepalive_interval")? {
+            configuration.keepalive_interval(value);
+        }
+        if let Some(value) = settings.get::<u32>("keepalive_misses")? {
+            check_positive(settings, "keepalive_misses", value as u64)?;
+            configuration.keepalive_misses(value);
+        }
+        if let Some(value) = settings.get::<String>("ciphers")? {
+            configuration.ciphers(&parse_ciphers(settings, &value)?);
+        }
+        if let Some(value) = settings.get("forward_secrecy")? {
+            configuration.forward_secrecy(value);
+        }
+        if let Some(value) = settings.get("padding")? {
+            configuration.padding(value);
+        }
+        if let Some(value) = settings.get::<String>("password")? {
+            configuration.password(&value);
+        }
+        if let Some(value) = settings.get::<String>("kdf_salt")? {
+            configuration.kdf_salt(&value);
+        }
+        if let Some(value) = settings.get("kdf_iterations")? {
+            configuration.kdf_iterations(value);
+        }
+        if let Some(value) = settings.get("kdf_legacy")? {
+            if value && configuration.kdf_salt.is_some() {
+                return Err(settings.invalid("kdf_legacy", "conflicts with `kdf_salt`"));
+            }
+            configuration.kdf_legacy(value);
+        }
+        check_kdf(settings, configuration.kdf_salt.as_ref(), configuration.kdf_iterations,
+                  configuration.kdf_legacy.unwrap_or(false))?;
+        if let Some(value) = settings.get("private_key")? {
+            configuration.private_key(value);
+        }
+        if let Some(value) = settings.get("server_key")? {
+            configuration.server_key(value);
+        }
+        match (&configuration.private_key, &configuration.server_key) {
+            (&Some(_), &None) => return Err(settings.invalid("private_key", "requires `server_key`")),
+            (&None, &Some(_)) => return Err(settings.invalid("server_key", "requires `private_key`")),
+            _ => {}
+        }
+
+        Ok(configuration)
+    }
+
     pub fn server_address(&mut self, value: SocketAddr) -> &mut Self {
         self.server_address = Some(value);
         self
     }
 
+    /// How the client reaches the server, over UDP if not set.
+    pub fn transport(&mut self, value: TransportMode) -> &mut Self {
+        self.transport = Some(value);
+        self
+    }
+
+    /// The user to connect as, the user of the shared password if not set.
+    pub fn user(&mut self, value: UserId) -> &mut Self {
+        self.user = Some(value);
+        self
+    }
+
+    /// The MTU of the tun, derived from the MTU of the path to the server if not set.
     pub fn mtu(&mut self, value: i32) -> &mut Self {
         self.mtu = Some(value);
         self
     }
+
+    /// Probe the path to the server and lower the MTU of the tun when large packets do not get through, on by
+    /// default.
+    pub fn mtu_probing(&mut self, value: bool) -> &mut Self {
+        self.mtu_probing = Some(value);
+        self
+    }
+
+    /// Lower the MSS offered by TCP connections through the tunnel to fit the MTU of the tun, off by default.
+    pub fn mss_clamping(&mut self, value: bool) -> &mut Self {
+        self.mss_clamping = Some(value);
+        self
+    }
+
+    /// How far behind the newest packet received a packet is still accepted, in packets.
+    pub fn replay_window(&mut self, value: u64) -> &mut Self {
+        self.replay_window = Some(value);
+        self
+    }
+
+    /// Replace the key of a session after it has sealed or opened this many packets.
+    pub fn rekey_packets(&mut self, value: u64) -> &mut Self {
+        self.rekey_packets = Some(value);
+        self
+    }
+
+    /// Replace the key of a session after it has sealed or opened this many bytes.
+    pub fn rekey_bytes(&mut self, value: u64) -> &mut Self {
+        self.rekey_bytes = Some(value);
+        self
+    }
+
+    /// Replace the key of a session after this many seconds.
+    pub fn rekey_interval(&mut self, value: u64) -> &mut Self {
+        self.rekey_interval = Some(value);
+        self
+    }
+
+    pub fn rekey_policy(&self) -> RekeyPolicy {
+        rekey_policy(self.rekey_packets, self.rekey_bytes, self.rekey_interval)
+    }
+
+    /// Send a keepalive to the server every this many seconds, 0 to never send any.
+    pub fn keepalive_interval(&mut self, value: u64) -> &mut Self {
+        self.keepalive_interval = Some(value);
+        self
+    }
+
+    /// Register again once the server has not replied to this many keepalives in a row.
+    pub fn keepalive_misses(&mut self, value: u32) -> &mut Self {
+        self.keepalive_misses = Some(value);
+        self
+    }
+
+    /// The ciphers offered to the server, in order of preference.
+    pub fn ciphers(&mut self, value: &[Ciphers]) -> &mut Self {
+        self.ciphers = Some(value.to_vec());
+        self
+    }
+
+    pub fn supported_ciphers(&self) -> &[Ciphers] {
+        self.ciphers.as_ref().map_or(Ciphers::ALL, |ciphers| ciphers.as_slice())
+    }
+
+    /// Give up rather than derive the session from the password if the server can not do a Noise handshake.
+    pub fn forward_secrecy(&mut self, value: bool) -> &mut Self {
+        self.forward_secrecy = Some(value);
+        self
+    }
+
+    /// How the packets sent to the server are padded, see `Padding`.
+    pub fn padding(&mut self, value: Padding) -> &mut Self {
+        self.padding = Some(value);
+        self
+    }
+
+    /// The static key identifying the client to the server.
+    pub fn private_key(&mut self, value: PrivateKey) -> &mut Self {
+        self.private_key = Some(value);
+        self
+    }
+
+    /// The static key of the server, the client only connects to a server proving it owns it.
+    pub fn server_key(&mut self, value: PublicKey) -> &mut Self {
+        self.server_key = Some(value);
+        self
+    }
+
+    pub fn password(&mut self, value: &str) -> &mut Self {
+        self.password = Some(value.to_string());
+        self
+    }
+
+    /// Salt the keys are derived from passwords with, it must be the same on the server and its clients.
+    pub fn kdf_salt(&mut self, value: &str) -> &mut Self {
+        self.kdf_salt = Some(value.to_string());
+        self
+    }
+
+    pub fn kdf_iterations(&mut self, value: u32) -> &mut Self {
+        self.kdf_iterations = Some(value);
+        self
+    }
+
+    /// Derive the key of the user without a salt like older releases did, for servers which still expect it.
+    pub fn kdf_legacy(&mut self, value: bool) -> &mut Self {
+        self.kdf_legacy = Some(value);
+        self
+    }
+
+    /// The derivation of the key of the user, the salted one unless `kdf_legacy` asks for the one of older releases.
+    pub fn kdf(&self) -> Result<Kdf> {
+        kdf(self.kdf_salt.as_ref(), self.kdf_iterations, self.kdf_legacy.unwrap_or(false))
+    }
 }
 
 impl ServerConfiguration {
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let mut configuration = ServerConfiguration::default();
+
+        if let Some(value) = settings.get("listen")? {
+            configuration.listen_address(value);
+        }
+        if let Some(value) = settings.get("tcp")? {
+            configuration.tcp(value);
+        }
+        if let Some(value) = settings.get("tcp_max_connections")? {
+            check_positive(settings, "tcp_max_connections", value as u64)?;
+            configuration.tcp_max_connections(value);
+        }
+        if let Some(value) = settings.get("subnet")? {
+            configuration.subnet(value);
+        }
+        if let Some(value) = settings.get("address")? {
+            configuration.address(value);
+        }
+        if let Some(value) = settings.get::<Subnet6>("subnet6")? {
+            // Client addresses are derived from their IPv4 addresses, which take up to 32 bits.
+            if value.prefix() > 96 {
+                return Err(settings.invalid("subnet6", "prefix length must be at most 96"));
+            }
+            configuration.subnet_v6(value);
+        }
+        if let Some(value) = settings.get("timeout")? {
+            configuration.client_timeout(value);
+        }
+        if let Some(value) = settings.get::<PathBuf>("users")? {
+            configuration.users(&value);
+        }
+        if let Some(value) = settings.get("mtu")? {
+            check_mtu(settings, value)?;
+            configuration.mtu(value);
+        }
+        if let Some(value) = settings.get("mss_clamping")? {
+            configuration.mss_clamping(value);
+        }
+        if let Some(value) = settings.get("replay_window")? {
+            check_replay_window(settings, value)?;
+            configuration.replay_window(value);
+        }
+        if let Some(value) = settings.get("rekey_packets")? {
+            check_positive(settings, "rekey_packets", value)?;
+            configuration.rekey_packets(value);
+        }
+        if let Some(value) = settings.get("rekey_bytes")? {
+            check_positive(settings, "rekey_bytes", value)?;
+            configuration.rekey_bytes(value);
+        }
+        if let Some(value) = settings.get("rekey_interval")? {
+            check_positive(settings, "rekey_interval", value)?;
+            configuration.rekey_interval(value);
+        }
+        if let Some(value) = settings.get::<String>("ciphers")? {
+            configuration.ciphers(&parse_ciphers(settings, &value)?);
+        }
+        if let Some(value) = settings.get("forward_secrecy")? {
+            configuration.forward_secrecy(value);
+        }
+        if let Some(value) = settings.get("padding")? {
+            configuration.padding(value);
+        }
+        if let Some(value) = settings.get::<String>("password")? {
+            configuration.password(&value);
+        }
+        if let Some(value) = settings.get::<String>("kdf_salt")? {
+            configuration.kdf_salt(&value);
+        }
+        if let Some(value) = settings.get("kdf_iterations")? {
+            configuration.kdf_iterations(value);
+        }
+        if let Some(value) = settings.get("kdf_legacy")? {
+            configuration.kdf_legacy(value);
+        }
+        check_kdf(settings, configuration.kdf_salt.as_ref(), configuration.kdf_iterations,
+                  configuration.kdf_legacy.unwrap_or(false))?;
+        if let Some(value) = settings.get("private_key")? {
+            configuration.private_key(value);
+        }
+        if let Some(value) = settings.get::<String>("public_keys")? {
+            if configuration.private_key.is_none() {
+                return Err(settings.invalid("public_keys", "requires `private_key`"));
+            }
+            configuration.public_keys(&parse_public_keys(settings, &value)?);
+        }
+
+        if let Some(address) = configuration.address {
+            let subnet = configuration.tunnel_subnet();
+            if !subnet.is_host(address) {
+                let reason = format!("`{}` is not a host address of subnet `{}`", address, subnet);
+                return Err(settings.invalid("address", &reason));
+            }
+        }
+
+        Ok(configuration)
+    }
+
+    /// The subnet client addresses are leased from, `DEFAULT_SUBNET` if not set.
+    pub fn tunnel_subnet(&self) -> Subnet {
+        self.subnet.unwrap_or_else(|| DEFAULT_SUBNET.parse().unwrap())
+    }
+
+    /// The address of the server inside the tunnel.
+    ///
+    /// Falls back to the address of the subnet, or to its gateway if the subnet is given by its network address,
+    /// e.g. `10.8.0.0/16`.
+    pub fn tunnel_address(&self) -> Ipv4Addr {
+        let subnet = self.tunnel_subnet();
+        match self.address {
+            Some(address) => address,
+            None if subnet.is_host(subnet.address()) => subnet.address(),
+            None => subnet.first_host(),
+        }
+    }
+
+    pub fn listen_address(&mut self, value: SocketAddr) -> &mut Self {
+        self.listen_address = Some(value);
+        self
+    }
+
+    /// Also accept clients over TCP, on the same address as UDP.
+    pub fn tcp(&mut self, value: bool) -> &mut Self {
+        self.tcp = Some(value);
+        self
+    }
+
+    /// Most TCP connections open at once, further ones are closed right away.
+    pub fn tcp_max_connections(&mut self, value: usize) -> &mut Self {
+        self.tcp_max_connections = Some(value);
+        self
+    }
+
+    pub fn subnet(&mut self, value: Subnet) -> &mut Self {
+        self.subnet = Some(value);
+        self
+    }
+
+    /// The address of the server inside the tunnel, see `tunnel_address`.
+    pub fn address(&mut self, value: Ipv4Addr) -> &mut Self {
+        self.address = Some(value);
+        self
+    }
+
+    /// The IPv6 subnet of the tunnel and the address of the server in it.
+    pub fn subnet_v6(&mut self, value: Subnet6) -> &mut Self {
+        self.subnet_v6 = Some(value);
+        self
+    }
+
+    /// The MTU of the tun, derived from the MTU of the interface it listens on if not set.
     pub fn mtu(&mut self, value: i32) -> &mut Self {
         self.mtu = Some(value);
         self
     }
 
+    /// Lower the MSS offered by TCP connections through the tunnel to fit the MTU of the tun, or of the path to the
+    /// client if smaller, off by default.
+    pub fn mss_clamping(&mut self, value: bool) -> &mut Self {
+        self.mss_clamping = Some(value);
+        self
+    }
+
+    /// How far behind the newest packet received a packet is still accepted, in packets.
+    pub fn replay_window(&mut self, value: u64) -> &mut Self {
+        self.replay_window = Some(value);
+        self
+    }
+
+    /// Replace the key of a session after it has sealed or opened this many packets.
+    pub fn rekey_packets(&mut self, value: u64) -> &mut Self {
+        self.rekey_packets = Some(value);
+        self
+    }
+
+    /// Replace the key of a session after it has sealed or opened this many bytes.
+    pub fn rekey_bytes(&mut self, value: u64) -> &mut Self {
+        self.rekey_bytes = Some(value);
+        self
+    }
+
+    /// Replace the key of a session after this many seconds.
+    pub fn rekey_interval(&mut self, value: u64) -> &mut Self {
+        self.rekey_interval = Some(value);
+        self
+    }
+
+    pub fn rekey_policy(&self) -> RekeyPolicy {
+        rekey_policy(self.rekey_packets, self.rekey_bytes, self.rekey_interval)
+    }
+
     pub fn client_timeout(&mut self, value: u32) -> &mut Self {
         self.client_timeout = Some(value);
         self
     }
+
+    /// A TOML file of users with their own passwords, see `akarin::user`.
+    pub fn users(&mut self, value: &Path) -> &mut Self {
+        self.users = Some(value.to_path_buf());
+        self
+    }
+
+    /// The ciphers clients may use, the first one a client also supports is chosen.
+    pub fn ciphers(&mut self, value: &[Ciphers]) -> &mut Self {
+        self.ciphers = Some(value.to_vec());
+        self
+    }
+
+    pub fn supported_ciphers(&self) -> &[Ciphers] {
+        self.ciphers.as_ref().map_or(Ciphers::ALL, |ciphers| ciphers.as_slice())
+    }
+
+    /// Reject clients which do not do a Noise handshake, rather than deriving their sessions from their passwords.
+    pub fn forward_secrecy(&mut self, value: bool) -> &mut Self {
+        self.forward_secrecy = Some(value);
+        self
+    }
+
+    /// The static key identifying the server to the clients which pin it.
+    pub fn private_key(&mut self, value: PrivateKey) -> &mut Self {
+        self.private_key = Some(value);
+        self
+    }
+
+    /// The static keys clients of the shared `password` may be identified by, any client is accepted if not set.
+    pub fn public_keys(&mut self, value: &[PublicKey]) -> &mut Self {
+        self.public_keys = Some(value.to_vec());
+        self
+    }
+
+    /// How the packets sent to clients of version 3 and later are padded, see `Padding`.
+    pub fn padding(&mut self, value: Padding) -> &mut Self {
+        self.padding = Some(value);
+        self
+    }
+
+    pub fn password(&mut self, value: &str) -> &mut Self {
+        self.password = Some(value.to_string());
+        self
+    }
+
+    /// Salt the keys are derived from passwords with, it must be the same on the server and its clients.
+    pub fn kdf_salt(&mut self, value: &str) -> &mut Self {
+        self.kdf_salt = Some(value.to_string());
+        self
+    }
+
+    pub fn kdf_iterations(&mut self, value: u32) -> &mut Self {
+        self.kdf_iterations = Some(value);
+        self
+    }
+
+    /// Also accept clients whose keys are derived without a salt, while they move to the configured one. Without a
+    /// salt, only those clients are accepted.
+    pub fn kdf_legacy(&mut self, value: bool) -> &mut Self {
+        self.kdf_legacy = Some(value);
+        self
+    }
+
+    /// The derivations the keys of users are accepted with, the configured one first.
+    pub fn kdfs(&self) -> Result<Vec<Kdf>> {
+        let legacy = self.kdf_legacy.unwrap_or(false);
+        let mut kdfs = vec![kdf(self.kdf_salt.as_ref(), self.kdf_iterations, legacy)?];
+        if legacy && kdfs[0] != Kdf::Legacy {
+            kdfs.push(Kdf::Legacy);
+        }
+        Ok(kdfs)
+    }
+}
+
+fn check_replay_window(settings: &Settings, size: u64) -> Result<()> {
+    if size < MIN_REPLAY_WINDOW || size > MAX_REPLAY_WINDOW {
+        let reason = format!("`{}` is out of range {}..{}", size, MIN_REPLAY_WINDOW, MAX_REPLAY_WINDOW);
+        return Err(settings.invalid("replay_window", &reason));
+    }
+    Ok(())
+}
+
+fn check_kdf(settings: &Settings, salt: Option<&String>, iterations: Option<u32>, legacy: bool) -> Result<()> {
+    match salt {
+        Some(salt) if salt.len() < MIN_KDF_SALT_LEN => {
+            let reason = format!("must be at least {} bytes long", MIN_KDF_SALT_LEN);
+            return Err(settings.invalid("kdf_salt", &reason));
+        }
+        Some(_) => {}
+        None if iterations.is_some() => return Err(settings.invalid("kdf_iterations", "requires `kdf_salt`")),
+        None if legacy => warn!("No `kdf_salt` configured, keys are derived from passwords without a salt"),
+        None => {
+            let reason = "is required, unless `kdf_legacy = true` derives keys without a salt like older releases";
+            return Err(settings.invalid("kdf_salt", reason));
+        }
+    }
+    match iterations {
+        Some(iterations) if iterations < MIN_KDF_ITERATIONS => {
+            Err(settings.invalid("kdf_iterations", &format!("must be at least {}", MIN_KDF_ITERATIONS)))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn kdf(salt: Option<&String>, iterations: Option<u32>, legacy: bool) -> Result<Kdf> {
+    match salt {
+        Some(salt) => {
+            Ok(Kdf::Pbkdf2 {
+                   salt: salt.clone(),
+                   iterations: iterations.unwrap_or(DEFAULT_KDF_ITERATIONS),
+               })
+        }
+        None if legacy => Ok(Kdf::Legacy),
+        None => Err(ErrorKind::MissingSetting("kdf_salt".to_string()).into()),
+    }
+}
+
+/// Parse a comma separated list of cipher names.
+fn parse_ciphers(settings: &Settings, value: &str) -> Result<Vec<Ciphers>> {
+    let mut ciphers = Vec::new();
+    for name in value.split(',').map(|name| name.trim()) {
+        let cipher = name.parse::<Ciphers>().map_err(|e| settings.invalid("ciphers", &e.to_string()))?;
+        if ciphers.contains(&cipher) {
+            return Err(settings.invalid("ciphers", &format!("`{}` is listed twice", name)));
+        }
+        ciphers.push(cipher);
+    }
+    Ok(ciphers)
+}
+
+/// Parse a comma separated list of public keys.
+fn parse_public_keys(settings: &Settings, value: &str) -> Result<Vec<PublicKey>> {
+    value.split(',')
+         .map(|key| key.trim().parse().map_err(|e: Error| settings.invalid("public_keys", &e.to_string())))
+         .collect()
+}
+
+fn check_positive(settings: &Settings, key: &str, value: u64) -> Result<()> {
+    if value == 0 {
+        return Err(settings.invalid(key, "must be positive"));
+    }
+    Ok(())
+}
+
+fn rekey_policy(packets: Option<u64>, bytes: Option<u64>, interval: Option<u64>) -> RekeyPolicy {
+    let default = RekeyPolicy::default();
+    RekeyPolicy {
+        packets: packets.unwrap_or(default.packets),
+        bytes: bytes.unwrap_or(default.bytes),
+        interval: interval.map_or(default.interval, Duration::from_secs),
+    }
 }
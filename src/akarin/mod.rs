@@ -1,10 +1,24 @@
 pub mod server;
 pub mod client;
 pub mod configuration;
-
+pub mod packet;
+pub mod pmtu;
+pub mod pool;
+pub mod session;
+pub mod user;
+
+use byteorder::{BigEndian, ByteOrder};
+use ring::rand::SecureRandom;
 use tokio_core::reactor::{Core, Handle};
 
+use self::packet::{AKARIN_DATA_OFFSET, AKARIN_TAILROOM};
+use common::buf::PacketBuf;
 use common::error::*;
+use transport::Endpoint;
+
+pub type ClientId = u32;
+pub type ClientToken = u64;
+pub type ClientMetadata = (ClientToken, Endpoint);
 
 #[derive(Debug)]
 pub enum State {
@@ -12,20 +26,26 @@ pub enum State {
     Down,
 }
 
-pub const AKARIN_ZERO_BYTES: usize = 32;
-pub const AKARIN_OVERHEAD_LEN: usize = 24;
 pub const AKARIN_PACKET_OFFSET: usize = 8;
 pub const AKARIN_USERTOKEN_LEN: usize = 8;
+pub const AKARIN_CLIENTID_LEN: usize = 4;
 
 
-pub fn new_buf(mtu: usize) -> Vec<u8> {
-    vec![0u8; mtu + AKARIN_ZERO_BYTES + AKARIN_USERTOKEN_LEN]
+/// A buffer for the packets of a tun of `mtu`, with the room to seal them into datagrams in place.
+pub fn new_buf(mtu: usize) -> PacketBuf {
+    PacketBuf::new(AKARIN_DATA_OFFSET, mtu, AKARIN_TAILROOM)
+}
+
+pub fn new_token(random: &SecureRandom) -> Result<ClientToken> {
+    let mut bytes = [0u8; AKARIN_USERTOKEN_LEN];
+    random.fill(&mut bytes)?;
+    Ok(BigEndian::read_u64(&bytes))
 }
 
 pub trait Server {
-    fn serve(self, mut core: Core, handle: Handle) -> Result<()>;
+    fn serve(self, core: Core, handle: Handle) -> Result<()>;
 }
 
 pub trait Client {
-    fn connect(self, mut core: Core, handle: Handle) -> Result<()>;
+    fn connect(self, core: Core, handle: Handle) -> Result<()>;
 }
@@ -0,0 +1,811 @@
+//! The akarin wire format.
+//!
+//! Every datagram exchanged between a client and the server has the following layout, all integers are
+//! big endian:
+//!
+//! ```text
+//! +-----------+---------+--------+-----------+----------------+---------+
+//! | client id |  token  | epoch  |   nonce   |   ciphertext   |   tag   |
+//! |  4 bytes  | 8 bytes | 1 byte | nonce_len |    variable    | tag_len |
+//! +-----------+---------+--------+-----------+----------------+---------+
+//! ```
+//!
+//! `nonce_len` and `tag_len` depend on the cipher both sides agree on. `epoch` tells which key of the session the
+//! packet is sealed with, see `akarin::session`.
+//!
+//! The plaintext starts with the counter of the packet in its session, 8 bytes, which protects against replays.
+//! The rest of it is a `Message`, its first byte tells the type of the message:
+//!
+//! ```text
+//! Data:      | 0x00 | IP packet        |
+//! Padded:    | 0x04 | length (2 bytes) | IP packet | padding |
+//! Hello:     | 0x01 | cookie (8 bytes) | version | cipher count | cipher ids | [ handshake len | handshake ] |
+//! Welcome:   | 0x02 | cookie (8 bytes) | version | cipher id | address (4 bytes) | [ handshake len | handshake ] |
+//!            [ address6 (16) | prefix6 (1) ] |
+//! Reject:    | 0x03 | cookie (8 bytes) | code    | min version | max version |
+//! Keepalive: | 0x05 |
+//! Probe:     | 0x06 | MTU (2 bytes)    | padding |
+//! ProbeAck:  | 0x07 | MTU (2 bytes)    |
+//! ```
+//!
+//! A client without an id sends `Hello` with a zero client id and its user id as the token, so that the server
+//! knows which user key the packet is sealed with. `Hello` carries the highest protocol version the client speaks and
+//! the ciphers it supports. The server answers with `Welcome` carrying the assigned client id and token in the
+//! header, and the cookie of the `Hello`, the version and cipher it has chosen and the inner addresses in the
+//! message. The IPv6 address is only present if the server has an IPv6 subnet. A client the server can not agree
+//! with gets a `Reject` instead, telling why and which versions the server speaks.
+//!
+//! Since version 2, `Hello` and `Welcome` may carry the messages of a Noise handshake, see `crypto::noise`. The
+//! handshake of a `Hello` starts with the id of its pattern. A `Welcome` of version 2 always has the length of the
+//! handshake, zero if the client has not asked for one. The
+//! session of a client which completes the handshake is derived from the key of the handshake instead of the key of
+//! the user, so that recorded traffic stays secret even if the password leaks.
+//!
+//! Since version 3, `Data` may be padded so that the length of a datagram does not tell the length of the packet it
+//! carries, the padded message keeps the length of the packet in its encrypted part. See `Padding`.
+//!
+//! Since version 4, a client sends `Keepalive` now and then to keep its registration and the mappings of the NATs
+//! on its way alive. The server answers every one with a `Keepalive` of its own, so that the client notices when
+//! the server has gone away.
+//!
+//! Since version 5, a client looks for the largest packets the path to the server carries with `Probe`, padded to
+//! the length of a padded `Data` carrying a packet of the MTU it probes, which the server acknowledges with a
+//! `ProbeAck` of that MTU. See `akarin::pmtu`.
+//!
+//! `Hello`, `Welcome` and `Reject` are sealed with the key of the user and `HANDSHAKE_CIPHER`, with a zero epoch
+//! and counter, every other packet with a key of the session and the chosen cipher.
+
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use std::str::FromStr;
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::{AKARIN_CLIENTID_LEN, AKARIN_USERTOKEN_LEN, ClientId, ClientToken};
+use common::buf::PacketBuf;
+use common::error::*;
+use common::subnet::Subnet6;
+use crypto::{Ciphers, Crypto, MAX_NONCE_LEN, MAX_TAG_LEN};
+
+pub const AKARIN_EPOCH_LEN: usize = 1;
+pub const AKARIN_HEADER_LEN: usize = AKARIN_CLIENTID_LEN + AKARIN_USERTOKEN_LEN + AKARIN_EPOCH_LEN;
+pub const AKARIN_COUNTER_LEN: usize = 8;
+
+/// Room to reserve in front of a message and behind it, to seal it into a packet in place.
+pub const AKARIN_HEADROOM: usize = AKARIN_HEADER_LEN + MAX_NONCE_LEN + AKARIN_COUNTER_LEN;
+pub const AKARIN_TAILROOM: usize = MAX_TAG_LEN;
+/// Offset of the payload of a `Data` message in a packet buffer, IP packets read from the tun land there so that
+/// they are sealed without moving them.
+pub const AKARIN_DATA_OFFSET: usize = AKARIN_HEADROOM + PADDED_DATA_LEN;
+
+/// Bytes a datagram sealed with `cipher` adds to the IP packet it carries, when it is not padded.
+pub fn overhead(cipher: Ciphers) -> usize {
+    AKARIN_HEADER_LEN + cipher.nonce_len() + AKARIN_COUNTER_LEN + PADDED_DATA_LEN + cipher.tag_len()
+}
+
+/// Version of the protocol spoken by this build, and the oldest one it still accepts.
+pub const PROTOCOL_VERSION: u8 = 5;
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+const MESSAGE_KIND_LEN: usize = 1;
+const MESSAGE_DATA: u8 = 0x00;
+const MESSAGE_HELLO: u8 = 0x01;
+const MESSAGE_WELCOME: u8 = 0x02;
+const MESSAGE_REJECT: u8 = 0x03;
+const MESSAGE_PADDED_DATA: u8 = 0x04;
+const MESSAGE_KEEPALIVE: u8 = 0x05;
+const MESSAGE_PROBE: u8 = 0x06;
+const MESSAGE_PROBE_ACK: u8 = 0x07;
+
+const PADDED_DATA_LEN: usize = 1 + 2;
+const HELLO_LEN: usize = 1 + 8 + 1 + 1;
+const WELCOME_LEN: usize = 1 + 8 + 1 + 1 + 4;
+const WELCOME_V6_LEN: usize = WELCOME_LEN + 16 + 1;
+const REJECT_LEN: usize = 1 + 8 + 1 + 1 + 1;
+const PROBE_LEN: usize = 1 + 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AkarinPacket<'a> {
+    pub client_id: ClientId,
+    pub token: ClientToken,
+    pub epoch: u8,
+    pub nonce: &'a [u8],
+    pub cipher_text: &'a [u8],
+    pub tag: &'a [u8],
+}
+
+impl<'a> AkarinPacket<'a> {
+    /// Split the output of `Crypto::encrypt` into a packet.
+    pub fn from_sealed(client_id: ClientId, token: ClientToken, epoch: u8, sealed: &'a [u8], nonce_len: usize,
+                       tag_len: usize)
+                       -> Result<Self> {
+        if sealed.len() < nonce_len + tag_len {
+            return Err(ErrorKind::TruncatedPacket(sealed.len()).into());
+        }
+
+        let (nonce, rest) = sealed.split_at(nonce_len);
+        let (cipher_text, tag) = rest.split_at(rest.len() - tag_len);
+        Ok(AkarinPacket {
+               client_id,
+               token,
+               epoch,
+               nonce,
+               cipher_text,
+               tag,
+           })
+    }
+
+    /// Read the client id, token and epoch of a packet, before knowing which key it is sealed with.
+    pub fn peek(bytes: &[u8]) -> Result<(ClientId, ClientToken, u8)> {
+        if bytes.len() < AKARIN_HEADER_LEN {
+            return Err(ErrorKind::TruncatedPacket(bytes.len()).into());
+        }
+        Ok((BigEndian::read_u32(&bytes[..AKARIN_CLIENTID_LEN]),
+            BigEndian::read_u64(&bytes[AKARIN_CLIENTID_LEN..AKARIN_CLIENTID_LEN + AKARIN_USERTOKEN_LEN]),
+            bytes[AKARIN_HEADER_LEN - AKARIN_EPOCH_LEN]))
+    }
+
+    pub fn decode(bytes: &'a [u8], nonce_len: usize, tag_len: usize) -> Result<Self> {
+        if bytes.len() < AKARIN_HEADER_LEN + nonce_len + tag_len {
+            return Err(ErrorKind::TruncatedPacket(bytes.len()).into());
+        }
+
+        let (header, sealed) = bytes.split_at(AKARIN_HEADER_LEN);
+        let client_id = BigEndian::read_u32(&header[..AKARIN_CLIENTID_LEN]);
+        let token = BigEndian::read_u64(&header[AKARIN_CLIENTID_LEN..AKARIN_CLIENTID_LEN + AKARIN_USERTOKEN_LEN]);
+        let epoch = header[AKARIN_HEADER_LEN - AKARIN_EPOCH_LEN];
+        Self::from_sealed(client_id, token, epoch, sealed, nonce_len, tag_len)
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        AKARIN_HEADER_LEN + self.nonce.len() + self.cipher_text.len() + self.tag.len()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        bytes.write_u32::<BigEndian>(self.client_id).unwrap();
+        bytes.write_u64::<BigEndian>(self.token).unwrap();
+        bytes.push(self.epoch);
+        bytes.extend_from_slice(self.nonce);
+        bytes.extend_from_slice(self.cipher_text);
+        bytes.extend_from_slice(self.tag);
+        bytes
+    }
+
+    /// Encrypt `message` with its counter and encode it as a packet, see `Session::seal_in_place`.
+    pub fn seal(client_id: ClientId, token: ClientToken, epoch: u8, counter: u64, message: &[u8], crypto: &Crypto)
+                -> Result<Vec<u8>> {
+        let mut buf = PacketBuf::from_slice(AKARIN_HEADROOM, message, AKARIN_TAILROOM);
+        AkarinPacket::seal_in_place(client_id, token, epoch, counter, &mut buf, crypto)?;
+        Ok(buf.as_slice().to_vec())
+    }
+
+    /// Turn the message in `buf` into a packet, writing the header, counter and nonce in its headroom and the tag in
+    /// its tailroom.
+    pub fn seal_in_place(client_id: ClientId, token: ClientToken, epoch: u8, counter: u64, buf: &mut PacketBuf,
+                         crypto: &Crypto)
+                         -> Result<()> {
+        BigEndian::write_u64(buf.prepend(AKARIN_COUNTER_LEN)?, counter);
+        crypto.encrypt_in_place(buf)?;
+
+        let header = buf.prepend(AKARIN_HEADER_LEN)?;
+        BigEndian::write_u32(&mut header[..AKARIN_CLIENTID_LEN], client_id);
+        BigEndian::write_u64(&mut header[AKARIN_CLIENTID_LEN..AKARIN_CLIENTID_LEN + AKARIN_USERTOKEN_LEN], token);
+        header[AKARIN_HEADER_LEN - AKARIN_EPOCH_LEN] = epoch;
+        Ok(())
+    }
+
+    /// Decrypt the packet in `buf`, leaving the message it carries in place, and return its counter.
+    ///
+    /// The counter is authenticated but not checked, see `Session::open_in_place`.
+    pub fn open_in_place(buf: &mut PacketBuf, crypto: &Crypto) -> Result<u64> {
+        buf.advance(AKARIN_HEADER_LEN)?;
+        crypto.decrypt_in_place(buf)?;
+        if buf.len() < AKARIN_COUNTER_LEN {
+            return Err(ErrorKind::TruncatedMessage(buf.len()).into());
+        }
+        let counter = BigEndian::read_u64(&buf.as_slice()[..AKARIN_COUNTER_LEN]);
+        buf.advance(AKARIN_COUNTER_LEN)?;
+        Ok(counter)
+    }
+
+    /// Decrypt the packet, returning its counter and the message it carries.
+    ///
+    /// The counter is authenticated but not checked, see `Session::open`.
+    pub fn open(&self, crypto: &Crypto) -> Result<(u64, Vec<u8>)> {
+        let mut sealed = Vec::with_capacity(self.nonce.len() + self.cipher_text.len() + self.tag.len());
+        sealed.extend_from_slice(self.nonce);
+        sealed.extend_from_slice(self.cipher_text);
+        sealed.extend_from_slice(self.tag);
+
+        let mut plain_text = crypto.decrypt(&sealed)?;
+        if plain_text.len() < AKARIN_COUNTER_LEN {
+            return Err(ErrorKind::TruncatedMessage(plain_text.len()).into());
+        }
+        let counter = BigEndian::read_u64(&plain_text[..AKARIN_COUNTER_LEN]);
+        Ok((counter, plain_text.split_off(AKARIN_COUNTER_LEN)))
+    }
+}
+
+/// Why the server has turned a client down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectCode {
+    UnsupportedVersion,
+    NoCommonCipher,
+    ForwardSecrecyRequired,
+    UnknownClientKey,
+    HandshakeFailed,
+    Other(u8),
+}
+
+impl RejectCode {
+    pub fn from_u8(code: u8) -> Self {
+        match code {
+            0x01 => RejectCode::UnsupportedVersion,
+            0x02 => RejectCode::NoCommonCipher,
+            0x03 => RejectCode::ForwardSecrecyRequired,
+            0x04 => RejectCode::UnknownClientKey,
+            0x05 => RejectCode::HandshakeFailed,
+            code => RejectCode::Other(code),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            RejectCode::UnsupportedVersion => 0x01,
+            RejectCode::NoCommonCipher => 0x02,
+            RejectCode::ForwardSecrecyRequired => 0x03,
+            RejectCode::UnknownClientKey => 0x04,
+            RejectCode::HandshakeFailed => 0x05,
+            RejectCode::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for RejectCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RejectCode::UnsupportedVersion => write!(f, "unsupported protocol version"),
+            RejectCode::NoCommonCipher => write!(f, "no cipher in common"),
+            RejectCode::ForwardSecrecyRequired => write!(f, "a forward secret handshake is required"),
+            RejectCode::UnknownClientKey => write!(f, "the key of the client is not allowed"),
+            RejectCode::HandshakeFailed => write!(f, "the handshake failed, check the key of the server"),
+            RejectCode::Other(code) => write!(f, "unknown reason {:#04x}", code),
+        }
+    }
+}
+
+/// How `Data` messages are padded to hide the length of the packets they carry.
+///
+/// The more padding, the less the lengths of the datagrams tell and the more bandwidth is spent: `Mtu` hides every
+/// length but makes every datagram as large as the largest one, `Bucket` only tells which bucket a packet falls in,
+/// and `Random` blurs lengths for an average of `(min + max) / 2` bytes a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    None,
+    /// Pad packets to the next multiple of the size.
+    Bucket(u16),
+    /// Pad every packet to the MTU of the tunnel.
+    Mtu,
+    /// Add from `min` to `max` random bytes to every packet.
+    Random(u16, u16),
+}
+
+impl Padding {
+    /// The length to pad a packet of `len` bytes to, never more than `max` nor less than `len`.
+    pub fn padded_len(&self, len: usize, max: usize, random: &SecureRandom) -> Result<usize> {
+        let padded = match *self {
+            Padding::None => len,
+            Padding::Bucket(size) => {
+                let size = size.max(1) as usize;
+                (len + size - 1) / size * size
+            }
+            Padding::Mtu => max,
+            Padding::Random(low, high) => {
+                let range = high.saturating_sub(low) as u32 + 1;
+                len + low as usize + random_below(range, random)? as usize
+            }
+        };
+        Ok(padded.min(max).max(len))
+    }
+}
+
+/// A number below `bound` drawn uniformly: a draw past the last whole multiple of `bound` is drawn again, the numbers
+/// it would end on once reduced would come up more often than the others.
+fn random_below(bound: u32, random: &SecureRandom) -> Result<u32> {
+    let limit = (1u64 << 32) / bound as u64 * bound as u64;
+    let mut bytes = [0u8; 4];
+    loop {
+        random.fill(&mut bytes)?;
+        let value = BigEndian::read_u32(&bytes);
+        if (value as u64) < limit {
+            return Ok(value % bound);
+        }
+    }
+}
+
+impl Default for Padding {
+    fn default() -> Self {
+        Padding::None
+    }
+}
+
+impl FromStr for Padding {
+    type Err = Error;
+
+    /// Parse `none`, `mtu`, `bucket:<size>` or `random:<min>-<max>`.
+    fn from_str(value: &str) -> Result<Self> {
+        let invalid = || Error::from(ErrorKind::InvalidPadding(value.to_string()));
+        let mut parts = value.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("none"), None) => Ok(Padding::None),
+            (Some("mtu"), None) => Ok(Padding::Mtu),
+            (Some("bucket"), Some(size)) => {
+                match size.parse() {
+                    Ok(size) if size > 0 => Ok(Padding::Bucket(size)),
+                    _ => Err(invalid()),
+                }
+            }
+            (Some("random"), Some(range)) => {
+                let mut bounds = range.splitn(2, '-').map(|bound| bound.parse::<u16>());
+                match (bounds.next(), bounds.next()) {
+                    (Some(Ok(min)), Some(Ok(max))) if min <= max => Ok(Padding::Random(min, max)),
+                    _ => Err(invalid()),
+                }
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message<'a> {
+    Data(&'a [u8]),
+    Hello {
+        cookie: ClientToken,
+        version: u8,
+        /// Ids of the ciphers the client supports, see `Ciphers::id`. Unknown ones are ignored.
+        ciphers: &'a [u8],
+        /// First message of the Noise handshake, empty if the client does not ask for one.
+        handshake: &'a [u8],
+    },
+    Welcome {
+        cookie: ClientToken,
+        version: u8,
+        cipher: u8,
+        address: Ipv4Addr,
+        /// Reply to the Noise handshake of the `Hello`, always empty before version 2.
+        handshake: &'a [u8],
+        address_v6: Option<Subnet6>,
+    },
+    Reject {
+        cookie: ClientToken,
+        code: RejectCode,
+        min_version: u8,
+        max_version: u8,
+    },
+    Keepalive,
+    Probe { mtu: u16 },
+    ProbeAck { mtu: u16 },
+}
+
+impl<'a> Message<'a> {
+    /// Turn the IP packet in `buf` into a `Data` message in place, padded with its tailroom as `padding` asks.
+    ///
+    /// Only peers of version 3 and later understand padded messages.
+    pub fn wrap_data(buf: &mut PacketBuf, padding: Padding) -> Result<()> {
+        if padding == Padding::None {
+            buf.prepend(MESSAGE_KIND_LEN)?[0] = MESSAGE_DATA;
+            return Ok(());
+        }
+
+        let len = buf.len();
+        let max = (len + buf.tailroom().saturating_sub(AKARIN_TAILROOM)).min(u16::max_value() as usize);
+        let padded_len = padding.padded_len(len, max, &SystemRandom::new())?;
+        for byte in buf.extend(padded_len - len)?.iter_mut() {
+            *byte = 0;
+        }
+        let header = buf.prepend(PADDED_DATA_LEN)?;
+        header[0] = MESSAGE_PADDED_DATA;
+        BigEndian::write_u16(&mut header[1..], len as u16);
+        Ok(())
+    }
+
+    /// Turn the `Data` message in `buf` into the IP packet it carries in place, dropping its padding.
+    pub fn unwrap_data(buf: &mut PacketBuf) -> Result<()> {
+        match buf.as_slice().first() {
+            Some(&MESSAGE_DATA) => buf.advance(MESSAGE_KIND_LEN),
+            Some(&MESSAGE_PADDED_DATA) => {
+                let len = padded_data_len(buf.as_slice())?;
+                buf.advance(PADDED_DATA_LEN)?;
+                buf.truncate(len);
+                Ok(())
+            }
+            Some(&kind) => Err(ErrorKind::UnknownMessageType(kind).into()),
+            None => Err(ErrorKind::TruncatedMessage(0).into()),
+        }
+    }
+
+    pub fn decode(bytes: &'a [u8]) -> Result<Self> {
+        let (kind, body) = match bytes.split_first() {
+            Some((kind, body)) => (*kind, body),
+            None => return Err(ErrorKind::TruncatedMessage(0).into()),
+        };
+
+        match kind {
+            MESSAGE_DATA => Ok(Message::Data(body)),
+            MESSAGE_PADDED_DATA => {
+                let len = padded_data_len(bytes)?;
+                Ok(Message::Data(&bytes[PADDED_DATA_LEN..PADDED_DATA_LEN + len]))
+            }
+            MESSAGE_HELLO => {
+                if bytes.len() < HELLO_LEN || bytes.len() < HELLO_LEN + body[9] as usize {
+                    return Err(ErrorKind::TruncatedMessage(bytes.len()).into());
+                }
+                let ciphers_end = 10 + body[9] as usize;
+                // Older clients end the message with their ciphers.
+                let handshake = match body.get(ciphers_end) {
+                    Some(&len) => {
+                        body.get(ciphers_end + 1..ciphers_end + 1 + len as usize)
+                            .ok_or_else(|| Error::from(ErrorKind::TruncatedMessage(bytes.len())))?
+                    }
+                    None => &[],
+                };
+                Ok(Message::Hello {
+                       cookie: BigEndian::read_u64(&body[..8]),
+                       version: body[8],
+                       ciphers: &body[10..ciphers_end],
+                       handshake,
+                   })
+            }
+            MESSAGE_WELCOME => {
+                if bytes.len() < WELCOME_LEN {
+                    return Err(ErrorKind::TruncatedMessage(bytes.len()).into());
+                }
+                let (handshake, rest) = if body[8] >= 2 {
+                    let len = *body.get(14).ok_or_else(|| Error::from(ErrorKind::TruncatedMessage(bytes.len())))?;
+                    if body.len() < 15 + len as usize {
+                        return Err(ErrorKind::TruncatedMessage(bytes.len()).into());
+                    }
+                    body[15..].split_at(len as usize)
+                } else {
+                    (&[][..], &body[14..])
+                };
+                let address_v6 = if rest.len() >= WELCOME_V6_LEN - WELCOME_LEN {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(&rest[..16]);
+                    Some(Subnet6::new(Ipv6Addr::from(octets), rest[16])?)
+                } else {
+                    None
+                };
+                Ok(Message::Welcome {
+                       cookie: BigEndian::read_u64(&body[..8]),
+                       version: body[8],
+                       cipher: body[9],
+                       address: Ipv4Addr::from(BigEndian::read_u32(&body[10..14])),
+                       handshake,
+                       address_v6,
+                   })
+            }
+            MESSAGE_REJECT => {
+                if bytes.len() < REJECT_LEN {
+                    return Err(ErrorKind::TruncatedMessage(bytes.len()).into());
+                }
+                Ok(Message::Reject {
+                       cookie: BigEndian::read_u64(&body[..8]),
+                       code: RejectCode::from_u8(body[8]),
+                       min_version: body[9],
+                       max_version: body[10],
+                   })
+            }
+            MESSAGE_KEEPALIVE => Ok(Message::Keepalive),
+            MESSAGE_PROBE => {
+                // A probe is only good if it has come whole.
+                let mtu = padded_data_len(bytes)?;
+                Ok(Message::Probe { mtu: mtu as u16 })
+            }
+            MESSAGE_PROBE_ACK => {
+                if bytes.len() < PROBE_LEN {
+                    return Err(ErrorKind::TruncatedMessage(bytes.len()).into());
+                }
+                Ok(Message::ProbeAck { mtu: BigEndian::read_u16(&body[..2]) })
+            }
+            kind => Err(ErrorKind::UnknownMessageType(kind).into()),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            Message::Data(payload) => {
+                let mut bytes = Vec::with_capacity(1 + payload.len());
+                bytes.push(MESSAGE_DATA);
+                bytes.extend_from_slice(payload);
+                bytes
+            }
+            Message::Hello {
+                cookie,
+                version,
+                ciphers,
+                handshake,
+            } => {
+                let ciphers = &ciphers[..ciphers.len().min(u8::max_value() as usize)];
+                let mut bytes = Vec::with_capacity(HELLO_LEN + ciphers.len() + 1 + handshake.len());
+                bytes.push(MESSAGE_HELLO);
+                bytes.write_u64::<BigEndian>(cookie).unwrap();
+                bytes.push(version);
+                bytes.push(ciphers.len() as u8);
+                bytes.extend_from_slice(ciphers);
+                if !handshake.is_empty() {
+                    bytes.push(handshake.len() as u8);
+                    bytes.extend_from_slice(handshake);
+                }
+                bytes
+            }
+            Message::Welcome {
+                cookie,
+                version,
+                cipher,
+                address,
+                handshake,
+                address_v6,
+            } => {
+                let mut bytes = Vec::with_capacity(WELCOME_V6_LEN + 1 + handshake.len());
+                bytes.push(MESSAGE_WELCOME);
+                bytes.write_u64::<BigEndian>(cookie).unwrap();
+                bytes.push(version);
+                bytes.push(cipher);
+                bytes.write_u32::<BigEndian>(address.into()).unwrap();
+                if version >= 2 {
+                    bytes.push(handshake.len() as u8);
+                    bytes.extend_from_slice(handshake);
+                }
+                if let Some(address_v6) = address_v6 {
+                    bytes.extend_from_slice(&address_v6.address().octets());
+                    bytes.push(address_v6.prefix());
+                }
+                bytes
+            }
+            Message::Reject {
+                cookie,
+                code,
+                min_version,
+                max_version,
+            } => {
+                let mut bytes = Vec::with_capacity(REJECT_LEN);
+                bytes.push(MESSAGE_REJECT);
+                bytes.write_u64::<BigEndian>(cookie).unwrap();
+                bytes.push(code.to_u8());
+                bytes.push(min_version);
+                bytes.push(max_version);
+                bytes
+            }
+            Message::Keepalive => vec![MESSAGE_KEEPALIVE],
+            Message::Probe { mtu } => {
+                let mut bytes = vec![0; PROBE_LEN + mtu as usize];
+                bytes[0] = MESSAGE_PROBE;
+                BigEndian::write_u16(&mut bytes[1..PROBE_LEN], mtu);
+                bytes
+            }
+            Message::ProbeAck { mtu } => {
+                let mut bytes = Vec::with_capacity(PROBE_LEN);
+                bytes.push(MESSAGE_PROBE_ACK);
+                bytes.write_u16::<BigEndian>(mtu).unwrap();
+                bytes
+            }
+        }
+    }
+}
+
+/// The length of the packet of a padded `Data` message.
+fn padded_data_len(bytes: &[u8]) -> Result<usize> {
+    if bytes.len() < PADDED_DATA_LEN {
+        return Err(ErrorKind::TruncatedMessage(bytes.len()).into());
+    }
+    let len = BigEndian::read_u16(&bytes[1..PADDED_DATA_LEN]) as usize;
+    if bytes.len() < PADDED_DATA_LEN + len {
+        return Err(ErrorKind::TruncatedMessage(bytes.len()).into());
+    }
+    Ok(len)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use ring::{aead, error};
+    use crypto::aead_crypto::AeadCrypto;
+    use crypto::kdf::Kdf;
+
+    #[test]
+    fn test_encode_and_decode() {
+        let packet = AkarinPacket {
+            client_id: 0x0a000002,
+            token: 0x0102030405060708,
+            epoch: 3,
+            nonce: &[1u8; 12],
+            cipher_text: b"akarin",
+            tag: &[2u8; 16],
+        };
+
+        let bytes = packet.encode();
+        assert_eq!(bytes.len(), packet.encoded_len());
+        assert_eq!(&bytes[..AKARIN_HEADER_LEN], &[10, 0, 0, 2, 1, 2, 3, 4, 5, 6, 7, 8, 3]);
+        assert_eq!(AkarinPacket::decode(&bytes, 12, 16).unwrap(), packet);
+        assert_eq!(AkarinPacket::peek(&bytes).unwrap(), (0x0a000002, 0x0102030405060708, 3));
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        assert!(AkarinPacket::decode(&[0u8; AKARIN_HEADER_LEN - 1], 0, 0).is_err());
+        assert!(AkarinPacket::decode(&[0u8; AKARIN_HEADER_LEN + 27], 12, 16).is_err());
+
+        let empty = AkarinPacket::decode(&[0u8; AKARIN_HEADER_LEN + 28], 12, 16).unwrap();
+        assert!(empty.cipher_text.is_empty());
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let crypto = AeadCrypto::new(&aead::CHACHA20_POLY1305, b"realityone", &Kdf::Legacy).unwrap();
+        let bytes = AkarinPacket::seal(1, 2, 3, 1, b"akarin", &crypto).unwrap();
+
+        let packet = AkarinPacket::decode(&bytes, crypto.nonce_len(), crypto.tag_len()).unwrap();
+        assert_eq!(packet.client_id, 1);
+        assert_eq!(packet.token, 2);
+        assert_eq!(packet.epoch, 3);
+        assert_eq!(packet.open(&crypto).unwrap(), (1, b"akarin".to_vec()));
+
+        let mut buf = PacketBuf::new(AKARIN_DATA_OFFSET, 6, AKARIN_TAILROOM);
+        buf.tail_mut()[..6].copy_from_slice(b"akarin");
+        buf.extend(6).unwrap();
+        Message::wrap_data(&mut buf, Padding::None).unwrap();
+        AkarinPacket::seal_in_place(1, 2, 3, 4, &mut buf, &crypto).unwrap();
+        assert_eq!((buf.headroom(), buf.tailroom()), (PADDED_DATA_LEN - MESSAGE_KIND_LEN, 0));
+        assert_eq!(AkarinPacket::peek(buf.as_slice()).unwrap(), (1, 2, 3));
+
+        let mut opened = PacketBuf::from_slice(0, buf.as_slice(), 0);
+        assert_eq!(AkarinPacket::open_in_place(&mut opened, &crypto).unwrap(), 4);
+        assert_eq!(Message::decode(opened.as_slice()).unwrap(), Message::Data(b"akarin"));
+        Message::unwrap_data(&mut opened).unwrap();
+        assert_eq!(opened.as_slice(), b"akarin");
+
+        let mut tampered = bytes.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let packet = AkarinPacket::decode(&tampered, crypto.nonce_len(), crypto.tag_len()).unwrap();
+        assert!(packet.open(&crypto).is_err());
+    }
+
+    #[test]
+    fn test_message() {
+        let messages = [
+            Message::Data(b"akarin"),
+            Message::Hello {
+                cookie: 42,
+                version: PROTOCOL_VERSION,
+                ciphers: &[2, 1],
+                handshake: &[],
+            },
+            Message::Hello {
+                cookie: 42,
+                version: PROTOCOL_VERSION,
+                ciphers: &[1],
+                handshake: &[7; 48],
+            },
+            Message::Welcome {
+                cookie: 42,
+                version: PROTOCOL_VERSION,
+                cipher: 2,
+                address: Ipv4Addr::new(10, 0, 0, 2),
+                handshake: &[],
+                address_v6: None,
+            },
+            Message::Welcome {
+                cookie: 42,
+                version: PROTOCOL_VERSION,
+                cipher: 1,
+                address: Ipv4Addr::new(10, 0, 0, 2),
+                handshake: &[7; 48],
+                address_v6: Some("fd00::2/64".parse().unwrap()),
+            },
+            Message::Welcome {
+                cookie: 42,
+                version: 1,
+                cipher: 1,
+                address: Ipv4Addr::new(10, 0, 0, 2),
+                handshake: &[],
+                address_v6: Some("fd00::2/64".parse().unwrap()),
+            },
+            Message::Reject {
+                cookie: 42,
+                code: RejectCode::NoCommonCipher,
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: PROTOCOL_VERSION,
+            },
+            Message::Keepalive,
+            Message::Probe { mtu: 1400 },
+            Message::ProbeAck { mtu: 1400 },
+        ];
+        for message in messages.iter() {
+            assert_eq!(&Message::decode(&message.encode()).unwrap(), message);
+        }
+
+        assert!(Message::decode(&[]).is_err());
+        assert!(Message::decode(&[MESSAGE_HELLO]).is_err());
+        assert!(Message::decode(&[MESSAGE_HELLO, 0, 0, 0, 0, 0, 0, 0, 42, 1, 2, 1]).is_err());
+        assert!(Message::decode(&[MESSAGE_HELLO, 0, 0, 0, 0, 0, 0, 0, 42, 2, 1, 1, 4, 0]).is_err());
+        assert!(Message::decode(&[MESSAGE_WELCOME, 0, 0]).is_err());
+        assert!(Message::decode(&[MESSAGE_WELCOME, 0, 0, 0, 0, 0, 0, 0, 42, 2, 1, 10, 0, 0, 2]).is_err());
+        assert!(Message::decode(&[0xff]).is_err());
+        assert_eq!(Message::Keepalive.encode(), vec![MESSAGE_KEEPALIVE]);
+        // A probe is as long as a padded `Data` carrying a packet of its MTU.
+        assert_eq!(Message::Probe { mtu: 1400 }.encode().len(), PADDED_DATA_LEN + 1400);
+        assert!(Message::decode(&Message::Probe { mtu: 1400 }.encode()[..1000]).is_err());
+        assert!(Message::decode(&[MESSAGE_PROBE_ACK, 5]).is_err());
+        assert_eq!(overhead(Ciphers::AES_256_GCM), AKARIN_HEADER_LEN + 12 + AKARIN_COUNTER_LEN + PADDED_DATA_LEN + 16);
+    }
+
+    /// Fills with the bytes it is given, in order.
+    struct Draws(RefCell<Vec<u8>>);
+
+    impl SecureRandom for Draws {
+        fn fill(&self, dest: &mut [u8]) -> ::std::result::Result<(), error::Unspecified> {
+            let bytes: Vec<u8> = self.0.borrow_mut().drain(..dest.len()).collect();
+            dest.copy_from_slice(&bytes);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_random_below() {
+        // The highest draw is past the last multiple of 3, it is drawn again.
+        let draws = Draws(RefCell::new(vec![0xff, 0xff, 0xff, 0xff, 0, 0, 0, 5]));
+        assert_eq!(random_below(3, &draws).unwrap(), 2);
+        let draws = Draws(RefCell::new(vec![0xff, 0xff, 0xff, 0xff]));
+        assert_eq!(random_below(1 << 16, &draws).unwrap(), 0xffff);
+
+        let draws = Draws(RefCell::new(vec![0xff, 0xff, 0xff, 0xff, 0, 0, 0, 4]));
+        assert_eq!(Padding::Random(10, 12).padded_len(100, 1400, &draws).unwrap(), 111);
+    }
+
+    #[test]
+    fn test_padding() {
+        let random = SystemRandom::new();
+        assert_eq!(Padding::None.padded_len(100, 1400, &random).unwrap(), 100);
+        assert_eq!(Padding::Bucket(256).padded_len(100, 1400, &random).unwrap(), 256);
+        assert_eq!(Padding::Bucket(256).padded_len(512, 1400, &random).unwrap(), 512);
+        assert_eq!(Padding::Bucket(256).padded_len(1300, 1400, &random).unwrap(), 1400);
+        assert_eq!(Padding::Mtu.padded_len(100, 1400, &random).unwrap(), 1400);
+        for _ in 0..64 {
+            let len = Padding::Random(10, 20).padded_len(100, 1400, &random).unwrap();
+            assert!(len >= 110 && len <= 120);
+        }
+
+        assert_eq!("none".parse::<Padding>().unwrap(), Padding::None);
+        assert_eq!("mtu".parse::<Padding>().unwrap(), Padding::Mtu);
+        assert_eq!("bucket:128".parse::<Padding>().unwrap(), Padding::Bucket(128));
+        assert_eq!("random:0-64".parse::<Padding>().unwrap(), Padding::Random(0, 64));
+        for value in &["", "bucket", "bucket:0", "mtu:1", "random:64-0", "random:8", "fixed"] {
+            assert!(value.parse::<Padding>().is_err(), "{} should not parse", value);
+        }
+
+        let mut buf = PacketBuf::new(AKARIN_DATA_OFFSET, 64, AKARIN_TAILROOM);
+        buf.tail_mut()[..6].copy_from_slice(b"akarin");
+        buf.extend(6).unwrap();
+        Message::wrap_data(&mut buf, Padding::Bucket(32)).unwrap();
+        assert_eq!(buf.len(), PADDED_DATA_LEN + 32);
+        assert_eq!(Message::decode(buf.as_slice()).unwrap(), Message::Data(b"akarin"));
+        Message::unwrap_data(&mut buf).unwrap();
+        assert_eq!(buf.as_slice(), b"akarin");
+
+        let mut buf = PacketBuf::new(AKARIN_DATA_OFFSET, 64, AKARIN_TAILROOM);
+        buf.extend(6).unwrap();
+        Message::wrap_data(&mut buf, Padding::Mtu).unwrap();
+        assert_eq!((buf.len(), buf.tailroom()), (PADDED_DATA_LEN + 64, AKARIN_TAILROOM));
+
+        assert!(Message::decode(&[MESSAGE_PADDED_DATA, 0]).is_err());
+        assert!(Message::decode(&[MESSAGE_PADDED_DATA, 0, 4, 1, 2, 3]).is_err());
+    }
+}
@@ -0,0 +1,203 @@
+//! Sizing the tun to the path between a client and the server.
+//!
+//! A packet of the tun travels in a datagram which adds `packet::overhead` and the headers of the transport to it,
+//! the MTU of the tun is the MTU of the path less these. The MTU of the path is first taken from the interface the
+//! server is routed through, which knows nothing of the links further on, so a client then probes the path in the
+//! way of RFC 8899: it sends probes as long as the datagrams of the packets of an MTU, and the largest MTU whose
+//! probes are acknowledged is the MTU of its tun.
+//!
+//! A packet read from the tun which does not fit the MTU of the path it is sent over is dropped, and its sender is
+//! told the MTU with an ICMP error written back to the tun, so that it sends smaller packets.
+
+use std::io;
+
+use super::packet::overhead;
+use crypto::Ciphers;
+use transport::Endpoint;
+use transport::network::packet_too_big;
+use tun::os::tokio::Device;
+
+/// The smallest MTU the search goes down to, the one IPv6 requires of every link.
+pub const MIN_PROBE_MTU: usize = 1280;
+/// Seconds between two probes.
+pub const PROBE_INTERVAL: u64 = 10;
+/// Probes of an MTU sent before giving up on it.
+const PROBE_ATTEMPTS: u32 = 3;
+/// The search ends once it has narrowed the MTU down to this many bytes.
+const PROBE_PRECISION: usize = 16;
+/// Acknowledged probes of the MTU found before searching again for a larger one, in case the path has changed.
+const RAISE_PROBES: u32 = 60;
+
+/// The MTU of a tun whose packets are sent to `endpoint` over a path of `path_mtu`, sealed with any of `ciphers`.
+pub fn tun_mtu(path_mtu: usize, ciphers: &[Ciphers], endpoint: &Endpoint) -> usize {
+    let overhead = ciphers.iter().map(|cipher| overhead(*cipher)).max().unwrap_or(0) + endpoint.overhead();
+    path_mtu.saturating_sub(overhead)
+}
+
+/// Drop `packet`, read from `tun` and larger than `mtu`, telling its sender the MTU.
+pub fn reject_packet(tun: &mut Device, packet: &[u8], mtu: usize) -> io::Result<()> {
+    debug!("Dropping packet of {} bytes, larger than the MTU of {}", packet.len(), mtu);
+    let reply = match packet_too_big(packet, mtu) {
+        Some(reply) => reply,
+        None => return Ok(()),
+    };
+    match tun.write(&reply) {
+        Ok(_) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The search for the largest MTU of a tun whose packets get through the path.
+///
+/// Every `PROBE_INTERVAL`, the current MTU is probed until it is acknowledged, then the largest MTU and MTUs between
+/// the current one and the smallest one known to be lost, halving the range each time. An MTU is lost after
+/// `PROBE_ATTEMPTS` probes of it have not been acknowledged, which lowers the current MTU if it is not above it.
+#[derive(Debug)]
+pub struct MtuSearch {
+    mtu: usize,
+    max: usize,
+    // The largest MTU known to get through, and the smallest one known not to.
+    floor: usize,
+    ceiling: usize,
+    // The MTU waiting for an acknowledgement, and the number of probes sent of it.
+    probe: Option<usize>,
+    attempts: u32,
+    // Probes acknowledged since the search has ended.
+    confirmed: u32,
+}
+
+impl MtuSearch {
+    /// Start a search from `max`, the MTU never grows past it.
+    pub fn new(max: usize) -> Self {
+        MtuSearch {
+            mtu: max,
+            max,
+            floor: MIN_PROBE_MTU.min(max),
+            ceiling: max + 1,
+            probe: None,
+            attempts: 0,
+            confirmed: 0,
+        }
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// The MTU to send the next probe of. The probe sent before is lost unless it has been acknowledged since.
+    pub fn next_probe(&mut self) -> usize {
+        if let Some(probe) = self.probe {
+            if self.attempts < PROBE_ATTEMPTS {
+                self.attempts += 1;
+                return probe;
+            }
+            self.lose(probe);
+        }
+
+        let probe = if self.mtu > self.floor || self.ceiling - self.floor <= PROBE_PRECISION {
+            self.mtu
+        } else if self.ceiling > self.max {
+            self.max
+        } else {
+            (self.floor + self.ceiling) / 2
+        };
+        self.probe = Some(probe);
+        self.attempts = 1;
+        probe
+    }
+
+    /// A probe of `mtu` has got through, the MTU grows to it if it is larger.
+    pub fn acknowledge(&mut self, mtu: usize) {
+        if mtu > self.max {
+            return;
+        }
+        if self.probe == Some(mtu) {
+            self.probe = None;
+        }
+        self.floor = self.floor.max(mtu);
+        self.ceiling = self.ceiling.max(mtu + 1);
+        self.mtu = self.mtu.max(mtu);
+
+        if self.ceiling - self.floor <= PROBE_PRECISION && mtu == self.mtu {
+            self.confirmed += 1;
+            if self.confirmed >= RAISE_PROBES {
+                self.confirmed = 0;
+                self.ceiling = self.max + 1;
+            }
+        }
+    }
+
+    /// Lower the MTU to at most `mtu`, the host has learned that larger packets do not get through.
+    pub fn lower(&mut self, mtu: usize) {
+        self.probe = None;
+        self.confirmed = 0;
+        self.ceiling = self.ceiling.min(mtu + 1);
+        self.floor = self.floor.min(mtu);
+        self.mtu = self.mtu.min(mtu);
+    }
+
+    fn lose(&mut self, mtu: usize) {
+        self.probe = None;
+        self.confirmed = 0;
+        self.ceiling = self.ceiling.min(mtu);
+        // The path has shrunk below an MTU which used to get through.
+        if mtu <= self.floor {
+            self.floor = MIN_PROBE_MTU.min(self.max).min(mtu);
+        }
+        if mtu <= self.mtu {
+            self.mtu = (self.floor + mtu) / 2;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Probe a path carrying packets of up to `path` bytes, returns the MTU found.
+    fn search(search: &mut MtuSearch, path: usize, probes: usize) -> usize {
+        for _ in 0..probes {
+            let probe = search.next_probe();
+            if probe <= path {
+                search.acknowledge(probe);
+            }
+        }
+        search.mtu()
+    }
+
+    #[test]
+    fn test_tun_mtu() {
+        let endpoint = Endpoint::Udp("192.0.2.1:8964".parse().unwrap());
+        assert_eq!(tun_mtu(1500, &[Ciphers::AES_256_GCM], &endpoint), 1500 - 28 - overhead(Ciphers::AES_256_GCM));
+        assert_eq!(tun_mtu(1500, Ciphers::ALL, &endpoint), tun_mtu(1500, &[Ciphers::CHACHA20_POLY1305], &endpoint));
+        assert_eq!(tun_mtu(20, Ciphers::ALL, &endpoint), 0);
+    }
+
+    #[test]
+    fn test_mtu_search() {
+        // A path carrying the largest MTU keeps it.
+        let mut mtu = MtuSearch::new(1440);
+        assert_eq!(search(&mut mtu, 1500, 100), 1440);
+
+        // The search narrows down a smaller path, and never settles above it.
+        let mut mtu = MtuSearch::new(1440);
+        let found = search(&mut mtu, 1350, 100);
+        assert!(found <= 1350 && found > 1350 - PROBE_PRECISION, "found {}", found);
+
+        // The path shrinks, and then grows again.
+        let found = search(&mut mtu, 1300, 100);
+        assert!(found <= 1300 && found > 1300 - PROBE_PRECISION, "found {}", found);
+        assert_eq!(search(&mut mtu, 1500, 100), 1440);
+
+        // A path smaller than the smallest MTU probed still gets the smallest MTU.
+        let mut mtu = MtuSearch::new(1440);
+        assert_eq!(search(&mut mtu, 1000, 100), MIN_PROBE_MTU);
+
+        let mut mtu = MtuSearch::new(1440);
+        mtu.lower(1400);
+        assert_eq!(mtu.mtu(), 1400);
+        assert_eq!(search(&mut mtu, 1500, 1), 1400);
+    }
+}
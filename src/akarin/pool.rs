@@ -0,0 +1,89 @@
+use std::collections::{HashSet, VecDeque};
+use std::iter::FromIterator;
+use std::net::Ipv4Addr;
+
+use common::error::*;
+use common::subnet::Subnet;
+
+/// The inner addresses the server leases to its clients.
+///
+/// The pool holds every host address of the subnet except the gateway, which is the first host, and the reserved
+/// addresses such as the address of the server itself.
+#[derive(Debug)]
+pub struct AddressPool {
+    subnet: Subnet,
+    reserved: HashSet<Ipv4Addr>,
+    // The addresses are handed out in order, `next` is the first one never leased so far.
+    next: u64,
+    released: VecDeque<Ipv4Addr>,
+}
+
+impl AddressPool {
+    pub fn new(subnet: Subnet, reserved: &[Ipv4Addr]) -> Self {
+        let mut reserved = HashSet::from_iter(reserved.iter().cloned());
+        reserved.insert(subnet.first_host());
+
+        AddressPool {
+            subnet,
+            reserved,
+            next: u32::from(subnet.first_host()) as u64,
+            released: VecDeque::new(),
+        }
+    }
+
+    pub fn subnet(&self) -> Subnet {
+        self.subnet
+    }
+
+    /// Lease an address, fails with `MaxClientExceed` if the pool is exhausted.
+    pub fn acquire(&mut self) -> Result<Ipv4Addr> {
+        // Reuse the address released the longest ago, so that stale packets rarely reach a new client.
+        if let Some(address) = self.released.pop_front() {
+            return Ok(address);
+        }
+
+        while self.next <= u32::from(self.subnet.last_host()) as u64 {
+            let address = Ipv4Addr::from(self.next as u32);
+            self.next += 1;
+            if !self.reserved.contains(&address) {
+                return Ok(address);
+            }
+        }
+        Err(ErrorKind::MaxClientExceed.into())
+    }
+
+    /// Give back an address returned by `acquire`, it must not be released twice.
+    pub fn release(&mut self, address: Ipv4Addr) {
+        if self.subnet.contains(address) && !self.reserved.contains(&address) {
+            self.released.push_back(address);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_pool() {
+        let subnet = "10.8.0.5/29".parse::<Subnet>().unwrap();
+        let mut pool = AddressPool::new(subnet, &[Ipv4Addr::new(10, 8, 0, 5)]);
+
+        let leased: Vec<Ipv4Addr> = (0..4).map(|_| pool.acquire().unwrap()).collect();
+        assert_eq!(leased,
+                   vec![Ipv4Addr::new(10, 8, 0, 2),
+                        Ipv4Addr::new(10, 8, 0, 3),
+                        Ipv4Addr::new(10, 8, 0, 4),
+                        Ipv4Addr::new(10, 8, 0, 6)]);
+        assert!(pool.acquire().is_err());
+
+        pool.release(Ipv4Addr::new(10, 8, 0, 3));
+        pool.release(Ipv4Addr::new(10, 8, 0, 1));
+        assert_eq!(pool.acquire().unwrap(), Ipv4Addr::new(10, 8, 0, 3));
+        assert!(pool.acquire().is_err());
+
+        let mut pool = AddressPool::new("10.8.0.1/32".parse().unwrap(), &[]);
+        assert!(pool.acquire().is_err());
+    }
+}
@@ -1,99 +1,189 @@
-use std::{fmt, io, mem};
-use std::collections::HashSet;
-use std::iter::FromIterator;
-use std::net::SocketAddr;
-use std::ops::Range;
-
-use futures::{Future, Poll};
-use tokio_core::net::UdpSocket;
-use tokio_core::reactor::{Core, Handle};
+use std::{fmt, fs, io, mem};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::{Async, Future, Poll, Stream};
+use futures::sync::oneshot;
+use ring::rand::SystemRandom;
+use tokio_core::reactor::{Core, Handle, Interval};
 use transient_hashmap::TransientHashMap;
 
-use super::{Server, State, new_buf};
-use super::configuration::ServerConfiguration;
+use super::{ClientId, ClientMetadata, ClientToken, Server, State, new_buf, new_token};
+use super::configuration::{DEFAULT_CLIENT_TIMEOUT, ServerConfiguration};
+use super::packet::{AKARIN_DATA_OFFSET, AkarinPacket, MIN_PROTOCOL_VERSION, Message, PROTOCOL_VERSION, RejectCode};
+use super::pmtu::{reject_packet, tun_mtu};
+use super::pool::AddressPool;
+use super::session::{DEFAULT_REPLAY_WINDOW, HANDSHAKE_PROLOGUE, Session, handshake_psk};
+use super::user::{DEFAULT_USER, User, UserId, UserTable};
+use common::buf::PacketBuf;
 use common::error::*;
-use crypto::Crypto;
-use transport::network::{IPV4_HEADER_LEN, IPv4Header};
+use common::subnet::Subnet6;
+use crypto::{Ciphers, HANDSHAKE_CIPHER};
+use crypto::noise::{Handshake, Pattern};
+use transport::{Endpoint, Transport};
+use transport::mtu::{is_too_large, path_mtu};
+use transport::network::{IPHeader, Ipv4Packet, clamp_mss};
+use tun::Tun;
 use tun::os::tokio::Device;
 
-type ClientId = u32;
-type ClientToken = u64;
-type ClientMetadata = (ClientToken, SocketAddr);
+/// Seconds between two checks of the users file.
+const USERS_RELOAD_INTERVAL: u64 = 5;
+/// Seconds between two reports of the datagrams of a user which have failed to authenticate.
+const FAILURE_REPORT_INTERVAL: u64 = 60;
 
-#[derive(Debug)]
-pub struct AkarinServer<'a> {
+pub struct AkarinServer {
     tun: Device,
-    udp: UdpSocket,
+    transport: Box<Transport>,
 
-    crypto: &'a Crypto,
+    configuration: ServerConfiguration,
+    users: UserTable,
+    users_modified: Option<SystemTime>,
+    reload_timer: Interval,
+    // Users being reloaded on a thread of their own, so that deriving their keys does not hold up forwarding.
+    reloading: Option<oneshot::Receiver<Result<UserTable>>>,
 
     clients: ClientStorage,
+    failures: AuthFailures,
+
+    mtu: usize,
+    mss_clamping: bool,
+
+    // Pending datagrams and packets are kept in the buffer they have been read into, until they are written out.
+    tun_buf: PacketBuf,
+    transport_buf: PacketBuf,
 
-    tun_buf: Vec<u8>,
-    udp_buf: Vec<u8>,
+    to_transport: Option<(ClientId, Endpoint)>,
+    to_tun: bool,
 
     state: State,
 }
 
+/// Clients registered to the server.
+///
+/// Every client leases an address of the tunnel subnet for as long as it stays registered, packets read from the
+/// tun are routed to the client holding their destination address.
 pub struct ClientStorage {
-    id_set: HashSet<ClientId>,
+    pool: AddressPool,
+    last_id: ClientId,
     storage: TransientHashMap<ClientId, ClientMetadata>,
+    owners: HashMap<ClientId, UserId>,
+    cookies: HashMap<(UserId, ClientToken), ClientId>,
+    sessions: HashMap<ClientId, Session>,
+    handshakes: HashMap<ClientId, Vec<u8>>,
+    leases: HashMap<ClientId, Ipv4Addr>,
+    addresses: HashMap<Ipv4Addr, ClientId>,
+    addresses_v6: HashMap<Ipv6Addr, ClientId>,
+    mtus: HashMap<ClientId, usize>,
+    tcp_endpoints: HashMap<ClientId, Endpoint>,
+    // TCP endpoints left by clients, see `disconnected`.
+    disconnected: Vec<Endpoint>,
 }
 
 impl ClientStorage {
-    pub fn new(id_range: Range<ClientId>, lifetime: u32) -> Self {
+    pub fn new(pool: AddressPool, lifetime: u32) -> Self {
         ClientStorage {
-            id_set: HashSet::from_iter(id_range.into_iter()),
+            pool,
+            last_id: 0,
             storage: TransientHashMap::new(lifetime),
+            owners: HashMap::new(),
+            cookies: HashMap::new(),
+            sessions: HashMap::new(),
+            handshakes: HashMap::new(),
+            leases: HashMap::new(),
+            addresses: HashMap::new(),
+            addresses_v6: HashMap::new(),
+            mtus: HashMap::new(),
+            tcp_endpoints: HashMap::new(),
+            disconnected: Vec::new(),
         }
     }
 
-    pub fn available_ids(&self) -> Vec<ClientId> {
-        self.id_set.iter().map(|i| *i).collect()
+    fn next_id(&mut self) -> ClientId {
+        // Zero is the id of a client which has not registered yet.
+        loop {
+            self.last_id = self.last_id.wrapping_add(1);
+            if self.last_id != 0 && !self.leases.contains_key(&self.last_id) {
+                return self.last_id;
+            }
+        }
     }
 
-    pub unsafe fn reserve_id(&mut self, id: ClientId) -> Result<ClientId> {
-        if !self.id_set.remove(&id) {
-            return Err(ErrorKind::ReserveClientIDFailed.into());
+    /// Register the client of a `Hello` with `cookie`.
+    ///
+    /// A `Hello` sent again, or replayed, gets the client registered by the first one.
+    pub fn register_client(&mut self, user: UserId, cookie: ClientToken, meta: &ClientMetadata) -> Result<ClientId> {
+        if let Some(id) = self.cookies.get(&(user, cookie)).cloned() {
+            if self.storage.contains_key(&id) {
+                return Ok(id);
+            }
         }
+
+        let id = self.insert_client(user, meta)?;
+        self.cookies.insert((user, cookie), id);
         Ok(id)
     }
 
-    fn next_id(&self) -> Option<&ClientId> {
-        self.id_set.iter().nth(0)
-    }
+    /// Register a client of `user` and lease it an address, fails with `MaxClientExceed` if the pool is exhausted.
+    pub fn insert_client(&mut self, user: UserId, meta: &ClientMetadata) -> Result<ClientId> {
+        let address = self.pool.acquire()?;
+        let id = self.next_id();
 
-    pub fn insert_client(&mut self, meta: &ClientMetadata) -> Result<ClientId> {
-        let id = {
-            match self.next_id() {
-                Some(id) => id.clone(),
-                None => return Err(ErrorKind::MaxClientExceed.into()),
-            }
-        };
-        self.id_set.remove(&id);
+        self.owners.insert(id, user);
+        self.leases.insert(id, address);
+        self.addresses.insert(address, id);
         self.storage.insert(id, *meta);
+        self.set_endpoint(id, meta.1);
 
         Ok(id)
     }
 
+    /// Keep a client registered, moving it to the endpoint of `meta` if it has changed. The token must be the one
+    /// of the client.
     pub fn refresh_client(&mut self, id: ClientId, meta: &ClientMetadata) -> Result<()> {
-        if !self.compare_client(id, meta) {
-            return Err(ErrorKind::NoSuchClientID.into());
+        match self.storage.get(&id) {
+            Some(&(token, endpoint)) if token == meta.0 => {
+                // The path to a client which has moved is another one.
+                if endpoint != meta.1 {
+                    self.mtus.remove(&id);
+                }
+            }
+            _ => return Err(ErrorKind::NoSuchClientID.into()),
         }
 
         self.storage.insert(id, *meta);
+        self.set_endpoint(id, meta.1);
         Ok(())
     }
 
+    /// Track the TCP endpoint of a client, the endpoint it leaves is disconnected.
+    fn set_endpoint(&mut self, id: ClientId, endpoint: Endpoint) {
+        let previous = match endpoint {
+            Endpoint::Tcp(_) => self.tcp_endpoints.insert(id, endpoint),
+            Endpoint::Udp(_) => self.tcp_endpoints.remove(&id),
+        };
+        match previous {
+            Some(previous) if previous != endpoint => self.disconnected.push(previous),
+            _ => {}
+        }
+    }
+
+    /// The TCP endpoints of clients removed, or moved elsewhere, since the last call. No client uses their
+    /// connections anymore.
+    pub fn disconnected(&mut self) -> Vec<Endpoint> {
+        let mut disconnected = mem::replace(&mut self.disconnected, Vec::new());
+        let tcp_endpoints = &self.tcp_endpoints;
+        disconnected.retain(|endpoint| !tcp_endpoints.values().any(|e| e == endpoint));
+        disconnected
+    }
+
     pub fn get(&mut self, id: ClientId) -> Option<&ClientMetadata> {
         self.storage.get(&id)
     }
 
     pub fn compare_client(&mut self, id: ClientId, meta: &ClientMetadata) -> bool {
-        if self.id_set.contains(&id) {
-            return false;
-        }
-
         if let Some(ref stored) = self.storage.get(&id) {
             if stored == &meta {
                 return true;
@@ -102,14 +192,105 @@ impl ClientStorage {
         false
     }
 
+    /// Attach the session derived for a newly registered client, and the reply to its handshake, which is sent
+    /// again if the client asks again.
+    pub fn start_session(&mut self, id: ClientId, session: Session, handshake: Vec<u8>) {
+        if self.storage.contains_key(&id) {
+            self.sessions.insert(id, session);
+            self.handshakes.insert(id, handshake);
+        }
+    }
+
+    /// The reply to the handshake of the client, empty if it has not done one.
+    pub fn handshake(&self, id: ClientId) -> Option<&[u8]> {
+        self.handshakes.get(&id).map(|handshake| handshake.as_slice())
+    }
+
+    pub fn session(&mut self, id: ClientId) -> Option<&mut Session> {
+        self.sessions.get_mut(&id)
+    }
+
+    /// The user the client has registered as.
+    pub fn user(&self, id: ClientId) -> Option<UserId> {
+        self.owners.get(&id).cloned()
+    }
+
+    /// The inner address leased to the client.
+    pub fn lease(&self, id: ClientId) -> Option<Ipv4Addr> {
+        self.leases.get(&id).cloned()
+    }
+
+    pub fn find_address(&self, address: &Ipv4Addr) -> Option<ClientId> {
+        self.addresses.get(address).cloned()
+    }
+
+    /// Route packets to `address` to the client.
+    pub fn set_address_v6(&mut self, id: ClientId, address: Ipv6Addr) {
+        self.addresses_v6.retain(|_, i| *i != id);
+        self.addresses_v6.insert(address, id);
+    }
+
+    pub fn find_address_v6(&self, address: &Ipv6Addr) -> Option<ClientId> {
+        self.addresses_v6.get(address).cloned()
+    }
+
+    /// Whether `address` is the inner IPv4 or IPv6 address of the client.
+    pub fn owns_address(&self, id: ClientId, address: &IpAddr) -> bool {
+        match *address {
+            IpAddr::V4(ref address) => self.lease(id) == Some(*address),
+            IpAddr::V6(ref address) => self.find_address_v6(address) == Some(id),
+        }
+    }
+
+    /// Reject packets to the client larger than `mtu`, the MTU of the path to it is smaller than the MTU of the tun.
+    pub fn set_mtu(&mut self, id: ClientId, mtu: usize) {
+        if self.storage.contains_key(&id) {
+            self.mtus.insert(id, mtu);
+        }
+    }
+
+    pub fn mtu(&self, id: ClientId) -> Option<usize> {
+        self.mtus.get(&id).cloned()
+    }
+
     pub fn remove_client(&mut self, id: ClientId) {
-        self.id_set.insert(id);
         self.storage.remove(&id);
+        self.release(id);
+    }
+
+    /// Remove every client of the user.
+    pub fn remove_user(&mut self, user: UserId) -> usize {
+        let ids: Vec<ClientId> = self.owners.iter().filter(|&(_, u)| *u == user).map(|(id, _)| *id).collect();
+        for id in ids.iter() {
+            self.remove_client(*id);
+        }
+        ids.len()
     }
 
     pub fn prune(&mut self) {
-        for id in self.storage.prune().iter() {
-            self.id_set.insert(*id);
+        for id in self.storage.prune() {
+            self.release(id);
+        }
+    }
+
+    fn release(&mut self, id: ClientId) {
+        self.owners.remove(&id);
+        self.cookies.retain(|_, i| *i != id);
+        if let Some(session) = self.sessions.remove(&id) {
+            let window = session.window();
+            if window.replayed() > 0 || window.outdated() > 0 {
+                info!("Client {} sent {} replayed and {} outdated packets", id, window.replayed(), window.outdated());
+            }
+        }
+        self.handshakes.remove(&id);
+        if let Some(address) = self.leases.remove(&id) {
+            self.addresses.remove(&address);
+            self.pool.release(address);
+        }
+        self.addresses_v6.retain(|_, i| *i != id);
+        self.mtus.remove(&id);
+        if let Some(endpoint) = self.tcp_endpoints.remove(&id) {
+            self.disconnected.push(endpoint);
         }
     }
 }
@@ -120,48 +301,725 @@ impl fmt::Debug for ClientStorage {
     }
 }
 
-impl<'a> AkarinServer<'a> {
-    fn new<'b>(tun: Device, crypto: &'a Crypto, udp: UdpSocket, configuration: &'b ServerConfiguration) -> Self {
-        AkarinServer {
-            tun,
-            crypto,
-            udp,
+/// Datagrams which have failed to authenticate, counted for each user. They are reported at most once every
+/// `FAILURE_REPORT_INTERVAL`, so that forged datagrams can not flood the logs.
+#[derive(Debug, Default)]
+struct AuthFailures {
+    // Failures not reported yet, and when the last report was.
+    failures: HashMap<UserId, (u64, Instant)>,
+}
 
-            clients: ClientStorage::new(0..255, configuration.client_timeout.unwrap_or(60)),
+impl AuthFailures {
+    /// Count a failure of `user`, returns the failures to report once a report is due.
+    fn count(&mut self, user: UserId) -> Option<u64> {
+        let now = Instant::now();
+        if let Some(&mut (ref mut failures, ref mut reported)) = self.failures.get_mut(&user) {
+            *failures += 1;
+            if now.duration_since(*reported) < Duration::from_secs(FAILURE_REPORT_INTERVAL) {
+                return None;
+            }
+            *reported = now;
+            return Some(mem::replace(failures, 0));
+        }
+        self.failures.insert(user, (0, now));
+        Some(1)
+    }
+}
 
-            tun_buf: new_buf(configuration.mtu.unwrap_or(1432) as usize),
-            udp_buf: new_buf(configuration.mtu.unwrap_or(1432) as usize),
+/// Build the users allowed to connect from the shared `password` and the users file, users of `known` keep their keys
+/// unless their password has changed.
+fn load_users(configuration: &ServerConfiguration, known: &UserTable) -> Result<UserTable> {
 
-            state: State::Down,
+    let kdfs = configuration.kdfs()?;
+    let mut users = UserTable::new();
+    if let Some(ref password) = configuration.password {
+        let mut user = known.derive(DEFAULT_USER, "default", password, &kdfs);
+        if let Some(ref public_keys) = configuration.public_keys {
+            user.set_public_keys(public_keys);
         }
+        users.insert(user);
     }
+    if let Some(ref path) = configuration.users {
+        users.load_file(path, &kdfs, known)?;
+        if users.has_public_keys() && configuration.private_key.is_none() {
+            let reason = "`public_keys` of users require the `private_key` of the server".to_string();
+            return Err(ErrorKind::InvalidConfigFile(path.display().to_string(), reason).into());
+        }
+    }
+    Ok(users)
 }
 
-impl<'a> Future for AkarinServer<'a> {
+/// Read the first message of the Noise handshake of a client of `user`, `key` is the key of the user the client has
+/// sealed its `Hello` with. Returns `None` for a client without a handshake.
+///
+/// A user with static keys only accepts clients identified by one of them.
+fn accept_handshake(configuration: &ServerConfiguration, user: &User, key: usize, handshake: &[u8],
+                    peer: Endpoint)
+                    -> ::std::result::Result<Option<Handshake>, RejectCode> {
+    let (pattern, message) = match handshake.split_first() {
+        Some((pattern, message)) => (*pattern, message),
+        None if !user.public_keys().is_empty() => return Err(RejectCode::UnknownClientKey),
+        None if configuration.forward_secrecy.unwrap_or(false) => return Err(RejectCode::ForwardSecrecyRequired),
+        None => return Ok(None),
+    };
+
+    let psk = handshake_psk(&*user.keys()[key]);
+    let responder = match Pattern::from_id(pattern) {
+        Some(pattern) => {
+            Handshake::responder(pattern, &psk, HANDSHAKE_PROLOGUE, configuration.private_key.clone())
+                .and_then(|mut responder| responder.read_message(message).map(|_| responder))
+        }
+        None => Err(ErrorKind::HandshakeFailed(format!("unknown pattern {:#04x}", pattern)).into()),
+    };
+    let responder = match responder {
+        Ok(responder) => responder,
+        Err(e) => {
+            debug!("Handshake of client {} of user `{}` failed: {}", peer, user.name(), e);
+            return Err(RejectCode::HandshakeFailed);
+        }
+    };
+
+    if !user.public_keys().is_empty() {
+        match responder.remote_static() {
+            Some(key) if user.public_keys().contains(key) => {}
+            Some(key) => {
+                debug!("Client {} of user `{}` is identified by an unknown key: {}", peer, user.name(), key);
+                return Err(RejectCode::UnknownClientKey);
+            }
+            None => return Err(RejectCode::UnknownClientKey),
+        }
+    }
+    Ok(Some(responder))
+}
+
+/// Choose the protocol version and cipher of a client from its `Hello`.
+///
+/// The highest version both ends speak is used, and the first of the `supported` ciphers the client also offers.
+fn negotiate(version: u8, offered: &[u8], supported: &[Ciphers]) -> ::std::result::Result<(u8, Ciphers), RejectCode> {
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(RejectCode::UnsupportedVersion);
+    }
+    let version = version.min(PROTOCOL_VERSION);
+
+    match supported.iter().find(|cipher| offered.contains(&cipher.id())) {
+        Some(cipher) => Ok((version, *cipher)),
+        None => Err(RejectCode::NoCommonCipher),
+    }
+}
+
+/// The destination of a packet read from the tun and its length, which leaves out whatever follows the total length
+/// of an IPv4 packet.
+fn parse_destination(packet: &[u8]) -> Result<(IpAddr, usize)> {
+    if packet.first().map(|b| b >> 4) == Some(4) {
+        let packet = Ipv4Packet::new(packet)?;
+        return Ok((IpAddr::V4(packet.destination_address()), packet.total_len()));
+    }
+    let header = IPHeader::parse(packet)?;
+    Ok((header.destination_address(), packet.len()))
+}
+
+/// The source of a packet received from a client, whose IPv4 header must hold its checksum.
+fn parse_source(packet: &[u8]) -> Result<IpAddr> {
+    if packet.first().map(|b| b >> 4) == Some(4) {
+        let packet = Ipv4Packet::new(packet)?;
+        if !packet.verify_checksum() {
+            return Err(ErrorKind::InvalidIpv4Packet("bad header checksum".to_string()).into());
+        }
+        return Ok(IpAddr::V4(packet.source_address()));
+    }
+    Ok(IPHeader::parse(packet)?.source_address())
+}
+
+fn modified_time(path: &PathBuf) -> io::Result<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified())
+}
+
+impl AkarinServer {
+    pub fn new<'b>(tun: Device, transport: Box<Transport>, configuration: &'b ServerConfiguration, handle: &Handle)
+                   -> Result<Self> {
+        let users = load_users(configuration, &UserTable::new())?;
+        if users.len() == 0 {
+            return Err(ErrorKind::MissingSetting("password".to_string()).into());
+        }
+        let users_modified = match configuration.users {
+            Some(ref path) => Some(modified_time(path)?),
+            None => None,
+        };
+        info!("Loaded {} users", users.len());
+
+        let pool = AddressPool::new(configuration.tunnel_subnet(), &[configuration.tunnel_address()]);
+        let mtu = tun.get_ref().mtu()? as usize;
+        Ok(AkarinServer {
+               tun,
+               transport,
+
+               configuration: configuration.clone(),
+               users,
+               users_modified,
+               reload_timer: Interval::new(Duration::from_secs(USERS_RELOAD_INTERVAL), handle)?,
+               reloading: None,
+
+               clients: ClientStorage::new(pool, configuration.client_timeout.unwrap_or(DEFAULT_CLIENT_TIMEOUT)),
+               failures: AuthFailures::default(),
+
+               mtu,
+               mss_clamping: configuration.mss_clamping.unwrap_or(false),
+
+               tun_buf: new_buf(mtu),
+               transport_buf: new_buf(mtu),
+
+               to_transport: None,
+               to_tun: false,
+
+               state: State::Down,
+           })
+    }
+
+    /// Start reloading the users file if it has changed, see `poll_reload`.
+    fn reload_users(&mut self) {
+        let path = match self.configuration.users {
+            Some(ref path) if self.reloading.is_none() => path.clone(),
+            _ => return,
+        };
+        let modified = match modified_time(&path) {
+            Ok(modified) => modified,
+            Err(e) => {
+                warn!("Failed to check users file {}: {}", path.display(), e);
+                return;
+            }
+        };
+        if self.users_modified == Some(modified) {
+            return;
+        }
+        self.users_modified = Some(modified);
+
+        let configuration = self.configuration.clone();
+        let known = self.users.clone();
+        let (sender, receiver) = oneshot::channel();
+        let spawned = thread::Builder::new().name("users".to_string()).spawn(move || {
+            let _ = sender.send(load_users(&configuration, &known));
+        });
+        match spawned {
+            Ok(_) => self.reloading = Some(receiver),
+            Err(e) => warn!("Failed to reload users, keeping the current ones: {}", e),
+        }
+    }
+
+    /// Take the reloaded users once their keys are derived, clients of revoked users are disconnected.
+    fn poll_reload(&mut self) {
+        let loaded = match self.reloading.as_mut().map(Future::poll) {
+            Some(Ok(Async::Ready(loaded))) => loaded,
+            Some(Ok(Async::NotReady)) | None => return,
+            Some(Err(_)) => Err("the thread loading them has failed".into()),
+        };
+        self.reloading = None;
+
+        // Keep the current users if the file is broken, a typo must not lock everyone out.
+        let users = match loaded {
+            Ok(users) => users,
+            Err(e) => {
+                warn!("Failed to reload users, keeping the current ones: {}", e);
+                return;
+            }
+        };
+        for id in self.users.changed(&users) {
+            let removed = self.clients.remove_user(id);
+            let name = self.users.get(id).map_or("", |user| user.name());
+            info!("User `{}` revoked, {} clients disconnected", name, removed);
+        }
+        self.users = users;
+        info!("Reloaded {} users", self.users.len());
+    }
+
+    /// Forward packets read from the tun to the clients they belong to.
+    ///
+    /// Returns `Ok(true)` if any progress has been made.
+    fn poll_tun(&mut self) -> io::Result<bool> {
+        if let Some((client_id, peer)) = self.to_transport {
+            match self.transport.send_to(self.tun_buf.as_slice(), &peer) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if is_too_large(e) => {
+                    debug!("Dropping datagram of {} bytes, larger than the path to {}", self.tun_buf.len(), peer);
+                    self.lower_mtu(client_id, peer);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.to_transport = None;
+
+        // The packet lands after the room of its header, so that it is sealed where it is.
+        self.tun_buf.clear(AKARIN_DATA_OFFSET);
+        let n = match self.tun.read(self.tun_buf.tail_mut()) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        self.tun_buf.extend(n).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        let (destination, len) = match parse_destination(self.tun_buf.as_slice()) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                warn!("Dropping invalid packet from tun, {} bytes: {}", n, e);
+                return Ok(true);
+            }
+        };
+        self.tun_buf.truncate(len);
+
+        let client = match destination {
+            IpAddr::V4(ref address) => self.clients.find_address(address),
+            IpAddr::V6(ref address) => self.clients.find_address_v6(address),
+        };
+        let (client_id, token, peer) = match client.and_then(|id| self.clients.get(id).map(|m| (id, m.0, m.1))) {
+            Some(client) => client,
+            None => {
+                debug!("Dropping packet to unknown client: {}", destination);
+                return Ok(true);
+            }
+        };
+        let mtu = self.clients.mtu(client_id).unwrap_or(self.mtu);
+        if len > mtu {
+            reject_packet(&mut self.tun, self.tun_buf.as_slice(), mtu)?;
+            return Ok(true);
+        }
+        if self.mss_clamping {
+            clamp_mss(self.tun_buf.as_mut_slice(), mtu);
+        }
+        let session = match self.clients.session(client_id) {
+            Some(session) => session,
+            None => return Ok(true),
+        };
+
+        let tun_buf = &mut self.tun_buf;
+        let padding = session.padding();
+        match Message::wrap_data(tun_buf, padding).and_then(|_| session.seal_in_place(client_id, token, tun_buf)) {
+            Ok(()) => self.to_transport = Some((client_id, peer)),
+            Err(e) => warn!("Failed to encrypt packet to client {}: {}", client_id, e),
+        }
+        Ok(true)
+    }
+
+    /// The host has refused to send a datagram larger than the MTU of the path to a client, which it has learned
+    /// from the network. Packets to the client which do not fit it any more are rejected from now on.
+    fn lower_mtu(&mut self, client_id: ClientId, peer: Endpoint) {
+        let cipher = match self.clients.session(client_id) {
+            Some(session) => session.crypto().cipher(),
+            None => return,
+        };
+        match path_mtu(&peer.address()) {
+            Ok(path_mtu) => {
+                let mtu = tun_mtu(path_mtu, &[cipher], &peer);
+                info!("MTU of the path to client {}: {}", client_id, mtu);
+                self.clients.set_mtu(client_id, mtu);
+            }
+            Err(e) => warn!("Failed to get the MTU of the path to {}: {}", peer, e),
+        }
+    }
+
+    /// Forward authenticated datagrams received from clients to the tun.
+    ///
+    /// Returns `Ok(true)` if any progress has been made.
+    fn poll_transport(&mut self) -> io::Result<bool> {
+        if self.to_tun {
+            match self.tun.write(self.transport_buf.as_slice()) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        self.to_tun = false;
+
+        self.transport_buf.clear(0);
+        let (n, peer) = match self.transport.recv_from(self.transport_buf.tail_mut()) {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        self.transport_buf.extend(n).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+        let (client_id, token, _) = match AkarinPacket::peek(self.transport_buf.as_slice()) {
+            Ok(header) => header,
+            Err(e) => {
+                debug!("Dropping datagram from {}: {}", peer, e);
+                return Ok(true);
+            }
+        };
+
+        if client_id == 0 {
+            self.poll_hello(token, peer);
+            return Ok(true);
+        }
+
+        // Datagrams of registered clients are opened in place with the keys of their session, from wherever they
+        // come: the endpoint of a client changes when it moves to another network or its NAT rebinds its port.
+        let endpoint = match self.clients.get(client_id) {
+            Some(&(stored, endpoint)) if stored == token => endpoint,
+            _ => {
+                debug!("Dropping datagram from {}, unknown client: {}", peer, client_id);
+                return Ok(true);
+            }
+        };
+        let opened = match self.clients.session(client_id) {
+            Some(session) => session.open_in_place(&mut self.transport_buf),
+            None => return Ok(true),
+        };
+        let newest = match opened {
+            Ok(newest) => newest,
+            // Authentic duplicates and late packets are counted by the replay window of the client.
+            Err(Error(ErrorKind::ReplayedPacket(_), _)) |
+            Err(Error(ErrorKind::OutdatedPacket(_), _)) => {
+                debug!("Dropping datagram from {}, client {}: replayed or outdated", peer, client_id);
+                return Ok(true);
+            }
+            Err(e) => {
+                debug!("Dropping datagram from {}, client {}: {}", peer, client_id, e);
+                if let Some(user_id) = self.clients.user(client_id) {
+                    self.authentication_failed(user_id, peer, &e.to_string());
+                }
+                return Ok(true);
+            }
+        };
+        // Only an authentic packet newer than any other moves the client, so that neither a forged one nor a
+        // replayed or late one can take its traffic elsewhere.
+        let endpoint = if endpoint != peer && newest {
+            info!("Client {} moved from {} to {}", client_id, endpoint, peer);
+            peer
+        } else {
+            endpoint
+        };
+        let _ = self.clients.refresh_client(client_id, &(token, endpoint));
+
+        // A keepalive has done its job by refreshing the client, which only waits for one back, and a probe has by
+        // getting here.
+        let reply = match Message::decode(self.transport_buf.as_slice()) {
+            Ok(Message::Keepalive) => Some(Message::Keepalive),
+            Ok(Message::Probe { mtu }) => Some(Message::ProbeAck { mtu }),
+            _ => None,
+        };
+        if let Some(reply) = reply {
+            self.reply(client_id, token, endpoint, &reply);
+            return Ok(true);
+        }
+
+        match Message::unwrap_data(&mut self.transport_buf) {
+            Ok(()) => self.to_tun = true,
+            Err(e) => debug!("Dropping datagram from {}: {}", peer, e),
+        }
+        // Authentic as it is, a packet is only handed to the kernel with a sound header and from the address of its
+        // client, so that no client can pass for another one or for a host behind the server.
+        if self.to_tun {
+            match parse_source(self.transport_buf.as_slice()) {
+                Ok(ref source) if self.clients.owns_address(client_id, source) => {}
+                Ok(source) => {
+                    debug!("Dropping packet from {}, client {} does not own source {}", peer, client_id, source);
+                    self.to_tun = false;
+                }
+                Err(e) => {
+                    debug!("Dropping packet from {}: {}", peer, e);
+                    self.to_tun = false;
+                }
+            }
+        }
+        // Both ends of a connection through the tunnel offer an MSS which fits it, whichever end has clamping on.
+        if self.to_tun && self.mss_clamping {
+            let mtu = self.clients.mtu(client_id).unwrap_or(self.mtu);
+            clamp_mss(self.transport_buf.as_mut_slice(), mtu);
+        }
+        Ok(true)
+    }
+
+    /// Answer a keepalive or a probe of a client.
+    fn reply(&mut self, client_id: ClientId, token: ClientToken, peer: Endpoint, message: &Message) {
+        let datagram = match self.clients.session(client_id) {
+            Some(session) => session.seal_message(client_id, token, message),
+            None => return,
+        };
+        // The client sends another one soon, a reply lost here only counts as one missed.
+        match datagram {
+            Ok(datagram) => {
+                if let Err(e) = self.transport.send_to(&datagram, &peer) {
+                    debug!("Failed to reply to {}: {}", peer, e);
+                }
+            }
+            Err(e) => warn!("Failed to encrypt reply to client {}: {}", client_id, e),
+        }
+    }
+
+    /// Handle a datagram of a new client, which tells its user in place of the token and seals its `Hello` with the
+    /// key of that user.
+    fn poll_hello(&mut self, token: ClientToken, peer: Endpoint) {
+        if token > UserId::max_value() as ClientToken {
+            debug!("Dropping datagram from {}, invalid user id: {}", peer, token);
+            return;
+        }
+        let user_id = token as UserId;
+
+        let opened = {
+            let user = match self.users.get(user_id) {
+                Some(user) => user,
+                None => {
+                    debug!("Dropping datagram from {}, unknown user: {}", peer, user_id);
+                    return;
+                }
+            };
+
+            let crypto = user.crypto();
+            let datagram = self.transport_buf.as_slice();
+            let packet = match AkarinPacket::decode(datagram, crypto.nonce_len(), crypto.tag_len()) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    debug!("Dropping datagram from {}: {}", peer, e);
+                    return;
+                }
+            };
+            // Every key of the user shares the handshake cipher, they only differ by how they are derived.
+            let mut opened = None;
+            for (key, crypto) in user.keys().iter().enumerate() {
+                if let Ok((_, message)) = packet.open(&**crypto) {
+                    opened = Some((key, message));
+                    break;
+                }
+            }
+            if let Some((key, _)) = opened {
+                if key > 0 {
+                    info!("Client {} of user `{}` derives its key without a salt", peer, user.name());
+                }
+            }
+            opened
+        };
+        let (key, message) = match opened {
+            Some(opened) => opened,
+            None => {
+                self.authentication_failed(user_id, peer, "failed to decrypt `Hello`");
+                return;
+            }
+        };
+
+        match Message::decode(&message) {
+            Ok(Message::Hello {
+                   cookie,
+                   version,
+                   ciphers,
+                   handshake,
+               }) => {
+                // Clients of version 1 have no handshake, whatever follows their ciphers is not one.
+                let handshake = if version >= 2 { handshake } else { &[] };
+                let accepted = match self.users.get(user_id) {
+                    Some(user) => {
+                        let configuration = &self.configuration;
+                        negotiate(version, ciphers, configuration.supported_ciphers()).and_then(|(version, cipher)| {
+                            let handshake = accept_handshake(configuration, user, key, handshake, peer)?;
+                            Ok((version, cipher, handshake))
+                        })
+                    }
+                    None => return,
+                };
+                match accepted {
+                    Ok((version, cipher, handshake)) => {
+                        self.register_client(user_id, key, cookie, version, cipher, handshake, peer)
+                    }
+                    Err(code) => self.reject_client(user_id, key, cookie, code, peer),
+                }
+            }
+            Ok(message) => debug!("Dropping unexpected message from {}: {:?}", peer, message),
+            Err(e) => debug!("Dropping datagram from {}: {}", peer, e),
+        }
+    }
+
+    /// Register a client of `user_id` which has agreed on `version` and `cipher`, `key` is the key of the user the
+    /// client has sealed its `Hello` with. The session of the client is derived from its Noise `handshake`, if it
+    /// has started one.
+    ///
+    /// A `Hello` sent again gets the client registered by the first one. The cookie of the `Hello` is echoed back, so
+    /// that the client can match the `Welcome` to its request.
+    fn register_client(&mut self, user_id: UserId, key: usize, cookie: ClientToken, version: u8, cipher: Ciphers,
+                       handshake: Option<Handshake>, peer: Endpoint) {
+        let user = match self.users.get(user_id) {
+            Some(user) => user,
+            None => return,
+        };
+        let crypto = &*user.keys()[key];
+        let token = match new_token(&SystemRandom::new()) {
+            Ok(token) => token,
+            Err(e) => {
+                warn!("Failed to generate token for client {}: {}", peer, e);
+                return;
+            }
+        };
+        let client_id = match self.clients.register_client(user_id, cookie, &(token, peer)) {
+            Ok(id) => id,
+            Err(e) => {
+                warn!("Failed to register client {}: {}", peer, e);
+                return;
+            }
+        };
+        let token = self.clients.get(client_id).map_or(token, |meta| meta.0);
+        if self.clients.session(client_id).is_none() {
+            let window_size = self.configuration.replay_window.unwrap_or(DEFAULT_REPLAY_WINDOW);
+            let policy = self.configuration.rekey_policy();
+            let started = match handshake {
+                None => {
+                    Session::start(crypto, cipher, client_id, token, window_size, policy)
+                        .map(|session| (session, vec![]))
+                }
+                Some(mut responder) => {
+                    responder.write_message(&SystemRandom::new()).and_then(|reply| {
+                        let root = HANDSHAKE_CIPHER.with_key(responder.split()?)?;
+                        Ok((Session::start(&*root, cipher, client_id, token, window_size, policy)?, reply))
+                    })
+                }
+            };
+            match started {
+                Ok((mut session, reply)) => {
+                    // Older clients do not understand padded messages.
+                    if version >= 3 {
+                        session.set_padding(self.configuration.padding.unwrap_or_default());
+                    }
+                    self.clients.start_session(client_id, session, reply)
+                }
+                Err(e) => {
+                    warn!("Failed to start session of client {}: {}", peer, e);
+                    self.clients.remove_client(client_id);
+                    return;
+                }
+            }
+        }
+
+        let address = match self.clients.lease(client_id) {
+            Some(address) => address,
+            None => return,
+        };
+        // The IPv6 address of a client embeds its IPv4 address, so that both of them are released together.
+        let address_v6 = match self.configuration.subnet_v6 {
+            Some(subnet) => {
+                let address = subnet.host(u32::from(address) as u128)
+                                    .and_then(|address| Subnet6::new(address, subnet.prefix()).ok());
+                if let Some(address) = address {
+                    self.clients.set_address_v6(client_id, address.address());
+                }
+                address
+            }
+            None => None,
+        };
+        let handshake = self.clients.handshake(client_id).unwrap_or(&[]).to_vec();
+        let welcome = Message::Welcome {
+            cookie,
+            version,
+            cipher: cipher.id(),
+            address,
+            handshake: &handshake,
+            address_v6,
+        };
+
+        // The `Welcome` is sealed with the key of the user, the client derives its session once it knows its id.
+        let datagram = match AkarinPacket::seal(client_id, token, 0, 0, &welcome.encode(), crypto) {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                warn!("Failed to encrypt registration reply to {}: {}", peer, e);
+                return;
+            }
+        };
+
+        // The client keeps asking until it hears from us, so a reply lost here is not fatal.
+        match self.transport.send_to(&datagram, &peer) {
+            Ok(_) => {
+                info!("Client {} of user `{}` registered as {}, address: {}, cipher: {}, forward secrecy: {}",
+                      peer,
+                      user.name(),
+                      client_id,
+                      address,
+                      cipher.name(),
+                      !handshake.is_empty())
+            }
+            Err(e) => warn!("Failed to reply registration to {}: {}", peer, e),
+        }
+    }
+
+    /// Tell a client of `user_id` why it can not register, it gives up instead of asking again.
+    fn reject_client(&mut self, user_id: UserId, key: usize, cookie: ClientToken, code: RejectCode,
+                     peer: Endpoint) {
+        match code {
+            RejectCode::HandshakeFailed | RejectCode::UnknownClientKey => {
+                self.authentication_failed(user_id, peer, &format!("rejected: {}", code))
+            }
+            _ => {
+                let name = self.users.get(user_id).map_or("", |user| user.name());
+                warn!("Rejecting client {} of user `{}`: {}", peer, name, code);
+            }
+        }
+        let user = match self.users.get(user_id) {
+            Some(user) => user,
+            None => return,
+        };
+        let reject = Message::Reject {
+            cookie,
+            code,
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+        };
+        match AkarinPacket::seal(0, user_id as ClientToken, 0, 0, &reject.encode(), &*user.keys()[key]) {
+            Ok(datagram) => {
+                if let Err(e) = self.transport.send_to(&datagram, &peer) {
+                    warn!("Failed to reply rejection to {}: {}", peer, e);
+                }
+            }
+            Err(e) => warn!("Failed to encrypt rejection to {}: {}", peer, e),
+        }
+        self.transport.disconnect(&peer);
+    }
+
+    /// Count a datagram of a client of `user_id` which has failed to authenticate, see `AuthFailures`.
+    fn authentication_failed(&mut self, user_id: UserId, peer: Endpoint, reason: &str) {
+        if let Some(failures) = self.failures.count(user_id) {
+            let name = self.users.get(user_id).map_or("", |user| user.name());
+            warn!("{} datagrams of user `{}` failed to authenticate, the last one from {}: {}", failures, name, peer,
+                  reason);
+        }
+    }
+}
+
+impl fmt::Debug for AkarinServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AkarinServer")
+         .field("tun", &self.tun)
+         .field("transport", &self.transport)
+         .field("users", &self.users)
+         .field("clients", &self.clients)
+         .field("state", &self.state)
+         .finish()
+    }
+}
+
+impl Future for AkarinServer {
     type Item = ();
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        loop {
-            if try_nb!(self.tun.read(&mut self.tun_buf)) < *IPV4_HEADER_LEN {
-                return Err(io::ErrorKind::UnexpectedEof.into());
-            };
+        self.state = State::Running;
+        self.clients.prune();
+        while let Async::Ready(Some(())) = self.reload_timer.poll()? {
+            self.reload_users();
+        }
+        self.poll_reload();
 
-            let header = {
-                let mut header_bytes = [0u8; 20];
-                header_bytes.copy_from_slice(&self.tun_buf[..*IPV4_HEADER_LEN]);
-                IPv4Header::from(header_bytes)
-            };
+        loop {
+            for endpoint in self.clients.disconnected() {
+                self.transport.disconnect(&endpoint);
+            }
+            self.transport.flush()?;
+            // Poll both directions in turn so that neither of them can starve the other.
+            let tun_progress = self.poll_tun()?;
+            let transport_progress = self.poll_transport()?;
 
-            let client_id = header.destination_address;
-            if let Some(&(token, sockaddr)) = self.clients.get(client_id) {}
+            if !tun_progress && !transport_progress {
+                return Ok(Async::NotReady);
+            }
         }
     }
 }
 
-impl<'a> Server for AkarinServer<'a> {
-    fn serve(mut self, mut core: Core, handle: Handle) -> Result<()> {
-        core.run(self);
+impl Server for AkarinServer {
+    fn serve(self, mut core: Core, _handle: Handle) -> Result<()> {
+        core.run(self)?;
         Ok(())
     }
 }
@@ -170,29 +1028,220 @@ impl<'a> Server for AkarinServer<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::SocketAddr;
     use std::str::FromStr;
+    use ring::aead;
+    use crypto::aead_crypto::AeadCrypto;
+    use crypto::kdf::Kdf;
+    use crypto::x25519::PrivateKey;
 
     #[test]
     fn test_client_storage() {
-        let reserved = 2..10;
-        let ref mut us = ClientStorage::new(0..255, 60);
-        for r in reserved {
-            unsafe {
-                us.reserve_id(r).unwrap();
-            }
-        }
-
-        let available_ids = us.available_ids();
-        for id in available_ids {
-            assert!(id < 2 || id >= 10);
-        }
+        let server = Ipv4Addr::new(10, 8, 0, 2);
+        let pool = AddressPool::new("10.8.0.0/29".parse().unwrap(), &[server]);
+        let ref mut us = ClientStorage::new(pool, 60);
 
-        let client = (123u64, SocketAddr::from_str("192.168.1.1:80").unwrap());
-        let cid = us.insert_client(&client).unwrap();
+        let client = (123u64, Endpoint::Udp(SocketAddr::from_str("192.168.1.1:80").unwrap()));
+        let cid = us.insert_client(1, &client).unwrap();
         us.refresh_client(cid, &client).unwrap();
         assert!(us.refresh_client(cid + 1, &client).is_err());
         assert!(us.compare_client(cid, &client));
+
+        // A client keeps its id when it moves, but only under its own token.
+        let moved = (123u64, Endpoint::Udp(SocketAddr::from_str("172.16.0.1:443").unwrap()));
+        assert!(us.refresh_client(cid, &(124u64, moved.1)).is_err());
+        us.refresh_client(cid, &moved).unwrap();
+        assert!(!us.compare_client(cid, &client));
+        assert_eq!(us.get(cid).unwrap(), &moved);
+        us.refresh_client(cid, &client).unwrap();
         assert_eq!(us.get(cid).unwrap(), &client);
         assert!(us.get(cid + 1).is_none());
+
+        // The MTU of the path to a client is forgotten when it moves.
+        us.set_mtu(cid, 1300);
+        us.set_mtu(cid + 1, 1300);
+        us.refresh_client(cid, &client).unwrap();
+        assert_eq!(us.mtu(cid), Some(1300));
+        assert_eq!(us.mtu(cid + 1), None);
+        us.refresh_client(cid, &moved).unwrap();
+        assert_eq!(us.mtu(cid), None);
+        us.refresh_client(cid, &client).unwrap();
+
+        assert!(us.session(cid).is_none());
+        let user = AeadCrypto::new(&aead::CHACHA20_POLY1305, b"realityone", &Kdf::Legacy).unwrap();
+        let session = || {
+            Session::start(&user, Ciphers::AES_256_GCM, cid, 123, DEFAULT_REPLAY_WINDOW, Default::default()).unwrap()
+        };
+        us.start_session(cid, session(), vec![1, 2]);
+        assert_eq!(us.session(cid).unwrap().epoch(), 0);
+        assert_eq!(us.handshake(cid), Some(&[1, 2][..]));
+        us.start_session(cid + 1, session(), vec![]);
+        assert!(us.session(cid + 1).is_none());
+
+        // The gateway and the server address are never leased.
+        let address = us.lease(cid).unwrap();
+        assert_eq!(address, Ipv4Addr::new(10, 8, 0, 3));
+        assert_eq!(us.find_address(&address), Some(cid));
+
+        // A client only sends from its own addresses.
+        let address_v6 = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 3);
+        us.set_address_v6(cid, address_v6);
+        assert!(us.owns_address(cid, &IpAddr::V4(address)));
+        assert!(us.owns_address(cid, &IpAddr::V6(address_v6)));
+        assert!(!us.owns_address(cid, &IpAddr::V4(server)));
+        assert!(!us.owns_address(cid, &IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2))));
+        assert!(!us.owns_address(cid + 1, &IpAddr::V4(address)));
+
+        let ids: Vec<ClientId> = (0..3).map(|_| us.insert_client(1, &client).unwrap()).collect();
+        match us.insert_client(1, &client) {
+            Err(Error(ErrorKind::MaxClientExceed, _)) => {}
+            result => panic!("expected MaxClientExceed, got {:?}", result),
+        }
+
+        assert_eq!(us.user(cid), Some(1));
+        us.remove_client(ids[0]);
+        assert!(us.find_address(&Ipv4Addr::new(10, 8, 0, 4)).is_none());
+        let cid = us.insert_client(1, &client).unwrap();
+        assert!(!ids.contains(&cid));
+        assert_eq!(us.lease(cid), Some(Ipv4Addr::new(10, 8, 0, 4)));
+
+        assert_eq!(us.remove_user(1), 4);
+        assert!(us.get(cid).is_none());
+        let other = us.register_client(2, 42, &client).unwrap();
+        assert_eq!(us.user(other), Some(2));
+        assert_eq!(us.register_client(2, 42, &client).unwrap(), other);
+        assert!(us.register_client(2, 43, &client).unwrap() != other);
+    }
+
+    #[test]
+    fn test_disconnected() {
+        let pool = AddressPool::new("10.8.0.0/29".parse().unwrap(), &[Ipv4Addr::new(10, 8, 0, 1)]);
+        let mut us = ClientStorage::new(pool, 60);
+        let first = Endpoint::Tcp(SocketAddr::from_str("192.168.1.1:80").unwrap());
+        let second = Endpoint::Tcp(SocketAddr::from_str("192.168.1.1:81").unwrap());
+        let udp = Endpoint::Udp(SocketAddr::from_str("192.168.1.1:80").unwrap());
+
+        let cid = us.insert_client(1, &(123, first)).unwrap();
+        let other = us.insert_client(1, &(124, udp)).unwrap();
+        us.refresh_client(cid, &(123, first)).unwrap();
+        assert!(us.disconnected().is_empty());
+
+        // The connection a client leaves is closed, unless another client still uses it.
+        us.refresh_client(cid, &(123, second)).unwrap();
+        assert_eq!(us.disconnected(), vec![first]);
+        us.refresh_client(other, &(124, second)).unwrap();
+        us.remove_client(cid);
+        assert!(us.disconnected().is_empty());
+        us.refresh_client(other, &(124, udp)).unwrap();
+        assert_eq!(us.disconnected(), vec![second]);
+        us.remove_client(other);
+        assert!(us.disconnected().is_empty());
+    }
+
+    #[test]
+    fn test_auth_failures() {
+        let mut failures = AuthFailures::default();
+        assert_eq!(failures.count(1), Some(1));
+        assert_eq!(failures.count(1), None);
+        assert_eq!(failures.count(1), None);
+        assert_eq!(failures.count(2), Some(1));
+
+        // The failures since the last report are reported once the next one is due.
+        failures.failures.get_mut(&1).unwrap().1 -= Duration::from_secs(FAILURE_REPORT_INTERVAL);
+        assert_eq!(failures.count(1), Some(3));
+        assert_eq!(failures.count(1), None);
+    }
+
+    #[test]
+    fn test_parse_destination() {
+        let mut packet = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 8, 0, 2, 10, 8, 0, 3];
+        let (destination, len) = parse_destination(&packet).unwrap();
+        assert_eq!(destination, IpAddr::V4(Ipv4Addr::new(10, 8, 0, 3)));
+        assert_eq!(len, 20);
+        // Bytes past the total length are not part of the packet.
+        packet.extend_from_slice(&[0; 4]);
+        assert_eq!(parse_destination(&packet).unwrap().1, 20);
+        // Neither is a total length longer than what has been read nor a truncated header.
+        packet[3] = 40;
+        assert!(parse_destination(&packet).is_err());
+        assert!(parse_destination(&packet[..12]).is_err());
+
+        let mut packet = vec![0x60, 0, 0, 0, 0, 0, 59, 64];
+        packet.extend_from_slice(&Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2).octets());
+        packet.extend_from_slice(&Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 3).octets());
+        let (destination, len) = parse_destination(&packet).unwrap();
+        assert_eq!(destination, IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 3)));
+        assert_eq!(len, 40);
+        assert!(parse_destination(&[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_source() {
+        let mut packet = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 8, 0, 2, 10, 8, 0, 3];
+        Ipv4Packet::new(&mut packet[..]).unwrap().fill_checksum();
+        assert_eq!(parse_source(&packet).unwrap(), IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2)));
+        // The header of a packet from a client must hold its checksum.
+        packet[8] = 63;
+        assert!(parse_source(&packet).is_err());
+
+        let mut packet = vec![0x60, 0, 0, 0, 0, 0, 59, 64];
+        packet.extend_from_slice(&Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2).octets());
+        packet.extend_from_slice(&Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 3).octets());
+        assert_eq!(parse_source(&packet).unwrap(), IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2)));
+        assert!(parse_source(&packet[..20]).is_err());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let (aes, chacha) = (Ciphers::AES_256_GCM.id(), Ciphers::CHACHA20_POLY1305.id());
+        assert_eq!(negotiate(PROTOCOL_VERSION, &[chacha, aes], Ciphers::ALL),
+                   Ok((PROTOCOL_VERSION, Ciphers::AES_256_GCM)));
+        assert_eq!(negotiate(PROTOCOL_VERSION + 1, &[0xff, chacha], Ciphers::ALL),
+                   Ok((PROTOCOL_VERSION, Ciphers::CHACHA20_POLY1305)));
+        assert_eq!(negotiate(PROTOCOL_VERSION, &[aes], &[Ciphers::CHACHA20_POLY1305]),
+                   Err(RejectCode::NoCommonCipher));
+        assert_eq!(negotiate(MIN_PROTOCOL_VERSION - 1, &[aes], Ciphers::ALL),
+                   Err(RejectCode::UnsupportedVersion));
+    }
+
+    #[test]
+    fn test_accept_handshake() {
+        let rng = SystemRandom::new();
+        let peer = Endpoint::Tcp(SocketAddr::from_str("192.168.1.1:80").unwrap());
+        let (server_key, client_key) = (PrivateKey::generate(&rng).unwrap(), PrivateKey::generate(&rng).unwrap());
+        let mut configuration = ServerConfiguration::default();
+        configuration.private_key(server_key.clone());
+        let mut user = User::new(1, "alice", "realityone", &[Kdf::Legacy]);
+        let psk = handshake_psk(&*user.keys()[0]);
+
+        let hello = |pattern: Pattern, s: Option<PrivateKey>| {
+            let rs = s.as_ref().map(|_| server_key.public_key());
+            let mut initiator = Handshake::initiator(pattern, &psk, HANDSHAKE_PROLOGUE, s, rs).unwrap();
+            let mut hello = vec![pattern.id()];
+            hello.extend(initiator.write_message(&rng).unwrap());
+            hello
+        };
+
+        assert!(accept_handshake(&configuration, &user, 0, &[], peer).unwrap().is_none());
+        assert!(accept_handshake(&configuration, &user, 0, &hello(Pattern::NNpsk0, None), peer)
+                    .unwrap()
+                    .is_some());
+        let responder = accept_handshake(&configuration, &user, 0, &hello(Pattern::IKpsk2, Some(client_key.clone())),
+                                         peer)
+            .unwrap()
+            .unwrap();
+        assert_eq!(responder.remote_static(), Some(&client_key.public_key()));
+        assert_eq!(accept_handshake(&configuration, &user, 0, &[0xff, 1, 2], peer).err(),
+                   Some(RejectCode::HandshakeFailed));
+
+        // Only the allowed keys of a user are accepted.
+        user.set_public_keys(&[PrivateKey::generate(&rng).unwrap().public_key()]);
+        for handshake in &[vec![], hello(Pattern::NNpsk0, None), hello(Pattern::IKpsk2, Some(client_key.clone()))] {
+            assert_eq!(accept_handshake(&configuration, &user, 0, handshake, peer).err(),
+                       Some(RejectCode::UnknownClientKey));
+        }
+        user.set_public_keys(&[client_key.public_key()]);
+        assert!(accept_handshake(&configuration, &user, 0, &hello(Pattern::IKpsk2, Some(client_key)), peer)
+                    .is_ok());
     }
 }
@@ -0,0 +1,398 @@
+//! State kept by both ends of a registered client.
+//!
+//! Every packet sealed in a session carries a counter which increases by one for each packet. The receiving end
+//! keeps a sliding window of the counters it has seen, in the way of RFC 6479, so that packets reordered by the
+//! network are still accepted while replayed or too old ones are dropped.
+//!
+//! The first key of a session is derived from the key of the Noise handshake of the client, or from the key of its
+//! user if it has not done one, and every following key from the one before it. Once the current key has sealed or
+//! opened enough packets or bytes, or is old enough, either end switches to the next key, starting a new epoch, and
+//! the other end follows as soon as it opens a packet of that epoch, a few epochs ahead if the packets of the ones in
+//! between have all been lost. The key of the previous epoch is kept for a little while, so that packets sealed before
+//! the switch still get through.
+
+use std::mem;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use super::{ClientId, ClientToken};
+use super::packet::{AKARIN_HEADROOM, AKARIN_TAILROOM, AkarinPacket, Message, Padding};
+use common::buf::PacketBuf;
+use common::error::*;
+use crypto::{Ciphers, Crypto, KEY_LEN};
+
+/// Size of the replay window, in packets, when none is configured.
+pub const DEFAULT_REPLAY_WINDOW: u64 = 2048;
+pub const MIN_REPLAY_WINDOW: u64 = 64;
+pub const MAX_REPLAY_WINDOW: u64 = 1 << 20;
+
+/// Packets, bytes and seconds after which a key is replaced when no limits are configured.
+pub const DEFAULT_REKEY_PACKETS: u64 = 1 << 30;
+pub const DEFAULT_REKEY_BYTES: u64 = 1 << 40;
+pub const DEFAULT_REKEY_INTERVAL: u64 = 600;
+
+/// Prologue of the Noise handshake of a client, see `crypto::noise`.
+pub const HANDSHAKE_PROLOGUE: &[u8] = b"akarin handshake";
+
+/// Seconds the key of the previous epoch is kept after switching to a new one.
+const EPOCH_OVERLAP: u64 = 10;
+/// Epochs a session follows the other end ahead of its own at once.
+const MAX_EPOCH_SKIP: u8 = 4;
+
+const BLOCK_BITS: u64 = 64;
+
+/// When the key of a session must be replaced, whichever limit comes first.
+#[derive(Clone, Copy, Debug)]
+pub struct RekeyPolicy {
+    pub packets: u64,
+    pub bytes: u64,
+    pub interval: Duration,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        RekeyPolicy {
+            packets: DEFAULT_REKEY_PACKETS,
+            bytes: DEFAULT_REKEY_BYTES,
+            interval: Duration::from_secs(DEFAULT_REKEY_INTERVAL),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Key {
+    epoch: u8,
+    crypto: Box<Crypto>,
+    created: Instant,
+    packets: u64,
+    bytes: u64,
+}
+
+impl Key {
+    fn new(epoch: u8, crypto: Box<Crypto>) -> Self {
+        Key {
+            epoch,
+            crypto,
+            created: Instant::now(),
+            packets: 0,
+            bytes: 0,
+        }
+    }
+
+    fn next(&self) -> Result<Key> {
+        let epoch = self.epoch.wrapping_add(1);
+        let mut context = b"akarin epoch ".to_vec();
+        context.push(epoch);
+        Ok(Key::new(epoch, self.crypto.rekey(&context)?))
+    }
+
+    fn record(&mut self, len: usize) {
+        self.packets += 1;
+        self.bytes += len as u64;
+    }
+}
+
+/// The pre-shared key of the Noise handshake of a client, derived from the key of its user.
+pub fn handshake_psk(user_crypto: &Crypto) -> [u8; KEY_LEN] {
+    user_crypto.derive_key(b"akarin handshake psk")
+}
+
+#[derive(Debug)]
+pub struct Session {
+    counter: u64,
+    window: ReplayWindow,
+
+    policy: RekeyPolicy,
+    current: Key,
+    previous: Option<(Key, Instant)>,
+
+    padding: Padding,
+}
+
+impl Session {
+    /// Start the session of a registered client, both ends derive the same keys for `cipher` from `root_crypto`, the
+    /// crypto of the key of the handshake or of the user.
+    pub fn start(root_crypto: &Crypto, cipher: Ciphers, client_id: ClientId, token: ClientToken, window_size: u64,
+                 policy: RekeyPolicy)
+                 -> Result<Self> {
+        let mut context = b"akarin session ".to_vec();
+        context.write_u32::<BigEndian>(client_id).unwrap();
+        context.write_u64::<BigEndian>(token).unwrap();
+        context.push(cipher.id());
+
+        Ok(Session {
+               counter: 0,
+               window: ReplayWindow::new(window_size),
+               policy,
+               current: Key::new(0, cipher.with_key(root_crypto.derive_key(&context))?),
+               previous: None,
+               padding: Padding::None,
+           })
+    }
+
+    pub fn epoch(&self) -> u8 {
+        self.current.epoch
+    }
+
+    /// The crypto of the current epoch.
+    pub fn crypto(&self) -> &Crypto {
+        &*self.current.crypto
+    }
+
+    pub fn window(&self) -> &ReplayWindow {
+        &self.window
+    }
+
+    /// Pad the `Data` messages of the session, only if the other end speaks version 3 or later.
+    pub fn set_padding(&mut self, padding: Padding) {
+        self.padding = padding;
+    }
+
+    pub fn padding(&self) -> Padding {
+        self.padding
+    }
+
+    /// Seal the message in `buf` into a packet in place, switching to the next key first if the current one is worn
+    /// out.
+    pub fn seal_in_place(&mut self, client_id: ClientId, token: ClientToken, buf: &mut PacketBuf) -> Result<()> {
+        if self.worn_out() {
+            let next = self.current.next()?;
+            self.switch(next);
+        }
+
+        let counter = self.counter;
+        self.counter += 1;
+        let len = buf.len();
+        AkarinPacket::seal_in_place(client_id, token, self.current.epoch, counter, buf, &*self.current.crypto)?;
+        self.current.record(len);
+        Ok(())
+    }
+
+    /// Seal `message` into a new datagram, for the messages which are not read from the tun into a buffer of their own.
+    pub fn seal_message(&mut self, client_id: ClientId, token: ClientToken, message: &Message) -> Result<Vec<u8>> {
+        let mut buf = PacketBuf::from_slice(AKARIN_HEADROOM, &message.encode(), AKARIN_TAILROOM);
+        self.seal_in_place(client_id, token, &mut buf)?;
+        Ok(buf.as_slice().to_vec())
+    }
+
+    /// Open the packet in `buf` in place, fails if it is not authentic, replayed or too old. Returns whether the
+    /// packet is the newest one opened so far.
+    ///
+    /// A packet of one of the next `MAX_EPOCH_SKIP` epochs switches the session to it.
+    pub fn open_in_place(&mut self, buf: &mut PacketBuf) -> Result<bool> {
+        let (_, _, epoch) = AkarinPacket::peek(buf.as_slice())?;
+        let ahead = epoch.wrapping_sub(self.current.epoch);
+        let next = if ahead == 0 {
+            None
+        } else if ahead <= MAX_EPOCH_SKIP {
+            let mut next = self.current.next()?;
+            for _ in 1..ahead {
+                next = next.next()?;
+            }
+            Some(next)
+        } else {
+            match self.previous {
+                Some((ref key, expires)) if key.epoch == epoch && Instant::now() < expires => None,
+                _ => return Err(ErrorKind::UnknownEpoch(epoch).into()),
+            }
+        };
+
+        let counter = {
+            let crypto = match next {
+                Some(ref next) => &*next.crypto,
+                None if epoch == self.current.epoch => &*self.current.crypto,
+                None => &*self.previous.as_ref().unwrap().0.crypto,
+            };
+            AkarinPacket::open_in_place(buf, crypto)?
+        };
+
+        let newest = self.window.accept(counter)?;
+        if let Some(next) = next {
+            self.switch(next);
+        }
+        if epoch == self.current.epoch {
+            self.current.record(buf.len());
+        }
+        Ok(newest)
+    }
+
+    fn worn_out(&self) -> bool {
+        let packets = self.policy.packets.min(self.current.crypto.max_messages());
+        self.current.packets >= packets || self.current.bytes >= self.policy.bytes ||
+        self.current.created.elapsed() >= self.policy.interval
+    }
+
+    fn switch(&mut self, next: Key) {
+        debug!("Switching to key epoch {}", next.epoch);
+        let previous = mem::replace(&mut self.current, next);
+        self.previous = Some((previous, Instant::now() + Duration::from_secs(EPOCH_OVERLAP)));
+    }
+}
+
+/// A sliding window over the counters of received packets.
+///
+/// The bitmap is a ring of 64 bits blocks, one bit per counter. Only the block the window slides into is cleared,
+/// so that moving the window forward never costs more than clearing the blocks it skips.
+#[derive(Debug)]
+pub struct ReplayWindow {
+    blocks: Vec<u64>,
+    last: u64,
+
+    replayed: u64,
+    outdated: u64,
+}
+
+impl ReplayWindow {
+    /// Create a window accepting counters at least `size` behind the highest one seen.
+    pub fn new(size: u64) -> Self {
+        // One more block than the window needs, it is the one being filled by the highest counters.
+        let blocks = ((size + BLOCK_BITS - 1) / BLOCK_BITS + 1).next_power_of_two();
+        ReplayWindow {
+            blocks: vec![0; blocks as usize],
+            last: 0,
+            replayed: 0,
+            outdated: 0,
+        }
+    }
+
+    /// Number of counters behind the highest one which are still accepted.
+    pub fn size(&self) -> u64 {
+        (self.blocks.len() as u64 - 1) * BLOCK_BITS
+    }
+
+    /// Number of packets dropped because their counter has been seen already.
+    pub fn replayed(&self) -> u64 {
+        self.replayed
+    }
+
+    /// Number of packets dropped because their counter has fallen behind the window.
+    pub fn outdated(&self) -> u64 {
+        self.outdated
+    }
+
+    /// Record `counter` as seen, returns whether it is the highest counter seen so far.
+    pub fn accept(&mut self, counter: u64) -> Result<bool> {
+        if counter.saturating_add(self.size()) < self.last {
+            self.outdated += 1;
+            return Err(ErrorKind::OutdatedPacket(counter).into());
+        }
+
+        // Only the very first counter can be equal to the highest one without being a replay.
+        let highest = counter >= self.last;
+        let mask = self.blocks.len() as u64 - 1;
+        if counter > self.last {
+            let current = self.last / BLOCK_BITS;
+            let skipped = (counter / BLOCK_BITS - current).min(self.blocks.len() as u64);
+            for i in 1..skipped + 1 {
+                self.blocks[((current + i) & mask) as usize] = 0;
+            }
+            self.last = counter;
+        }
+
+        let block = &mut self.blocks[((counter / BLOCK_BITS) & mask) as usize];
+        let bit = 1u64 << (counter % BLOCK_BITS);
+        if *block & bit != 0 {
+            self.replayed += 1;
+            return Err(ErrorKind::ReplayedPacket(counter).into());
+        }
+        *block |= bit;
+        Ok(highest)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::aead;
+    use crypto::aead_crypto::AeadCrypto;
+    use crypto::kdf::Kdf;
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::new(100);
+        assert_eq!(window.size(), 192);
+
+        let highest: Vec<bool> = [0, 2, 1, 5, 3].iter().map(|counter| window.accept(*counter).unwrap()).collect();
+        assert_eq!(highest, [true, true, false, true, false]);
+        assert!(window.accept(2).is_err());
+        assert!(window.accept(4).is_ok());
+
+        // Jump far ahead, the window slides and clears the blocks it skips.
+        window.accept(1000).unwrap();
+        assert!(window.accept(5).is_err());
+        assert!(window.accept(1000 - 192).is_ok());
+        assert!(window.accept(1000 - 193).is_err());
+        assert!(window.accept(999).is_ok());
+        assert!(window.accept(999).is_err());
+        assert!(window.accept(1064).is_ok());
+        assert!(window.accept(1001).is_ok());
+
+        assert_eq!(window.replayed(), 2);
+        assert_eq!(window.outdated(), 2);
+    }
+
+    fn seal(session: &mut Session, message: &[u8]) -> Vec<u8> {
+        let mut buf = PacketBuf::from_slice(AKARIN_HEADROOM, message, AKARIN_TAILROOM);
+        session.seal_in_place(1, 2, &mut buf).unwrap();
+        buf.as_slice().to_vec()
+    }
+
+    fn open(session: &mut Session, datagram: &[u8]) -> Result<Vec<u8>> {
+        let mut buf = PacketBuf::from_slice(0, datagram, 0);
+        session.open_in_place(&mut buf).map(|_| buf.as_slice().to_vec())
+    }
+
+    #[test]
+    fn test_session() {
+        let user = AeadCrypto::new(&aead::CHACHA20_POLY1305, b"realityone", &Kdf::Legacy).unwrap();
+        let policy = RekeyPolicy {
+            packets: 2,
+            ..RekeyPolicy::default()
+        };
+        let cipher = Ciphers::AES_256_GCM;
+        let mut client = Session::start(&user, cipher, 1, 2, DEFAULT_REPLAY_WINDOW, policy).unwrap();
+        let mut server = Session::start(&user, cipher, 1, 2, DEFAULT_REPLAY_WINDOW, RekeyPolicy::default()).unwrap();
+        assert_eq!(server.crypto().cipher(), cipher);
+
+        let first = seal(&mut client, b"first");
+        let second = seal(&mut client, b"second");
+        assert_eq!(open(&mut server, &second).unwrap(), b"second");
+        assert!(open(&mut server, &second).is_err());
+        assert_eq!(server.window().replayed(), 1);
+
+        // The client is out of packets for the first key, the server follows it to the next epoch.
+        let third = seal(&mut client, b"third");
+        assert_eq!(client.epoch(), 1);
+        assert_eq!(open(&mut server, &third).unwrap(), b"third");
+        assert_eq!(server.epoch(), 1);
+
+        // Packets of the previous epoch are still accepted for a while.
+        assert_eq!(open(&mut server, &first).unwrap(), b"first");
+        assert_eq!(open(&mut client, &seal(&mut server, b"reply")).unwrap(), b"reply");
+        let keepalive = server.seal_message(1, 2, &Message::Keepalive).unwrap();
+        assert_eq!(Message::decode(&open(&mut client, &keepalive).unwrap()).unwrap(), Message::Keepalive);
+
+        // Only packets sealed after every one opened so far are the newest.
+        let (fourth, fifth) = (seal(&mut client, b"fourth"), seal(&mut client, b"fifth"));
+        assert!(server.open_in_place(&mut PacketBuf::from_slice(0, &fifth, 0)).unwrap());
+        assert!(!server.open_in_place(&mut PacketBuf::from_slice(0, &fourth, 0)).unwrap());
+
+        // Every packet of an epoch may be lost, the server follows the client over the epochs it has missed.
+        let epoch = server.epoch() + 2;
+        while client.epoch() != epoch {
+            seal(&mut client, b"lost");
+        }
+        assert_eq!(open(&mut server, &seal(&mut client, b"sixth")).unwrap(), b"sixth");
+        assert_eq!(server.epoch(), epoch);
+        assert_eq!(open(&mut client, &seal(&mut server, b"reply")).unwrap(), b"reply");
+        // But not too far ahead.
+        let ahead = AkarinPacket::seal(1, 2, epoch + MAX_EPOCH_SKIP + 1, 99, b"", server.crypto()).unwrap();
+        assert!(open(&mut server, &ahead).is_err());
+        assert_eq!(server.epoch(), epoch);
+
+        let other = Session::start(&user, cipher, 1, 3, DEFAULT_REPLAY_WINDOW, policy).unwrap();
+        assert!(open(&mut server, &AkarinPacket::seal(1, 3, 1, 9, b"", other.crypto()).unwrap()).is_err());
+    }
+}
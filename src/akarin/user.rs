@@ -0,0 +1,336 @@
+//! Users allowed to connect to the server, each of them with its own pre-shared key.
+//!
+//! The shared `password` of the server belongs to the user `DEFAULT_USER`, other users are read from a TOML file
+//! with one table per user:
+//!
+//! ```toml
+//! [alice]
+//! id = 1
+//! password = "wakaba"
+//!
+//! [bob]
+//! id = 2
+//! password = "lain"
+//! revoked = true
+//!
+//! [carol]
+//! id = 3
+//! password = "navi"
+//! public_keys = ["8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"]
+//! ```
+//!
+//! Clients of a user with `public_keys` must also prove they own one of these keys, see `crypto::noise`.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+use ring::{digest, hmac};
+use ring::rand::SystemRandom;
+use toml::Value;
+
+use common::error::*;
+use crypto::{Crypto, HANDSHAKE_CIPHER};
+use crypto::kdf::Kdf;
+use crypto::x25519::PublicKey;
+
+pub type UserId = u32;
+
+/// The user of the shared `password`, it can not be declared in a users file.
+pub const DEFAULT_USER: UserId = 0;
+
+const USER_KEYS: &[&str] = &["id", "password", "public_keys", "revoked"];
+
+lazy_static!{
+    // Passwords are only kept as digests under this key, which lives as long as the process.
+    static ref FINGERPRINT_KEY: hmac::SigningKey = hmac::SigningKey::generate(&digest::SHA256, &SystemRandom::new())
+        .expect("failed to generate the fingerprint key");
+}
+
+/// A digest of `password`, which tells whether it has changed without keeping it around.
+fn fingerprint(password: &str) -> Vec<u8> {
+    hmac::sign(&FINGERPRINT_KEY, password.as_bytes()).as_ref().to_vec()
+}
+
+#[derive(Clone, Debug)]
+pub struct User {
+    id: UserId,
+    name: String,
+    fingerprint: Vec<u8>,
+    // One key for each derivation accepted, the configured one first, shared with the tables reloaded after this one.
+    keys: Vec<Arc<Crypto>>,
+    public_keys: Vec<PublicKey>,
+}
+
+impl User {
+    pub fn new(id: UserId, name: &str, password: &str, kdfs: &[Kdf]) -> Self {
+        User {
+            id,
+            name: name.to_string(),
+            fingerprint: fingerprint(password),
+            keys: kdfs.iter().map(|kdf| Arc::from(HANDSHAKE_CIPHER.init(password, kdf))).collect(),
+            public_keys: Vec::new(),
+        }
+    }
+
+    /// Only accept clients of the user identified by one of `keys`.
+    pub fn set_public_keys(&mut self, keys: &[PublicKey]) {
+        self.public_keys = keys.to_vec();
+    }
+
+    pub fn id(&self) -> UserId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The key of the configured derivation.
+    pub fn crypto(&self) -> &Crypto {
+        &*self.keys[0]
+    }
+
+    /// Every key of the user, see `ServerConfiguration::kdfs`.
+    pub fn keys(&self) -> &[Arc<Crypto>] {
+        &self.keys
+    }
+
+    /// The static keys the clients of the user may be identified by, any client is accepted if empty.
+    pub fn public_keys(&self) -> &[PublicKey] {
+        &self.public_keys
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct UserTable {
+    users: HashMap<UserId, User>,
+}
+
+impl UserTable {
+    pub fn new() -> Self {
+        UserTable::default()
+    }
+
+    pub fn insert(&mut self, user: User) -> Option<User> {
+        self.users.insert(user.id, user)
+    }
+
+    pub fn get(&self, id: UserId) -> Option<&User> {
+        self.users.get(&id)
+    }
+
+    pub fn revoke(&mut self, id: UserId) -> Option<User> {
+        self.users.remove(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    /// A user with `password`, which shares the keys of the same user of this table if its password has not changed:
+    /// they are only derived, slowly by design, for new users and new passwords.
+    ///
+    /// The keys of the table must have been derived with the same `kdfs`.
+    pub fn derive(&self, id: UserId, name: &str, password: &str, kdfs: &[Kdf]) -> User {
+        let fingerprint = fingerprint(password);
+        match self.users.get(&id) {
+            Some(known) if known.fingerprint == fingerprint => {
+                User {
+                    id,
+                    name: name.to_string(),
+                    fingerprint,
+                    keys: known.keys.clone(),
+                    public_keys: Vec::new(),
+                }
+            }
+            _ => User::new(id, name, password, kdfs),
+        }
+    }
+
+    /// Load the users of a file into the table, users marked as `revoked` are left out.
+    ///
+    /// Users of `known` keep their keys, see `derive`.
+    pub fn load_file(&mut self, path: &Path, kdfs: &[Kdf], known: &UserTable) -> Result<()> {
+        let name = path.display().to_string();
+
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut content))
+            .chain_err(|| ErrorKind::InvalidConfigFile(name.clone(), "failed to read file".to_string()))?;
+        let root = match content.parse::<Value>() {
+            Ok(Value::Table(root)) => root,
+            Ok(_) => unreachable!(),
+            Err(e) => return Err(ErrorKind::InvalidConfigFile(name, e.to_string()).into()),
+        };
+
+        let mut names: HashMap<UserId, String> = HashMap::new();
+        for (user, table) in root.iter() {
+            let origin = |key: &str| format!("{}: `{}.{}`", name, user, key);
+            let invalid = |key: &str, reason: &str| Error::from(ErrorKind::InvalidSetting(origin(key), reason.into()));
+
+            let table = match *table {
+                Value::Table(ref table) => table,
+                _ => {
+                    let origin = format!("{}: `{}`", name, user);
+                    return Err(ErrorKind::InvalidSetting(origin, "expected a table".to_string()).into());
+                }
+            };
+            for key in table.keys() {
+                if !USER_KEYS.contains(&key.as_str()) {
+                    return Err(ErrorKind::UnknownSetting(origin(key)).into());
+                }
+            }
+
+            let id = match table.get("id") {
+                Some(&Value::Integer(id)) if id > DEFAULT_USER as i64 && id <= UserId::max_value() as i64 => {
+                    id as UserId
+                }
+                Some(_) => return Err(invalid("id", &format!("expected an integer in 1..{}", UserId::max_value()))),
+                None => return Err(invalid("id", "missing setting")),
+            };
+            if let Some(other) = names.insert(id, user.clone()) {
+                return Err(invalid("id", &format!("`{}` is already used by `{}`", id, other)));
+            }
+
+            let password = match table.get("password") {
+                Some(&Value::String(ref password)) if !password.is_empty() => password,
+                Some(_) => return Err(invalid("password", "expected a non-empty string")),
+                None => return Err(invalid("password", "missing setting")),
+            };
+            let public_keys = match table.get("public_keys") {
+                Some(&Value::Array(ref keys)) => {
+                    let mut public_keys = Vec::new();
+                    for key in keys.iter() {
+                        let key = match *key {
+                            Value::String(ref key) => key.parse::<PublicKey>(),
+                            _ => return Err(invalid("public_keys", "expected an array of strings")),
+                        };
+                        public_keys.push(key.map_err(|e| invalid("public_keys", &e.to_string()))?);
+                    }
+                    public_keys
+                }
+                Some(_) => return Err(invalid("public_keys", "expected an array of strings")),
+                None => Vec::new(),
+            };
+            match table.get("revoked") {
+                Some(&Value::Boolean(true)) => continue,
+                Some(&Value::Boolean(false)) | None => {}
+                Some(_) => return Err(invalid("revoked", "expected a boolean")),
+            }
+
+            let mut user = known.derive(id, user, password, kdfs);
+            user.set_public_keys(&public_keys);
+            self.insert(user);
+        }
+
+        Ok(())
+    }
+
+    /// Whether any user only accepts clients identified by their static keys.
+    pub fn has_public_keys(&self) -> bool {
+        self.users.values().any(|user| !user.public_keys.is_empty())
+    }
+
+    /// The users which are missing from `other`, or whose password or static keys are different in it.
+    pub fn changed(&self, other: &UserTable) -> Vec<UserId> {
+        self.users
+            .values()
+            .filter(|user| {
+                        other.get(user.id)
+                             .map_or(true, |u| u.fingerprint != user.fingerprint || u.public_keys != user.public_keys)
+                    })
+            .map(|user| user.id)
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn users_from(name: &str, content: &str) -> Result<UserTable> {
+        let path = env::temp_dir().join(format!("akarin-users-{}.toml", name));
+        fs::write(&path, content).unwrap();
+
+        let mut users = UserTable::new();
+        let result = users.load_file(&path, &[Kdf::Legacy], &UserTable::new());
+        fs::remove_file(&path).unwrap();
+        result.map(|_| users)
+    }
+
+    #[test]
+    fn test_load_users() {
+        let content = r#"
+[alice]
+id = 1
+password = "wakaba"
+
+[bob]
+id = 2
+password = "lain"
+revoked = true
+
+[carol]
+id = 3
+password = "navi"
+public_keys = ["8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"]
+"#;
+        let users = users_from("load", content).unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users.get(1).unwrap().name(), "alice");
+        assert!(users.get(2).is_none());
+        assert!(users.get(1).unwrap().public_keys().is_empty());
+        assert_eq!(users.get(3).unwrap().public_keys().len(), 1);
+        assert!(users.has_public_keys());
+
+        let error = users_from("duplicate", "[a]\nid = 1\npassword = \"a\"\n[b]\nid = 1\npassword = \"b\"\n")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("`b.id`"), "{}", error);
+        assert!(users_from("default", "[a]\nid = 0\npassword = \"a\"\n").is_err());
+        assert!(users_from("unknown", "[a]\nid = 1\npassword = \"a\"\nkey = \"a\"\n").is_err());
+        assert!(users_from("missing", "[a]\nid = 1\n").is_err());
+        assert!(users_from("key", "[a]\nid = 1\npassword = \"a\"\npublic_keys = [\"8520f0\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_derive_users() {
+        let mut users = UserTable::new();
+        users.insert(User::new(1, "alice", "wakaba", &[Kdf::Legacy]));
+        let known = &users.get(1).unwrap().keys()[0];
+
+        // Keys are only derived again for a new password or a new user.
+        let user = users.derive(1, "alice", "wakaba", &[Kdf::Legacy]);
+        assert!(Arc::ptr_eq(&user.keys()[0], known));
+        assert!(!Arc::ptr_eq(&users.derive(1, "alice", "knights", &[Kdf::Legacy]).keys()[0], known));
+        assert!(!Arc::ptr_eq(&users.derive(2, "bob", "wakaba", &[Kdf::Legacy]).keys()[0], known));
+        assert!(users.changed(&users.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_changed_users() {
+        let mut users = UserTable::new();
+        users.insert(User::new(1, "alice", "wakaba", &[Kdf::Legacy]));
+        users.insert(User::new(2, "bob", "lain", &[Kdf::Legacy]));
+        users.insert(User::new(3, "carol", "navi", &[Kdf::Legacy]));
+
+        let mut reloaded = UserTable::new();
+        reloaded.insert(User::new(1, "alice", "wakaba", &[Kdf::Legacy]));
+        reloaded.insert(User::new(2, "bob", "knights", &[Kdf::Legacy]));
+        let mut carol = User::new(3, "carol", "navi", &[Kdf::Legacy]);
+        carol.set_public_keys(&["8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a".parse().unwrap()]);
+        reloaded.insert(carol);
+
+        let mut changed = users.changed(&reloaded);
+        changed.sort();
+        assert_eq!(changed, vec![2, 3]);
+        // Passwords are not kept.
+        assert!(!format!("{:?}", users).contains("wakaba"));
+    }
+}
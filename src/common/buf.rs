@@ -0,0 +1,139 @@
+use common::error::*;
+
+/// A packet with room reserved in front of it and behind it.
+///
+/// Headers are written into the headroom and trailers into the tailroom, so that a packet read from the tun is sealed
+/// into a datagram, and a datagram opened into a packet, without moving its payload.
+///
+/// ```text
+/// +----------+------------------+----------+
+/// | headroom |      packet      | tailroom |
+/// +----------+------------------+----------+
+///            ^ start            ^ end
+/// ```
+#[derive(Clone, Debug)]
+pub struct PacketBuf {
+    bytes: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl PacketBuf {
+    /// An empty packet of at most `len` bytes, with `headroom` and `tailroom` bytes around it.
+    pub fn new(headroom: usize, len: usize, tailroom: usize) -> Self {
+        PacketBuf {
+            bytes: vec![0u8; headroom + len + tailroom],
+            start: headroom,
+            end: headroom,
+        }
+    }
+
+    pub fn from_slice(headroom: usize, packet: &[u8], tailroom: usize) -> Self {
+        let mut buf = PacketBuf::new(headroom, packet.len(), tailroom);
+        buf.bytes[headroom..headroom + packet.len()].copy_from_slice(packet);
+        buf.end += packet.len();
+        buf
+    }
+
+    pub fn headroom(&self) -> usize {
+        self.start
+    }
+
+    pub fn tailroom(&self) -> usize {
+        self.bytes.len() - self.end
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[self.start..self.end]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.bytes[self.start..self.end]
+    }
+
+    /// Empty the packet, leaving `headroom` bytes in front of it. The whole buffer is tailroom if `headroom` is zero.
+    pub fn clear(&mut self, headroom: usize) {
+        let headroom = headroom.min(self.bytes.len());
+        self.start = headroom;
+        self.end = headroom;
+    }
+
+    /// The tailroom, to read a packet into before `extend`ing the packet over it.
+    pub fn tail_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes[self.end..]
+    }
+
+    /// Grow the packet by `len` bytes of its tailroom, returning them.
+    pub fn extend(&mut self, len: usize) -> Result<&mut [u8]> {
+        if len > self.tailroom() {
+            return Err(ErrorKind::InsufficientRoom(len).into());
+        }
+        self.end += len;
+        Ok(&mut self.bytes[self.end - len..self.end])
+    }
+
+    /// Grow the packet by `len` bytes of its headroom, returning them.
+    pub fn prepend(&mut self, len: usize) -> Result<&mut [u8]> {
+        if len > self.headroom() {
+            return Err(ErrorKind::InsufficientRoom(len).into());
+        }
+        self.start -= len;
+        Ok(&mut self.bytes[self.start..self.start + len])
+    }
+
+    /// Drop the first `len` bytes of the packet, they become headroom.
+    pub fn advance(&mut self, len: usize) -> Result<()> {
+        if len > self.len() {
+            return Err(ErrorKind::TruncatedPacket(self.len()).into());
+        }
+        self.start += len;
+        Ok(())
+    }
+
+    /// Keep the first `len` bytes of the packet, the rest becomes tailroom.
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len() {
+            self.end = self.start + len;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_buf() {
+        let mut buf = PacketBuf::new(4, 8, 2);
+        assert!(buf.is_empty());
+        assert_eq!(buf.tail_mut().len(), 10);
+
+        buf.tail_mut()[..3].copy_from_slice(b"abc");
+        buf.extend(3).unwrap();
+        buf.prepend(2).unwrap().copy_from_slice(b"<<");
+        buf.extend(2).unwrap().copy_from_slice(b">>");
+        assert_eq!(buf.as_slice(), b"<<abc>>");
+        assert_eq!((buf.headroom(), buf.tailroom()), (2, 5));
+
+        assert!(buf.prepend(3).is_err());
+        assert!(buf.extend(6).is_err());
+
+        buf.advance(2).unwrap();
+        buf.truncate(3);
+        assert_eq!(buf.as_slice(), b"abc");
+        assert!(buf.advance(4).is_err());
+
+        buf.clear(0);
+        assert_eq!(buf.tail_mut().len(), 14);
+        assert_eq!(PacketBuf::from_slice(1, b"akarin", 1).as_slice(), b"akarin");
+    }
+}
@@ -4,18 +4,109 @@ error_chain! {
         TunNameTooLong
         InvalidTunName
         InvalidTunAddress
+        NotSupported
 
         // Crypto
         InitCryptoFailed
+        UnknownCipher(name: String) {
+            description("unknown cipher")
+            display("unknown cipher `{}`, expected `aes_256_gcm` or `chacha20_poly1305`", name)
+        }
+        InvalidKey(key: String) {
+            description("invalid key")
+            display("invalid key `{}`, expected 64 hexadecimal digits of an X25519 key", key)
+        }
+        UnknownTransport(name: String) {
+            description("unknown transport")
+            display("unknown transport `{}`, expected `udp`, `tcp` or `auto`", name)
+        }
+        InvalidPadding(value: String) {
+            description("invalid padding")
+            display("invalid padding `{}`, expected `none`, `mtu`, `bucket:<size>` or `random:<min>-<max>`", value)
+        }
+        HandshakeFailed(reason: String) {
+            description("handshake failed")
+            display("handshake failed: {}", reason)
+        }
 
         // Akarin
         ServerError
+        ServerUnreachable {
+            description("server unreachable")
+            display("server unreachable")
+        }
+        MissingServerAddress {
+            description("missing server address")
+            display("missing setting: `server`")
+        }
         NoSuchClientID
         MaxClientExceed
         ReserveClientIDFailed
+        TruncatedPacket(len: usize) {
+            description("truncated packet")
+            display("truncated packet: {} bytes", len)
+        }
+        InsufficientRoom(len: usize) {
+            description("insufficient room in packet buffer")
+            display("no room for {} more bytes in packet buffer", len)
+        }
+        TruncatedMessage(len: usize) {
+            description("truncated message")
+            display("truncated message: {} bytes", len)
+        }
+        UnknownMessageType(kind: u8) {
+            description("unknown message type")
+            display("unknown message type: {:#04x}", kind)
+        }
+        ReplayedPacket(counter: u64) {
+            description("replayed packet")
+            display("replayed packet: {}", counter)
+        }
+        OutdatedPacket(counter: u64) {
+            description("outdated packet")
+            display("packet {} is behind the replay window", counter)
+        }
+        UnknownEpoch(epoch: u8) {
+            description("unknown key epoch")
+            display("no key for epoch {}", epoch)
+        }
 
         // Transport
         InvalidByteSource
+        InvalidIpv4Packet(reason: String) {
+            description("invalid IPv4 packet")
+            display("invalid IPv4 packet: {}", reason)
+        }
+
+        // Configuration
+        InvalidSubnet(subnet: String) {
+            description("invalid subnet")
+            display("invalid subnet: `{}`", subnet)
+        }
+        NonContiguousNetmask(netmask: ::std::net::Ipv4Addr) {
+            description("non-contiguous netmask")
+            display("non-contiguous netmask: `{}`", netmask)
+        }
+        InvalidConfigFile(path: String, reason: String) {
+            description("invalid configuration file")
+            display("{}: {}", path, reason)
+        }
+        ProfileNotFound(path: String, profile: String) {
+            description("profile not found")
+            display("{}: profile `{}` not found", path, profile)
+        }
+        UnknownSetting(origin: String) {
+            description("unknown setting")
+            display("{}: unknown setting", origin)
+        }
+        InvalidSetting(origin: String, reason: String) {
+            description("invalid setting")
+            display("{}: {}", origin, reason)
+        }
+        MissingSetting(key: String) {
+            description("missing setting")
+            display("missing setting: `{}`", key)
+        }
     }
 
     foreign_links {
@@ -23,5 +114,6 @@ error_chain! {
         Nul(::std::ffi::NulError);
         ParseNum(::std::num::ParseIntError);
         CryptoError(::ring::error::Unspecified);
+        Clap(::clap::Error);
     }
 }
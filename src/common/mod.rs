@@ -1 +1,4 @@
+pub mod buf;
 pub mod error;
+pub mod settings;
+pub mod subnet;
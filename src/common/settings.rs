@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use toml::Value;
+use toml::value::Table;
+
+use common::error::*;
+
+/// Top-level tables of a configuration file.
+const SECTIONS: &[&str] = &["server", "client"];
+
+/// Raw settings of a subcommand, collected from a configuration file, the environment and the command line.
+///
+/// Every value remembers where it comes from, so that an invalid value can be reported together with the file
+/// and key, the environment variable or the argument it was read from.
+#[derive(Debug)]
+pub struct Settings {
+    section: &'static str,
+    keys: &'static [&'static str],
+    values: HashMap<String, (String, String)>,
+}
+
+impl Settings {
+    pub fn new(section: &'static str, keys: &'static [&'static str]) -> Self {
+        Settings {
+            section,
+            keys,
+            values: HashMap::new(),
+        }
+    }
+
+    /// Load the section of a TOML file, then the named profile of the section on top of it.
+    ///
+    /// ```toml
+    /// [client]
+    /// password = "realityone"
+    ///
+    /// [client.office]
+    /// server = "192.0.2.1:8964"
+    /// ```
+    pub fn load_file(&mut self, path: &Path, profile: Option<&str>) -> Result<()> {
+        let name = path.display().to_string();
+
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut content))
+            .chain_err(|| ErrorKind::InvalidConfigFile(name.clone(), "failed to read file".to_string()))?;
+        let root = match content.parse::<Value>() {
+            Ok(Value::Table(root)) => root,
+            Ok(_) => unreachable!(),
+            Err(e) => return Err(ErrorKind::InvalidConfigFile(name, e.to_string()).into()),
+        };
+
+        for key in root.keys() {
+            if !SECTIONS.contains(&key.as_str()) {
+                return Err(ErrorKind::UnknownSetting(format!("{}: `{}`", name, key)).into());
+            }
+        }
+
+        let section = match root.get(self.section) {
+            Some(&Value::Table(ref section)) => section.clone(),
+            Some(_) => {
+                let origin = format!("{}: `{}`", name, self.section);
+                return Err(ErrorKind::InvalidSetting(origin, "expected a table".to_string()).into());
+            }
+            None => Table::new(),
+        };
+
+        // Check every profile, not only the one in use, so that a typo never waits for its profile to be picked.
+        for (key, value) in section.iter() {
+            if let Value::Table(ref table) = *value {
+                self.check_table(&format!("{}: `{}.{}", name, self.section, key), table)?;
+            }
+        }
+        self.load_table(&format!("{}: `{}", name, self.section), &section)?;
+
+        if let Some(profile) = profile {
+            match section.get(profile) {
+                Some(&Value::Table(ref table)) => {
+                    self.load_table(&format!("{}: `{}.{}", name, self.section, profile), table)?
+                }
+                _ => return Err(ErrorKind::ProfileNotFound(name, format!("{}.{}", self.section, profile)).into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Override settings with the `AKARIN_<KEY>` environment variables.
+    pub fn load_env(&mut self) {
+        for key in self.keys {
+            let variable = format!("AKARIN_{}", key.to_uppercase());
+            if let Ok(value) = env::var(&variable) {
+                self.set(key, value, format!("environment variable `{}`", variable));
+            }
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: String, origin: String) {
+        self.values.insert(key.to_string(), (value, origin));
+    }
+
+    pub fn get<T>(&self, key: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.values.get(key) {
+            Some(&(ref value, ref origin)) => {
+                value.parse::<T>()
+                     .map(Some)
+                     .map_err(|e| ErrorKind::InvalidSetting(origin.clone(), format!("`{}`: {}", value, e)).into())
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn require<T>(&self, key: &str) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.get(key)?
+            .ok_or_else(|| ErrorKind::MissingSetting(key.to_string()).into())
+    }
+
+    /// Report that the value of `key` is invalid.
+    pub fn invalid(&self, key: &str, reason: &str) -> Error {
+        let origin = match self.values.get(key) {
+            Some(&(_, ref origin)) => origin.clone(),
+            None => format!("`{}`", key),
+        };
+        ErrorKind::InvalidSetting(origin, reason.to_string()).into()
+    }
+
+    fn check_table(&self, prefix: &str, table: &Table) -> Result<()> {
+        for (key, value) in table.iter() {
+            if value.is_table() || !self.keys.contains(&key.as_str()) {
+                return Err(ErrorKind::UnknownSetting(format!("{}.{}`", prefix, key)).into());
+            }
+        }
+        Ok(())
+    }
+
+    fn load_table(&mut self, prefix: &str, table: &Table) -> Result<()> {
+        for (key, value) in table.iter() {
+            let origin = format!("{}.{}`", prefix, key);
+            let value = match *value {
+                Value::String(ref value) => value.clone(),
+                Value::Integer(value) => value.to_string(),
+                Value::Boolean(value) => value.to_string(),
+                // Profiles are loaded on demand.
+                Value::Table(_) => continue,
+                _ => {
+                    let reason = "expected a string, an integer or a boolean".to_string();
+                    return Err(ErrorKind::InvalidSetting(origin, reason).into());
+                }
+            };
+
+            if !self.keys.contains(&key.as_str()) {
+                return Err(ErrorKind::UnknownSetting(origin).into());
+            }
+            self.set(key, value, origin);
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn settings_from(name: &str, content: &str, profile: Option<&str>) -> Result<Settings> {
+        let path = env::temp_dir().join(format!("akarin-settings-{}.toml", name));
+        fs::write(&path, content).unwrap();
+
+        let mut settings = Settings::new("client", &["server", "password", "mtu"]);
+        let result = settings.load_file(&path, profile);
+        fs::remove_file(&path).unwrap();
+        result.map(|_| settings)
+    }
+
+    #[test]
+    fn test_profiles() {
+        let content = r#"
+[client]
+password = "realityone"
+mtu = 1400
+
+[client.office]
+server = "192.0.2.1:8964"
+mtu = 1300
+"#;
+
+        let settings = settings_from("profiles", content, None).unwrap();
+        assert_eq!(settings.get::<String>("password").unwrap().unwrap(), "realityone");
+        assert_eq!(settings.get::<i32>("mtu").unwrap(), Some(1400));
+        assert!(settings.get::<String>("server").unwrap().is_none());
+
+        let mut settings = settings_from("profiles", content, Some("office")).unwrap();
+        assert_eq!(settings.get::<i32>("mtu").unwrap(), Some(1300));
+        assert!(settings.require::<String>("server").is_ok());
+
+        settings.set("mtu", "abc".to_string(), "argument `--mtu`".to_string());
+        let error = settings.get::<i32>("mtu").unwrap_err().to_string();
+        assert!(error.starts_with("argument `--mtu`: `abc`"));
+
+        assert!(settings_from("profiles", content, Some("home")).is_err());
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        let error = settings_from("unknown", "[client]\nport = 1\n", None).unwrap_err().to_string();
+        assert!(error.ends_with("`client.port`: unknown setting"), "{}", error);
+
+        let error = settings_from("type", "[client.office]\nmtu = [1]\n", Some("office")).unwrap_err().to_string();
+        assert!(error.contains("`client.office.mtu`"), "{}", error);
+
+        assert!(settings_from("section", "[clinet]\n", None).is_err());
+        assert!(settings_from("syntax", "[client\n", None).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use common::error::*;
+
+/// An IPv4 address together with the prefix length of its network, e.g. `10.8.0.1/24`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Subnet {
+    address: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Subnet {
+    pub fn new(address: Ipv4Addr, prefix: u8) -> Result<Self> {
+        if prefix > 32 {
+            return Err(ErrorKind::InvalidSubnet(format!("{}/{}", address, prefix)).into());
+        }
+        Ok(Subnet { address, prefix })
+    }
+
+    /// Create a subnet from an address and a netmask, the netmask must be contiguous.
+    pub fn with_netmask(address: Ipv4Addr, netmask: Ipv4Addr) -> Result<Self> {
+        let mask = u32::from(netmask);
+
+        // The host bits of a contiguous netmask plus one is a power of two.
+        if (!mask).wrapping_add(1) & !mask != 0 {
+            return Err(ErrorKind::NonContiguousNetmask(netmask).into());
+        }
+        Subnet::new(address, mask.count_ones() as u8)
+    }
+
+    pub fn address(&self) -> Ipv4Addr {
+        self.address
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.mask())
+    }
+
+    pub fn network(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.address) & self.mask())
+    }
+
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.address) | !self.mask())
+    }
+
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        u32::from(address) & self.mask() == u32::from(self.network())
+    }
+
+    /// The first address of the subnet usable by a host, /31 and /32 subnets have no network address.
+    pub fn first_host(&self) -> Ipv4Addr {
+        if self.prefix >= 31 {
+            self.network()
+        } else {
+            Ipv4Addr::from(u32::from(self.network()) + 1)
+        }
+    }
+
+    /// The last address of the subnet usable by a host, /31 and /32 subnets have no broadcast address.
+    pub fn last_host(&self) -> Ipv4Addr {
+        if self.prefix >= 31 {
+            self.broadcast()
+        } else {
+            Ipv4Addr::from(u32::from(self.broadcast()) - 1)
+        }
+    }
+
+    pub fn is_host(&self, address: Ipv4Addr) -> bool {
+        self.first_host() <= address && address <= self.last_host()
+    }
+
+    fn mask(&self) -> u32 {
+        if self.prefix == 0 {
+            0
+        } else {
+            !0u32 << (32 - self.prefix as u32)
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = Error;
+
+    /// Parse `address/prefix` or `address/netmask`.
+    fn from_str(value: &str) -> Result<Self> {
+        let invalid = || Error::from(ErrorKind::InvalidSubnet(value.to_string()));
+
+        let mut parts = value.splitn(2, '/');
+        let address = parts.next()
+                           .and_then(|a| a.parse::<Ipv4Addr>().ok())
+                           .ok_or_else(&invalid)?;
+        let prefix = parts.next().ok_or_else(&invalid)?;
+
+        if let Ok(netmask) = prefix.parse::<Ipv4Addr>() {
+            return Subnet::with_netmask(address, netmask);
+        }
+        match prefix.parse::<u8>() {
+            Ok(prefix) if prefix <= 32 => Subnet::new(address, prefix),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+
+/// An IPv6 address together with the prefix length of its network, e.g. `fd00::1/64`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Subnet6 {
+    address: Ipv6Addr,
+    prefix: u8,
+}
+
+impl Subnet6 {
+    pub fn new(address: Ipv6Addr, prefix: u8) -> Result<Self> {
+        if prefix > 128 {
+            return Err(ErrorKind::InvalidSubnet(format!("{}/{}", address, prefix)).into());
+        }
+        Ok(Subnet6 { address, prefix })
+    }
+
+    pub fn address(&self) -> Ipv6Addr {
+        self.address
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn network(&self) -> Ipv6Addr {
+        Ipv6Addr::from(u128::from(self.address) & self.mask())
+    }
+
+    pub fn contains(&self, address: Ipv6Addr) -> bool {
+        u128::from(address) & self.mask() == u128::from(self.network())
+    }
+
+    /// The `index`th address of the network, if it fits in the host bits.
+    pub fn host(&self, index: u128) -> Option<Ipv6Addr> {
+        if index & self.mask() != 0 {
+            return None;
+        }
+        Some(Ipv6Addr::from(u128::from(self.network()) | index))
+    }
+
+    fn mask(&self) -> u128 {
+        if self.prefix == 0 {
+            0
+        } else {
+            !0u128 << (128 - self.prefix as u32)
+        }
+    }
+}
+
+impl FromStr for Subnet6 {
+    type Err = Error;
+
+    /// Parse `address/prefix`.
+    fn from_str(value: &str) -> Result<Self> {
+        let invalid = || Error::from(ErrorKind::InvalidSubnet(value.to_string()));
+
+        let mut parts = value.splitn(2, '/');
+        let address = parts.next()
+                           .and_then(|a| a.parse::<Ipv6Addr>().ok())
+                           .ok_or_else(&invalid)?;
+        match parts.next().map(|p| p.parse::<u8>()) {
+            Some(Ok(prefix)) if prefix <= 128 => Subnet6::new(address, prefix),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Subnet6 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subnet() {
+        let subnet = Subnet::from_str("10.8.0.1/24").unwrap();
+        assert_eq!(subnet.address(), Ipv4Addr::new(10, 8, 0, 1));
+        assert_eq!(subnet.netmask(), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(subnet.network(), Ipv4Addr::new(10, 8, 0, 0));
+        assert_eq!(subnet.broadcast(), Ipv4Addr::new(10, 8, 0, 255));
+        assert!(subnet.contains(Ipv4Addr::new(10, 8, 0, 200)));
+        assert!(!subnet.contains(Ipv4Addr::new(10, 8, 1, 1)));
+        assert_eq!(subnet.first_host(), Ipv4Addr::new(10, 8, 0, 1));
+        assert_eq!(subnet.last_host(), Ipv4Addr::new(10, 8, 0, 254));
+        assert!(!subnet.is_host(subnet.broadcast()));
+
+        assert_eq!(Subnet::from_str("10.8.0.1/255.255.0.0").unwrap().prefix(), 16);
+        assert_eq!(Subnet::from_str("0.0.0.0/0").unwrap().netmask(), Ipv4Addr::new(0, 0, 0, 0));
+        assert_eq!(Subnet::from_str("10.8.0.1/32").unwrap().broadcast(), Ipv4Addr::new(10, 8, 0, 1));
+        assert!(Subnet::from_str("10.8.0.0/31").unwrap().is_host(Ipv4Addr::new(10, 8, 0, 0)));
+
+        assert!(Subnet::from_str("10.8.0.1").is_err());
+        assert!(Subnet::from_str("10.8.0.1/33").is_err());
+        assert!(Subnet::from_str("10.8.0.1/255.0.255.0").is_err());
+    }
+
+    #[test]
+    fn test_subnet6() {
+        let subnet = Subnet6::from_str("fd00::1/64").unwrap();
+        assert_eq!(subnet.network(), Ipv6Addr::from_str("fd00::").unwrap());
+        assert!(subnet.contains(Ipv6Addr::from_str("fd00::ffff").unwrap()));
+        assert!(!subnet.contains(Ipv6Addr::from_str("fd00:0:0:1::1").unwrap()));
+        assert_eq!(subnet.host(0x0a080002), Some(Ipv6Addr::from_str("fd00::a08:2").unwrap()));
+        assert_eq!(Subnet6::from_str("fd00::/120").unwrap().host(0x100), None);
+
+        assert!(Subnet6::from_str("fd00::1").is_err());
+        assert!(Subnet6::from_str("fd00::1/129").is_err());
+    }
+}
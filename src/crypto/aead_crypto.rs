@@ -0,0 +1,171 @@
+use std::fmt;
+
+use ring::{aead, digest, hkdf, hmac, rand};
+use ring::rand::SecureRandom;
+
+use super::{Ciphers, Crypto, KEY_LEN, MAX_NONCE_LEN};
+use super::kdf::Kdf;
+use common::buf::PacketBuf;
+use common::error::*;
+
+pub fn init_crypto(algorithm: &'static aead::Algorithm, password: &str, kdf: &Kdf) -> Box<Crypto> {
+    let crypto = Box::new(AeadCrypto::new(algorithm, password.as_bytes(), kdf)
+                              .map_err(|e| error!("Failed to init crypto: {}", e))
+                              .unwrap());
+
+    info!("Initializing crypto succeed: `{}`", crypto.name());
+    crypto
+}
+
+const REKEY_SALT: &[u8] = b"akarin rekey";
+
+/// A crypto of any AEAD algorithm of ring, with a random nonce before every packet.
+pub struct AeadCrypto {
+    cipher: Ciphers,
+    key: [u8; KEY_LEN],
+    sealing_key: aead::SealingKey,
+    opening_key: aead::OpeningKey,
+    random: rand::SystemRandom,
+}
+
+impl fmt::Debug for AeadCrypto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AeadCrypto {{ cipher: {:?}, key, sealing_key, opening_key, random }}", self.cipher)
+    }
+}
+
+impl AeadCrypto {
+    pub fn new(algorithm: &'static aead::Algorithm, password: &[u8], kdf: &Kdf) -> Result<Self> {
+        Self::with_key(algorithm, kdf.derive(password))
+    }
+
+    pub fn with_key(algorithm: &'static aead::Algorithm, key: [u8; KEY_LEN]) -> Result<Self> {
+        let cipher = Ciphers::ALL.iter()
+                                 .cloned()
+                                 .find(|cipher| cipher.algorithm() == algorithm)
+                                 .ok_or_else(|| ErrorKind::UnknownCipher("unsupported AEAD algorithm".to_string()))?;
+        let sealing_key = aead::SealingKey::new(algorithm, &key)?;
+        let opening_key = aead::OpeningKey::new(algorithm, &key)?;
+
+        Ok(AeadCrypto {
+               cipher,
+               key,
+               sealing_key,
+               opening_key,
+               random: rand::SystemRandom::new(),
+           })
+    }
+}
+
+impl Crypto for AeadCrypto {
+    fn name(&self) -> String {
+        self.cipher.name().to_string()
+    }
+
+    fn nonce_len(&self) -> usize {
+        self.sealing_key.algorithm().nonce_len()
+    }
+
+    fn tag_len(&self) -> usize {
+        self.sealing_key.algorithm().tag_len()
+    }
+
+    fn encrypt_in_place(&self, buf: &mut PacketBuf) -> Result<()> {
+        let tag_len = self.sealing_key.algorithm().tag_len();
+        let nonce_len = self.sealing_key.algorithm().nonce_len();
+
+        let mut nonce = [0u8; MAX_NONCE_LEN];
+        self.random.fill(&mut nonce[..nonce_len])?;
+
+        buf.extend(tag_len)?;
+        aead::seal_in_place(&self.sealing_key, &nonce[..nonce_len], &[], buf.as_mut_slice(), tag_len)?;
+        buf.prepend(nonce_len)?.copy_from_slice(&nonce[..nonce_len]);
+        Ok(())
+    }
+
+    fn decrypt_in_place(&self, buf: &mut PacketBuf) -> Result<()> {
+        let tag_len = self.opening_key.algorithm().tag_len();
+        let nonce_len = self.opening_key.algorithm().nonce_len();
+        if buf.len() < nonce_len + tag_len {
+            return Err(ErrorKind::TruncatedPacket(buf.len()).into());
+        }
+
+        let mut nonce = [0u8; MAX_NONCE_LEN];
+        nonce[..nonce_len].copy_from_slice(&buf.as_slice()[..nonce_len]);
+        buf.advance(nonce_len)?;
+
+        let len = aead::open_in_place(&self.opening_key, &nonce[..nonce_len], &[], 0, buf.as_mut_slice())?.len();
+        buf.truncate(len);
+        Ok(())
+    }
+
+    fn cipher(&self) -> Ciphers {
+        self.cipher
+    }
+
+    fn derive_key(&self, context: &[u8]) -> [u8; KEY_LEN] {
+        let salt = hmac::SigningKey::new(&digest::SHA256, REKEY_SALT);
+        let mut key = [0u8; KEY_LEN];
+        hkdf::extract_and_expand(&salt, &self.key, context, &mut key);
+        key
+    }
+
+    fn max_messages(&self) -> u64 {
+        // Nonces are random, past 2^32 messages the chance of two of them colliding is no longer negligible. This is
+        // also the limit of NIST SP 800-38D for AES-GCM.
+        1 << 32
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        let origin_message = br#"# akarin
+
+Lightweight and stateless IP tunnel.
+"#
+                             .to_vec();
+        for algorithm in &[&aead::AES_256_GCM, &aead::CHACHA20_POLY1305] {
+            let crypto = AeadCrypto::new(algorithm, b"realityone", &Kdf::Legacy).unwrap();
+
+            let cipher_text = {
+                let message = origin_message.to_vec();
+                crypto.encrypt(&message).unwrap()
+            };
+
+            let plain_text = {
+                let message = cipher_text.to_vec();
+                crypto.decrypt(&message).unwrap()
+            };
+
+            assert_eq!(origin_message, plain_text);
+        }
+    }
+
+    #[test]
+    fn test_rekey() {
+        let crypto = AeadCrypto::new(&aead::CHACHA20_POLY1305, b"realityone", &Kdf::Legacy).unwrap();
+        let (first, second) = (crypto.rekey(b"epoch").unwrap(), crypto.rekey(b"epoch").unwrap());
+
+        let cipher_text = first.encrypt(b"akarin").unwrap();
+        assert_eq!(second.decrypt(&cipher_text).unwrap(), b"akarin");
+        assert!(crypto.decrypt(&cipher_text).is_err());
+        assert!(crypto.rekey(b"other").unwrap().decrypt(&cipher_text).is_err());
+    }
+
+    #[test]
+    fn test_other_cipher() {
+        let crypto = AeadCrypto::new(&aead::AES_256_GCM, b"realityone", &Kdf::Legacy).unwrap();
+        let other = AeadCrypto::new(&aead::CHACHA20_POLY1305, b"realityone", &Kdf::Legacy).unwrap();
+
+        let cipher_text = crypto.encrypt(b"akarin").unwrap();
+        assert_eq!(crypto.tag_len(), other.tag_len());
+        assert!(other.decrypt(&cipher_text).is_err());
+        assert_eq!(crypto.rekey(b"epoch").unwrap().name(), "aes_256_gcm");
+        assert!(AeadCrypto::new(&aead::AES_128_GCM, b"realityone", &Kdf::Legacy).is_err());
+    }
+}
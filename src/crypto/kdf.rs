@@ -0,0 +1,59 @@
+//! Derivation of keys from passwords.
+//!
+//! Keys are derived with PBKDF2-HMAC-SHA256, salted with a value shared by a whole deployment. The salt and the
+//! number of iterations must be the same on the server and its clients, or they derive different keys.
+
+use ring::{digest, pbkdf2};
+
+use super::KEY_LEN;
+
+/// Iterations of PBKDF2 when none are configured, and the fewest which are accepted.
+pub const DEFAULT_KDF_ITERATIONS: u32 = 100_000;
+pub const MIN_KDF_ITERATIONS: u32 = 100_000;
+/// Shortest salt accepted, in bytes, NIST SP 800-132 asks for at least 128 bits.
+pub const MIN_KDF_SALT_LEN: usize = 16;
+
+const LEGACY_ITERATIONS: u32 = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Kdf {
+    Pbkdf2 { salt: String, iterations: u32 },
+    /// The derivation of releases without a salt, 1024 iterations and an empty salt. It is only kept so that
+    /// deployments can move to a salt one client at a time.
+    Legacy,
+}
+
+impl Kdf {
+    pub fn derive(&self, password: &[u8]) -> [u8; KEY_LEN] {
+        let mut key = [0u8; KEY_LEN];
+        match *self {
+            Kdf::Pbkdf2 { ref salt, iterations } => {
+                pbkdf2::derive(&digest::SHA256, iterations, salt.as_bytes(), password, &mut key)
+            }
+            Kdf::Legacy => pbkdf2::derive(&digest::SHA256, LEGACY_ITERATIONS, &[], password, &mut key),
+        }
+        key
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive() {
+        let kdf = Kdf::Pbkdf2 {
+            salt: "akarin-deployment".to_string(),
+            iterations: 1,
+        };
+        let other = Kdf::Pbkdf2 {
+            salt: "another-deployment".to_string(),
+            iterations: 1,
+        };
+
+        assert_eq!(kdf.derive(b"realityone"), kdf.derive(b"realityone"));
+        assert!(kdf.derive(b"realityone") != other.derive(b"realityone"));
+        assert!(kdf.derive(b"realityone") != Kdf::Legacy.derive(b"realityone"));
+    }
+}
@@ -1,35 +1,148 @@
-pub mod chacha20_poly1305;
+pub mod aead_crypto;
+pub mod kdf;
+pub mod noise;
+pub mod x25519;
 
 use std::fmt::Debug;
+use std::str::FromStr;
 
+use ring::aead;
+
+use self::kdf::Kdf;
+use common::buf::PacketBuf;
 use common::error::*;
 
-pub trait Crypto: Debug {
+/// Cipher the keys of users are used with, `Hello` and `Welcome` must be readable before a cipher is agreed on.
+pub const HANDSHAKE_CIPHER: Ciphers = Ciphers::CHACHA20_POLY1305;
+
+/// Length of the keys of every cipher.
+pub const KEY_LEN: usize = 32;
+/// Longest nonce and tag of the ciphers, the room to reserve around a packet to encrypt it in place.
+pub const MAX_NONCE_LEN: usize = 12;
+pub const MAX_TAG_LEN: usize = 16;
+
+/// Cryptos are `Send` and `Sync` so that the keys of users can be derived on another thread and shared.
+pub trait Crypto: Debug + Send + Sync {
     fn name(&self) -> String;
 
-    fn encrypt(&self, message: &[u8]) -> Result<Vec<u8>>;
-    fn decrypt(&self, cipher_text: &[u8]) -> Result<Vec<u8>>;
+    fn nonce_len(&self) -> usize;
+    fn tag_len(&self) -> usize;
+
+    /// Encrypt the packet, prepending its nonce in the headroom and appending its tag in the tailroom.
+    fn encrypt_in_place(&self, buf: &mut PacketBuf) -> Result<()>;
+    /// Decrypt a packet made of a nonce, a ciphertext and a tag, leaving only the plaintext in it.
+    ///
+    /// The packet is garbage if this fails.
+    fn decrypt_in_place(&self, buf: &mut PacketBuf) -> Result<()>;
+
+    fn cipher(&self) -> Ciphers;
+    /// Derive a new key from the current one, both ends deriving with the same `context` get the same key, which
+    /// reveals nothing about the current one.
+    fn derive_key(&self, context: &[u8]) -> [u8; KEY_LEN];
+    /// Number of messages which can be encrypted with one key before it must be replaced.
+    fn max_messages(&self) -> u64;
+
+    fn encrypt(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let mut buf = PacketBuf::from_slice(self.nonce_len(), message, self.tag_len());
+        self.encrypt_in_place(&mut buf)?;
+        Ok(buf.as_slice().to_vec())
+    }
+
+    fn decrypt(&self, cipher_text: &[u8]) -> Result<Vec<u8>> {
+        let mut buf = PacketBuf::from_slice(0, cipher_text, 0);
+        self.decrypt_in_place(&mut buf)?;
+        Ok(buf.as_slice().to_vec())
+    }
+
+    /// Derive a crypto of the same cipher with a new key, see `derive_key`.
+    fn rekey(&self, context: &[u8]) -> Result<Box<Crypto>> {
+        self.cipher().with_key(self.derive_key(context))
+    }
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Ciphers {
+    AES_256_GCM,
     CHACHA20_POLY1305,
 }
 
 impl Ciphers {
-    pub fn init(self, password: &str) -> Box<Crypto> {
+    /// Every cipher, from the strongest one.
+    pub const ALL: &'static [Ciphers] = &[Ciphers::AES_256_GCM, Ciphers::CHACHA20_POLY1305];
+
+    pub fn init(self, password: &str, kdf: &Kdf) -> Box<Crypto> {
+        aead_crypto::init_crypto(self.algorithm(), password, kdf)
+    }
+
+    pub fn with_key(self, key: [u8; KEY_LEN]) -> Result<Box<Crypto>> {
+        Ok(Box::new(aead_crypto::AeadCrypto::with_key(self.algorithm(), key)?))
+    }
+
+    /// Identifier of the cipher on the wire.
+    pub fn id(self) -> u8 {
         match self {
-            Ciphers::CHACHA20_POLY1305 => chacha20_poly1305::init_crypto(password),
+            Ciphers::CHACHA20_POLY1305 => 0x01,
+            Ciphers::AES_256_GCM => 0x02,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Ciphers::ALL.iter().cloned().find(|cipher| cipher.id() == id)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Ciphers::AES_256_GCM => "aes_256_gcm",
+            Ciphers::CHACHA20_POLY1305 => "chacha20_poly1305",
+        }
+    }
+
+    /// Length of the nonce of the cipher, the same as `Crypto::nonce_len` of its cryptos.
+    pub fn nonce_len(self) -> usize {
+        self.algorithm().nonce_len()
+    }
+
+    /// Length of the tag of the cipher, the same as `Crypto::tag_len` of its cryptos.
+    pub fn tag_len(self) -> usize {
+        self.algorithm().tag_len()
+    }
+
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Ciphers::AES_256_GCM => &aead::AES_256_GCM,
+            Ciphers::CHACHA20_POLY1305 => &aead::CHACHA20_POLY1305,
         }
     }
 }
 
-impl<'a> From<&'a str> for Ciphers {
-    fn from(name: &str) -> Self {
+impl FromStr for Ciphers {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
         match name {
-            "chacha20_poly1305" => Ciphers::CHACHA20_POLY1305,
-            _ => Ciphers::CHACHA20_POLY1305,
+            "aes_256_gcm" => Ok(Ciphers::AES_256_GCM),
+            "chacha20_poly1305" => Ok(Ciphers::CHACHA20_POLY1305),
+            _ => Err(ErrorKind::UnknownCipher(name.to_string()).into()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ciphers() {
+        for cipher in Ciphers::ALL.iter() {
+            assert_eq!(cipher.name().parse::<Ciphers>().unwrap(), *cipher);
+            assert_eq!(Ciphers::from_id(cipher.id()), Some(*cipher));
+            let crypto = cipher.init("realityone", &Kdf::Legacy);
+            assert_eq!(crypto.name(), cipher.name());
+            assert_eq!((crypto.nonce_len(), crypto.tag_len()), (cipher.nonce_len(), cipher.tag_len()));
         }
+        assert_eq!(Ciphers::from_id(0), None);
+        assert!("aes_128_gcm".parse::<Ciphers>().is_err());
     }
 }
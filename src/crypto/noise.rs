@@ -0,0 +1,622 @@
+//! Handshakes of the Noise protocol framework, with X25519, ChaChaPoly and SHA256.
+//!
+//! ```text
+//! NNpsk0:            IKpsk2:
+//!                      <- s
+//!                      ...
+//!   -> psk, e          -> e, es, s, ss
+//!   <- e, ee           <- e, ee, se, psk
+//! ```
+//!
+//! Both ends generate an ephemeral key for every handshake and mix the pre-shared key, the key of the user, in. Only
+//! ends knowing the key of the user complete the handshake, and since the keys it derives depend on the ephemeral
+//! keys, which are forgotten right away, a key of a user leaked later can not decrypt sessions recorded before.
+//!
+//! `IKpsk2` also authenticates both ends by their static keys: the initiator knows the static key of the responder
+//! beforehand and sends its own, encrypted, in the first message.
+//!
+//! Every message carries an empty payload.
+
+use ring::{aead, agreement, digest, error, hkdf, hmac};
+use ring::rand::SecureRandom;
+use untrusted;
+
+use super::KEY_LEN;
+use super::x25519::{PrivateKey, PublicKey, X25519_KEY_LEN};
+use common::error::*;
+
+const DH_LEN: usize = X25519_KEY_LEN;
+const HASH_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token {
+    E,
+    S,
+    EE,
+    ES,
+    SE,
+    SS,
+    Psk,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pattern {
+    NNpsk0,
+    IKpsk2,
+}
+
+impl Pattern {
+    pub fn id(self) -> u8 {
+        match self {
+            Pattern::NNpsk0 => 0x01,
+            Pattern::IKpsk2 => 0x02,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x01 => Some(Pattern::NNpsk0),
+            0x02 => Some(Pattern::IKpsk2),
+            _ => None,
+        }
+    }
+
+    fn protocol_name(self) -> &'static [u8] {
+        match self {
+            Pattern::NNpsk0 => b"Noise_NNpsk0_25519_ChaChaPoly_SHA256",
+            Pattern::IKpsk2 => b"Noise_IKpsk2_25519_ChaChaPoly_SHA256",
+        }
+    }
+
+    /// The tokens of the messages, the initiator sends the even ones.
+    fn messages(self) -> &'static [&'static [Token]] {
+        use self::Token::*;
+        match self {
+            Pattern::NNpsk0 => &[&[Psk, E], &[E, EE]],
+            Pattern::IKpsk2 => &[&[E, ES, S, SS], &[E, EE, SE, Psk]],
+        }
+    }
+
+    /// Whether the initiator knows the static key of the responder before the handshake.
+    fn responder_known(self) -> bool {
+        self == Pattern::IKpsk2
+    }
+}
+
+/// An ephemeral key of a handshake, agreeing with `ring`.
+///
+/// `ring` lets an ephemeral key agree only once but `IKpsk2` agrees with it twice, so the scalar is kept and handed to
+/// `ring` again for every agreement. It is dropped with the handshake.
+struct EphemeralKey {
+    scalar: [u8; DH_LEN],
+    public_key: PublicKey,
+}
+
+/// Hands the scalar of an ephemeral key to `ring` in place of random bytes.
+struct Scalar<'a>(&'a [u8; DH_LEN]);
+
+impl<'a> SecureRandom for Scalar<'a> {
+    fn fill(&self, dest: &mut [u8]) -> ::std::result::Result<(), error::Unspecified> {
+        if dest.len() != DH_LEN {
+            return Err(error::Unspecified);
+        }
+        dest.copy_from_slice(self.0);
+        Ok(())
+    }
+}
+
+impl EphemeralKey {
+    fn generate(rng: &SecureRandom) -> Result<Self> {
+        let mut scalar = [0u8; DH_LEN];
+        rng.fill(&mut scalar)?;
+        let mut public_key = PublicKey([0u8; DH_LEN]);
+        agreement::EphemeralPrivateKey::generate(&agreement::X25519, &Scalar(&scalar))?
+            .compute_public_key(&mut public_key.0)?;
+        Ok(EphemeralKey { scalar, public_key })
+    }
+
+    /// Agree on a shared secret with `public_key`, fails if the public key is of a small order.
+    fn agree(&self, public_key: &PublicKey) -> Result<[u8; DH_LEN]> {
+        let private_key = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &Scalar(&self.scalar))?;
+        agreement::agree_ephemeral(private_key,
+                                   &agreement::X25519,
+                                   untrusted::Input::from(&public_key.0),
+                                   ErrorKind::InvalidKey(public_key.to_string()).into(),
+                                   |material| {
+                                       let mut shared = [0u8; DH_LEN];
+                                       shared.copy_from_slice(material);
+                                       Ok(shared)
+                                   })
+    }
+}
+
+/// The `SymmetricState` of the specification, the chaining key, the handshake hash and the current key.
+struct SymmetricState {
+    ck: [u8; HASH_LEN],
+    h: [u8; HASH_LEN],
+    k: Option<[u8; KEY_LEN]>,
+    n: u64,
+}
+
+impl SymmetricState {
+    fn new(protocol_name: &[u8]) -> Self {
+        let mut h = [0u8; HASH_LEN];
+        if protocol_name.len() <= HASH_LEN {
+            h[..protocol_name.len()].copy_from_slice(protocol_name);
+        } else {
+            h.copy_from_slice(digest::digest(&digest::SHA256, protocol_name).as_ref());
+        }
+        SymmetricState {
+            ck: h,
+            h,
+            k: None,
+            n: 0,
+        }
+    }
+
+    fn hkdf(&self, ikm: &[u8], out: &mut [u8]) {
+        hkdf::extract_and_expand(&hmac::SigningKey::new(&digest::SHA256, &self.ck), ikm, &[], out);
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(&self.h);
+        ctx.update(data);
+        self.h.copy_from_slice(ctx.finish().as_ref());
+    }
+
+    fn mix_key(&mut self, ikm: &[u8]) {
+        let mut out = [0u8; HASH_LEN * 2];
+        self.hkdf(ikm, &mut out);
+        self.ck.copy_from_slice(&out[..HASH_LEN]);
+        self.set_key(&out[HASH_LEN..]);
+    }
+
+    fn mix_key_and_hash(&mut self, ikm: &[u8]) {
+        let mut out = [0u8; HASH_LEN * 3];
+        self.hkdf(ikm, &mut out);
+        self.ck.copy_from_slice(&out[..HASH_LEN]);
+        self.mix_hash(&out[HASH_LEN..HASH_LEN * 2]);
+        self.set_key(&out[HASH_LEN * 2..]);
+    }
+
+    fn set_key(&mut self, key: &[u8]) {
+        let mut k = [0u8; KEY_LEN];
+        k.copy_from_slice(&key[..KEY_LEN]);
+        self.k = Some(k);
+        self.n = 0;
+    }
+
+    /// The nonce of the specification for ChaChaPoly, 32 zero bits then the counter in little endian.
+    fn nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        for (i, byte) in nonce[4..].iter_mut().enumerate() {
+            *byte = (self.n >> (8 * i)) as u8;
+        }
+        self.n += 1;
+        nonce
+    }
+
+    /// Length of `len` bytes once encrypted, there is no tag before a key is mixed in.
+    fn encrypted_len(&self, len: usize) -> usize {
+        if self.k.is_some() { len + TAG_LEN } else { len }
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut in_out = plaintext.to_vec();
+        if let Some(k) = self.k {
+            in_out.extend_from_slice(&[0u8; TAG_LEN]);
+            let key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &k)?;
+            aead::seal_in_place(&key, &self.nonce(), &self.h, &mut in_out, TAG_LEN)?;
+        }
+        self.mix_hash(&in_out);
+        Ok(in_out)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let mut in_out = ciphertext.to_vec();
+        if let Some(k) = self.k {
+            let key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &k)?;
+            let len = aead::open_in_place(&key, &self.nonce(), &self.h, 0, &mut in_out)?.len();
+            in_out.truncate(len);
+        }
+        self.mix_hash(ciphertext);
+        Ok(in_out)
+    }
+}
+
+/// One end of a handshake.
+pub struct Handshake {
+    pattern: Pattern,
+    initiator: bool,
+    state: SymmetricState,
+    psk: [u8; KEY_LEN],
+
+    s: Option<PrivateKey>,
+    e: Option<EphemeralKey>,
+    rs: Option<PublicKey>,
+    re: Option<PublicKey>,
+
+    // Index of the next message of the pattern.
+    turn: usize,
+}
+
+impl Handshake {
+    /// The end sending the first message, `s` is its static key and `rs` the static key of the responder, both are
+    /// only needed by patterns which authenticate the ends.
+    pub fn initiator(pattern: Pattern, psk: &[u8; KEY_LEN], prologue: &[u8], s: Option<PrivateKey>,
+                     rs: Option<PublicKey>)
+                     -> Result<Self> {
+        if pattern.responder_known() && (s.is_none() || rs.is_none()) {
+            return Err(ErrorKind::HandshakeFailed(format!("{:?} needs both static keys", pattern)).into());
+        }
+        Ok(Handshake::new(pattern, true, psk, prologue, s, rs))
+    }
+
+    /// The end answering the first message, `s` is its static key.
+    pub fn responder(pattern: Pattern, psk: &[u8; KEY_LEN], prologue: &[u8], s: Option<PrivateKey>) -> Result<Self> {
+        if pattern.responder_known() && s.is_none() {
+            return Err(ErrorKind::HandshakeFailed(format!("{:?} needs a static key", pattern)).into());
+        }
+        Ok(Handshake::new(pattern, false, psk, prologue, s, None))
+    }
+
+    fn new(pattern: Pattern, initiator: bool, psk: &[u8; KEY_LEN], prologue: &[u8], s: Option<PrivateKey>,
+           rs: Option<PublicKey>)
+           -> Self {
+        let mut state = SymmetricState::new(pattern.protocol_name());
+        state.mix_hash(prologue);
+        if pattern.responder_known() {
+            let responder = if initiator { rs } else { s.as_ref().map(|s| s.public_key()) };
+            state.mix_hash(&responder.unwrap().0);
+        }
+        Handshake {
+            pattern,
+            initiator,
+            state,
+            psk: *psk,
+            s,
+            e: None,
+            rs,
+            re: None,
+            turn: 0,
+        }
+    }
+
+    pub fn pattern(&self) -> Pattern {
+        self.pattern
+    }
+
+    /// The static key of the other end, once it is known.
+    pub fn remote_static(&self) -> Option<&PublicKey> {
+        self.rs.as_ref()
+    }
+
+    fn tokens(&mut self, writing: bool) -> Result<&'static [Token]> {
+        let messages = self.pattern.messages();
+        if self.turn >= messages.len() || (self.turn % 2 == 0) != (self.initiator == writing) {
+            return Err(ErrorKind::HandshakeFailed("message out of turn".to_string()).into());
+        }
+        self.turn += 1;
+        Ok(messages[self.turn - 1])
+    }
+
+    fn dh(&self, token: Token) -> Result<[u8; DH_LEN]> {
+        let missing = || Error::from(ErrorKind::HandshakeFailed(format!("missing key for `{:?}`", token)));
+        // `es` is between the ephemeral key of the initiator and the static key of the responder, `se` the other
+        // way around.
+        let (ephemeral, remote) = match (token, self.initiator) {
+            (Token::EE, _) => (true, &self.re),
+            (Token::SS, _) => (false, &self.rs),
+            (Token::ES, true) | (Token::SE, false) => (true, &self.rs),
+            (Token::ES, false) | (Token::SE, true) => (false, &self.re),
+            _ => unreachable!(),
+        };
+        let remote = remote.as_ref().ok_or_else(&missing)?;
+        if ephemeral {
+            self.e.as_ref().ok_or_else(&missing)?.agree(remote)
+        } else {
+            self.s.as_ref().ok_or_else(&missing)?.agree(remote)
+        }
+    }
+
+    /// Write the next message of the handshake.
+    pub fn write_message(&mut self, rng: &SecureRandom) -> Result<Vec<u8>> {
+        self.write_payload(&[], rng)
+    }
+
+    /// Read the next message of the handshake, fails if it is not authentic.
+    pub fn read_message(&mut self, message: &[u8]) -> Result<()> {
+        if !self.read_payload(message)?.is_empty() {
+            return Err(ErrorKind::HandshakeFailed("unexpected payload".to_string()).into());
+        }
+        Ok(())
+    }
+
+    fn write_payload(&mut self, payload: &[u8], rng: &SecureRandom) -> Result<Vec<u8>> {
+        let mut message = Vec::new();
+        for token in self.tokens(true)? {
+            match *token {
+                Token::E => {
+                    let e = EphemeralKey::generate(rng)?;
+                    let public_key = e.public_key;
+                    message.extend_from_slice(&public_key.0);
+                    // Every pattern here has a pre-shared key, ephemeral keys then go into the key too.
+                    self.state.mix_hash(&public_key.0);
+                    self.state.mix_key(&public_key.0);
+                    self.e = Some(e);
+                }
+                Token::S => {
+                    let s = self.s
+                                .as_ref()
+                                .ok_or_else(|| ErrorKind::HandshakeFailed("missing static key".to_string()))?;
+                    let public_key = s.public_key();
+                    message.extend(self.state.encrypt_and_hash(&public_key.0)?);
+                }
+                Token::Psk => self.state.mix_key_and_hash(&self.psk),
+                token => {
+                    let shared = self.dh(token)?;
+                    self.state.mix_key(&shared);
+                }
+            }
+        }
+        message.extend(self.state.encrypt_and_hash(payload)?);
+        Ok(message)
+    }
+
+    fn read_payload(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+        let truncated = || Error::from(ErrorKind::TruncatedMessage(message.len()));
+        let mut rest = message;
+        for token in self.tokens(false)? {
+            match *token {
+                Token::E => {
+                    if rest.len() < DH_LEN {
+                        return Err(truncated());
+                    }
+                    let mut public_key = PublicKey([0u8; DH_LEN]);
+                    public_key.0.copy_from_slice(&rest[..DH_LEN]);
+                    rest = &rest[DH_LEN..];
+                    self.state.mix_hash(&public_key.0);
+                    self.state.mix_key(&public_key.0);
+                    self.re = Some(public_key);
+                }
+                Token::S => {
+                    let len = self.state.encrypted_len(DH_LEN);
+                    if rest.len() < len {
+                        return Err(truncated());
+                    }
+                    let mut public_key = PublicKey([0u8; DH_LEN]);
+                    public_key.0.copy_from_slice(&self.state.decrypt_and_hash(&rest[..len])?);
+                    rest = &rest[len..];
+                    self.rs = Some(public_key);
+                }
+                Token::Psk => self.state.mix_key_and_hash(&self.psk),
+                token => {
+                    let shared = self.dh(token)?;
+                    self.state.mix_key(&shared);
+                }
+            }
+        }
+        if rest.len() < self.state.encrypted_len(0) {
+            return Err(truncated());
+        }
+        self.state.decrypt_and_hash(rest)
+    }
+
+    /// The key of the finished handshake, the first key of the split. Sessions seal both directions with keys
+    /// derived from a single one.
+    pub fn split(&self) -> Result<[u8; KEY_LEN]> {
+        if self.turn < self.pattern.messages().len() {
+            return Err(ErrorKind::HandshakeFailed("handshake not finished".to_string()).into());
+        }
+        let mut out = [0u8; HASH_LEN * 2];
+        self.state.hkdf(&[], &mut out);
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(&out[..KEY_LEN]);
+        Ok(key)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+    use ring::rand::SystemRandom;
+
+    /// A generator handing out the ephemeral key of a test vector.
+    struct FixedRandom(Vec<u8>);
+
+    impl SecureRandom for FixedRandom {
+        fn fill(&self, dest: &mut [u8]) -> ::std::result::Result<(), error::Unspecified> {
+            dest.copy_from_slice(&self.0[..dest.len()]);
+            Ok(())
+        }
+    }
+
+    struct Vector {
+        pattern: Pattern,
+        init_static: Option<&'static str>,
+        init_ephemeral: &'static str,
+        init_remote_static: Option<&'static str>,
+        resp_static: Option<&'static str>,
+        resp_ephemeral: &'static str,
+        handshake_hash: &'static str,
+        messages: &'static [(&'static str, &'static str)],
+    }
+
+    // Test vectors of cacophony, https://github.com/haskell-cryptography/cacophony, all of them with this prologue
+    // and pre-shared key.
+    const PROLOGUE: &str = "4a6f686e2047616c74";
+    const PSK: &str = "54686973206973206d7920417573747269616e20706572737065637469766521";
+
+    const NN_PSK0: Vector = Vector {
+        pattern: Pattern::NNpsk0,
+        init_static: None,
+        init_ephemeral: "893e28b9dc6ca8d611ab664754b8ceb7bac5117349a4439a6b0569da977c464a",
+        init_remote_static: None,
+        resp_static: None,
+        resp_ephemeral: "bbdb4cdbd309f1a1f2e1456967fe288cadd6f712d65dc7b7793d5e63da6b375b",
+        handshake_hash: "f4d03dc34495c95729ea6de9e1b59004b59733102488b3e24bc441e0be208eaf",
+        messages: &[("4c756477696720766f6e204d69736573",
+                     "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c7944\
+                      79b962b8aff8485742ac32f905ba45369e2465fb59e138a93d67a0d1266b6a54"),
+                    ("4d757272617920526f746862617264",
+                     "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f144808843\
+                      d6062704d5a9c422a8e834423f8c1feada7e8d0d910a1a2cd030fb584221e3"),
+                    ("462e20412e20486179656b",
+                     "e632c3763d7669067383433197a3baddf146e9e70ad4b4e9e59e0f"),
+                    ("4361726c204d656e676572",
+                     "64c6bee32ea91c8474bb4c21d7a700109ad45af77b29764ba5eb1e"),
+                    ("4a65616e2d426170746973746520536179",
+                     "e2fa0bed0603b62d3ccac2ecabbf3fe33f3e86514909b323361626266cb2471cc8"),
+                    ("457567656e2042f6686d20766f6e2042617765726b",
+                     "0c01dc9cec1fe4ddd692e8dd32188aa351088dc91183639a53b57aa4692b5ebd\
+                      ef8b8ca111")],
+    };
+
+    const IK_PSK2: Vector = Vector {
+        pattern: Pattern::IKpsk2,
+        init_static: Some("e61ef9919cde45dd5f82166404bd08e38bceb5dfdfded0a34c8df7ed542214d1"),
+        init_ephemeral: "893e28b9dc6ca8d611ab664754b8ceb7bac5117349a4439a6b0569da977c464a",
+        init_remote_static: Some("31e0303fd6418d2f8c0e78b91f22e8caed0fbe48656dcf4767e4834f701b8f62"),
+        resp_static: Some("4a3acbfdb163dec651dfa3194dece676d437029c62a408b4c5ea9114246e4893"),
+        resp_ephemeral: "bbdb4cdbd309f1a1f2e1456967fe288cadd6f712d65dc7b7793d5e63da6b375b",
+        handshake_hash: "8310f86394dc0dabb40beb8210031556db4403ab1202db7034c526232147a700",
+        messages: &[("4c756477696720766f6e204d69736573",
+                     "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c7944\
+                      2ec9b09893d0f510791784c10cbc959f25b1766e0def6e301d14fbca1c7790ac\
+                      829b8b3674f5f649a5f0e98479662cbfbf2b2c47cd4b09fcd266cd29d7cb675f\
+                      1808849707847840f6d178ec4d3733aa"),
+                    ("4d757272617920526f746862617264",
+                     "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f144808843\
+                      9a1b3cebf680b2c74217fcb5eba4ff58a9468cd90c4aca6194f57479b379a7"),
+                    ("462e20412e20486179656b",
+                     "a8fde7a0accec190cd306c5950d4fd8e04a205ec288aa747d8b347"),
+                    ("4361726c204d656e676572",
+                     "59caddd9984a3bbe24c4fb31a2bd455b7eba3fa0980674b1a3a5f9"),
+                    ("4a65616e2d426170746973746520536179",
+                     "3b9bfebd210c22ba0cff9de79b4007d7a552fffbf92616881faa8a883e25b80258"),
+                    ("457567656e2042f6686d20766f6e2042617765726b",
+                     "f37512df1043d564d7c46ac85c53d3b6a9a05724bc297e7142808f2175616512\
+                      17fe85b782")],
+    };
+
+    fn hex(value: &str) -> Vec<u8> {
+        (0..value.len() / 2).map(|i| u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).unwrap()).collect()
+    }
+
+    /// Seal a transport message of a split handshake.
+    fn seal(key: &[u8], n: u64, payload: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; 12];
+        LittleEndian::write_u64(&mut nonce[4..], n);
+        let mut in_out = payload.to_vec();
+        in_out.extend_from_slice(&[0u8; TAG_LEN]);
+        aead::seal_in_place(&aead::SealingKey::new(&aead::CHACHA20_POLY1305, key).unwrap(), &nonce, &[],
+                            &mut in_out, TAG_LEN)
+                .unwrap();
+        in_out
+    }
+
+    fn handshake(initiator: &mut Handshake, responder: &mut Handshake) -> Result<()> {
+        let rng = SystemRandom::new();
+        responder.read_message(&initiator.write_message(&rng)?)?;
+        initiator.read_message(&responder.write_message(&rng)?)
+    }
+
+    #[test]
+    fn test_vectors() {
+        let mut psk = [0u8; KEY_LEN];
+        psk.copy_from_slice(&hex(PSK));
+        let prologue = hex(PROLOGUE);
+        for vector in &[NN_PSK0, IK_PSK2] {
+            let init_static = vector.init_static.map(|key| key.parse::<PrivateKey>().unwrap());
+            let resp_static = vector.resp_static.map(|key| key.parse::<PrivateKey>().unwrap());
+            let init_remote_static = vector.init_remote_static.map(|key| key.parse::<PublicKey>().unwrap());
+            assert_eq!(init_remote_static, resp_static.as_ref().map(|key| key.public_key()));
+
+            let mut initiator =
+                Handshake::initiator(vector.pattern, &psk, &prologue, init_static.clone(), init_remote_static)
+                    .unwrap();
+            let mut responder = Handshake::responder(vector.pattern, &psk, &prologue, resp_static).unwrap();
+
+            let (payload, ciphertext) = vector.messages[0];
+            let message = initiator.write_payload(&hex(payload), &FixedRandom(hex(vector.init_ephemeral))).unwrap();
+            assert_eq!(message, hex(ciphertext));
+            assert_eq!(responder.read_payload(&message).unwrap(), hex(payload));
+            assert_eq!(responder.remote_static(), init_static.map(|key| key.public_key()).as_ref());
+
+            let (payload, ciphertext) = vector.messages[1];
+            let message = responder.write_payload(&hex(payload), &FixedRandom(hex(vector.resp_ephemeral))).unwrap();
+            assert_eq!(message, hex(ciphertext));
+            assert_eq!(initiator.read_payload(&message).unwrap(), hex(payload));
+
+            assert_eq!(initiator.state.h.to_vec(), hex(vector.handshake_hash));
+            assert_eq!(responder.state.h.to_vec(), hex(vector.handshake_hash));
+
+            // Transport messages, the initiator sends with the first key of the split and the responder with the
+            // second one.
+            let mut keys = [0u8; HASH_LEN * 2];
+            responder.state.hkdf(&[], &mut keys);
+            assert_eq!(initiator.split().unwrap(), responder.split().unwrap());
+            assert_eq!(&initiator.split().unwrap()[..], &keys[..KEY_LEN]);
+            for (i, &(payload, ciphertext)) in vector.messages[2..].iter().enumerate() {
+                let key = if i % 2 == 0 { &keys[..KEY_LEN] } else { &keys[HASH_LEN..HASH_LEN + KEY_LEN] };
+                assert_eq!(seal(key, (i / 2) as u64, &hex(payload)), hex(ciphertext));
+            }
+        }
+    }
+
+    #[test]
+    fn test_nn_handshake() {
+        let psk = [7u8; KEY_LEN];
+        let mut initiator = Handshake::initiator(Pattern::NNpsk0, &psk, b"akarin", None, None).unwrap();
+        let mut responder = Handshake::responder(Pattern::NNpsk0, &psk, b"akarin", None).unwrap();
+        assert!(initiator.split().is_err());
+        handshake(&mut initiator, &mut responder).unwrap();
+        let key = initiator.split().unwrap();
+        assert_eq!(responder.split().unwrap(), key);
+        assert!(responder.write_message(&SystemRandom::new()).is_err());
+
+        // Every handshake derives another key.
+        let mut initiator = Handshake::initiator(Pattern::NNpsk0, &psk, b"akarin", None, None).unwrap();
+        let mut responder = Handshake::responder(Pattern::NNpsk0, &psk, b"akarin", None).unwrap();
+        handshake(&mut initiator, &mut responder).unwrap();
+        assert!(initiator.split().unwrap() != key);
+
+        let mut initiator = Handshake::initiator(Pattern::NNpsk0, &psk, b"akarin", None, None).unwrap();
+        let mut responder = Handshake::responder(Pattern::NNpsk0, &[8u8; KEY_LEN], b"akarin", None).unwrap();
+        assert!(handshake(&mut initiator, &mut responder).is_err());
+        let mut initiator = Handshake::initiator(Pattern::NNpsk0, &psk, b"akarin", None, None).unwrap();
+        let mut responder = Handshake::responder(Pattern::NNpsk0, &psk, b"another prologue", None).unwrap();
+        assert!(handshake(&mut initiator, &mut responder).is_err());
+    }
+
+    #[test]
+    fn test_ik_handshake() {
+        let rng = SystemRandom::new();
+        let psk = [7u8; KEY_LEN];
+        let client = PrivateKey::generate(&rng).unwrap();
+        let server = PrivateKey::generate(&rng).unwrap();
+
+        let mut initiator = Handshake::initiator(Pattern::IKpsk2, &psk, b"akarin", Some(client.clone()),
+                                                 Some(server.public_key()))
+                .unwrap();
+        let mut responder = Handshake::responder(Pattern::IKpsk2, &psk, b"akarin", Some(server.clone())).unwrap();
+        handshake(&mut initiator, &mut responder).unwrap();
+        assert_eq!(responder.remote_static(), Some(&client.public_key()));
+        assert_eq!(initiator.split().unwrap(), responder.split().unwrap());
+
+        // A client pinning another key of the server, or without its own, can not start.
+        let other = PrivateKey::generate(&rng).unwrap();
+        let mut initiator = Handshake::initiator(Pattern::IKpsk2, &psk, b"akarin", Some(client.clone()),
+                                                 Some(other.public_key()))
+                .unwrap();
+        let mut responder = Handshake::responder(Pattern::IKpsk2, &psk, b"akarin", Some(server)).unwrap();
+        assert!(handshake(&mut initiator, &mut responder).is_err());
+        assert!(Handshake::initiator(Pattern::IKpsk2, &psk, b"akarin", None, Some(other.public_key())).is_err());
+        assert!(Handshake::responder(Pattern::IKpsk2, &psk, b"akarin", None).is_err());
+    }
+}
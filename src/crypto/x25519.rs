@@ -0,0 +1,141 @@
+//! Static X25519 keys identifying servers and clients.
+//!
+//! `ring::agreement` only lets an ephemeral key agree once, static keys agree with `x25519-dalek` instead.
+//!
+//! Keys are written as 64 hexadecimal digits.
+
+use std::fmt;
+use std::str::FromStr;
+
+use ring::rand::SecureRandom;
+use x25519_dalek::{self, StaticSecret};
+
+use common::error::*;
+
+pub const X25519_KEY_LEN: usize = 32;
+
+fn decode_hex(value: &str) -> Result<[u8; X25519_KEY_LEN]> {
+    let invalid = || Error::from(ErrorKind::InvalidKey(value.to_string()));
+    if value.len() != X25519_KEY_LEN * 2 || !value.is_ascii() {
+        return Err(invalid());
+    }
+    let mut key = [0u8; X25519_KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+fn encode_hex(key: &[u8; X25519_KEY_LEN], f: &mut fmt::Formatter) -> fmt::Result {
+    for byte in key.iter() {
+        write!(f, "{:02x}", byte)?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey(pub [u8; X25519_KEY_LEN]);
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        encode_hex(&self.0, f)
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        decode_hex(value).map(PublicKey)
+    }
+}
+
+#[derive(Clone)]
+pub struct PrivateKey(StaticSecret);
+
+impl PrivateKey {
+    pub fn generate(rng: &SecureRandom) -> Result<Self> {
+        let mut key = [0u8; X25519_KEY_LEN];
+        rng.fill(&mut key)?;
+        Ok(PrivateKey(StaticSecret::from(key)))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(x25519_dalek::PublicKey::from(&self.0).to_bytes())
+    }
+
+    /// Agree on a shared secret with `public_key`, fails if the public key is of a small order and the secret would
+    /// not depend on the private key.
+    pub fn agree(&self, public_key: &PublicKey) -> Result<[u8; X25519_KEY_LEN]> {
+        let shared = self.0.diffie_hellman(&x25519_dalek::PublicKey::from(public_key.0));
+        if !shared.was_contributory() {
+            return Err(ErrorKind::InvalidKey(public_key.to_string()).into());
+        }
+        Ok(shared.to_bytes())
+    }
+}
+
+impl fmt::Display for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        encode_hex(self.0.as_bytes(), f)
+    }
+}
+
+// Private keys stay out of logs.
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PrivateKey({})", self.public_key())
+    }
+}
+
+impl FromStr for PrivateKey {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        // Keep the secret out of error messages and logs.
+        decode_hex(value).map(|key| PrivateKey(StaticSecret::from(key)))
+                         .map_err(|_| ErrorKind::InvalidKey("<private key>".to_string()).into())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+
+    fn key(value: &str) -> [u8; X25519_KEY_LEN] {
+        decode_hex(value).unwrap()
+    }
+
+    #[test]
+    fn test_x25519() {
+        // RFC 7748, section 6.1.
+        let alice: PrivateKey = "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a".parse().unwrap();
+        let bob: PrivateKey = "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb".parse().unwrap();
+        assert_eq!(alice.public_key().to_string(), "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+        assert_eq!(bob.public_key().to_string(), "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+        let shared = key("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        assert_eq!(alice.agree(&bob.public_key()).unwrap(), shared);
+        assert_eq!(bob.agree(&alice.public_key()).unwrap(), shared);
+
+        assert!(alice.agree(&PublicKey([0; X25519_KEY_LEN])).is_err());
+    }
+
+    #[test]
+    fn test_keys() {
+        let private_key = PrivateKey::generate(&SystemRandom::new()).unwrap();
+        let parsed: PrivateKey = private_key.to_string().parse().unwrap();
+        assert_eq!(parsed.public_key(), private_key.public_key());
+        assert!(!format!("{:?}", private_key).contains(&private_key.to_string()));
+
+        assert!("8520f0".parse::<PublicKey>().is_err());
+        assert!("zz20f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a".parse::<PublicKey>().is_err());
+    }
+}
@@ -1,3 +1,4 @@
+#![recursion_limit = "256"]
 #![allow(dead_code)]
 
 #[macro_use]
@@ -10,12 +11,17 @@ extern crate ring;
 extern crate libc;
 extern crate byteorder;
 #[macro_use]
+extern crate clap;
+#[macro_use]
 extern crate log;
 extern crate mio;
 extern crate pretty_env_logger;
 #[macro_use]
 extern crate tokio_core;
+extern crate toml;
 extern crate transient_hashmap;
+extern crate untrusted;
+extern crate x25519_dalek;
 
 #[cfg(unix)]
 #[macro_use]
@@ -27,12 +33,214 @@ mod common;
 mod crypto;
 mod transport;
 
-use crypto::Ciphers;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::Duration;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use ring::rand::SystemRandom;
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::Core;
+
+use akarin::{Client, Server};
+use akarin::client::AkarinClient;
+use akarin::configuration::{CLIENT_KEYS, ClientConfiguration, DEFAULT_CLIENT_TIMEOUT, SERVER_KEYS,
+                            ServerConfiguration};
+use akarin::pmtu::tun_mtu;
+use akarin::server::AkarinServer;
+use common::error::*;
+use common::settings::Settings;
+use crypto::HANDSHAKE_CIPHER;
+use crypto::x25519::PrivateKey;
+use transport::{Endpoint, ServerTransport};
+use transport::mtu::{DEFAULT_PATH_MTU, interface_mtu, set_dont_fragment};
+use transport::tcp::{DEFAULT_MAX_CONNECTIONS, TcpServerTransport};
+use transport::udp::UdpTransport;
+use tun::os::tokio::Device;
 
-fn main() {
+quick_main!(run);
+
+fn run() -> Result<()> {
     // setup logger
     pretty_env_logger::init().unwrap();
 
-    let password = "realityone";
-    let crypto = Ciphers::CHACHA20_POLY1305.init(password);
+    let config = Arg::with_name("config")
+        .long("config")
+        .takes_value(true)
+        .help("Read settings from a TOML file, arguments and `AKARIN_*` variables take precedence");
+    let profile = Arg::with_name("profile")
+        .long("profile")
+        .takes_value(true)
+        .requires("config")
+        .help("Profile of the configuration file to use");
+    let password = Arg::with_name("password")
+        .long("password")
+        .takes_value(true)
+        .help("Password shared by the server and its clients");
+    let ciphers = Arg::with_name("ciphers")
+        .long("ciphers")
+        .takes_value(true)
+        .help("Ciphers to use by order of preference [default: aes_256_gcm,chacha20_poly1305]");
+    let tun = Arg::with_name("tun")
+        .long("tun")
+        .takes_value(true)
+        .help("Name of the tun device");
+    let mtu = Arg::with_name("mtu")
+        .long("mtu")
+        .takes_value(true)
+        .help("MTU of the tun device");
+
+    let matches = App::new("akarin")
+        .version(crate_version!())
+        .about("Lightweight and stateless IP tunnel.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("server")
+                        .about("Run an akarin server")
+                        .arg(Arg::with_name("listen")
+                                 .long("listen")
+                                 .takes_value(true)
+                                 .help("Address to receive client packets on [default: 0.0.0.0:8964]"))
+                        .arg(Arg::with_name("subnet")
+                                 .long("subnet")
+                                 .takes_value(true)
+                                 .help("Subnet client addresses are leased from [default: 10.8.0.1/24]"))
+                        .arg(Arg::with_name("address")
+                                 .long("address")
+                                 .takes_value(true)
+                                 .help("Address of the server inside the tunnel, overrides the one of `--subnet`"))
+                        .arg(Arg::with_name("subnet6")
+                                 .long("subnet6")
+                                 .takes_value(true)
+                                 .help("IPv6 subnet of the tunnel and the server address in it, e.g. `fd00::1/64`"))
+                        .arg(Arg::with_name("timeout")
+                                 .long("timeout")
+                                 .takes_value(true)
+                                 .help("Seconds before an idle client is forgotten [default: 60]"))
+                        .arg(Arg::with_name("users")
+                                 .long("users")
+                                 .takes_value(true)
+                                 .help("TOML file of users with their own passwords, reloaded when it changes"))
+                        .arg(config.clone())
+                        .arg(profile.clone())
+                        .arg(password.clone())
+                        .arg(ciphers.clone())
+                        .arg(tun.clone())
+                        .arg(mtu.clone()))
+        .subcommand(SubCommand::with_name("client")
+                        .about("Connect to an akarin server")
+                        .arg(Arg::with_name("server")
+                                 .long("server")
+                                 .takes_value(true)
+                                 .help("Address of the server"))
+                        .arg(Arg::with_name("transport")
+                                 .long("transport")
+                                 .takes_value(true)
+                                 .help("`udp`, `tcp`, or `auto` to fall back to TCP if UDP fails [default: udp]"))
+                        .arg(Arg::with_name("user")
+                                 .long("user")
+                                 .takes_value(true)
+                                 .help("Id of the user to connect as, the user of the shared password if not set"))
+                        .arg(config)
+                        .arg(profile)
+                        .arg(password)
+                        .arg(ciphers)
+                        .arg(tun)
+                        .arg(mtu))
+        .subcommand(SubCommand::with_name("genkey").about("Print a new private key"))
+        .subcommand(SubCommand::with_name("pubkey").about("Print the public key of the private key read from stdin"))
+        .get_matches();
+
+    match matches.subcommand() {
+        ("server", Some(matches)) => run_server(&load_settings("server", SERVER_KEYS, matches)?),
+        ("client", Some(matches)) => run_client(&load_settings("client", CLIENT_KEYS, matches)?),
+        ("genkey", Some(_)) => Ok(println!("{}", PrivateKey::generate(&SystemRandom::new())?)),
+        ("pubkey", Some(_)) => {
+            let mut private_key = String::new();
+            io::stdin().read_to_string(&mut private_key)?;
+            Ok(println!("{}", private_key.trim().parse::<PrivateKey>()?.public_key()))
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Collect settings from the configuration file, the environment and the command line, in that order.
+fn load_settings(section: &'static str, keys: &'static [&'static str], matches: &ArgMatches) -> Result<Settings> {
+    let mut settings = Settings::new(section, keys);
+
+    if let Some(path) = matches.value_of("config") {
+        settings.load_file(Path::new(path), matches.value_of("profile"))?;
+    }
+    settings.load_env();
+    for key in keys {
+        if let Some(value) = matches.value_of(key) {
+            settings.set(key, value.to_string(), format!("argument `--{}`", key));
+        }
+    }
+
+    Ok(settings)
+}
+
+fn run_server(settings: &Settings) -> Result<()> {
+    let configuration = ServerConfiguration::from_settings(settings)?;
+
+    let subnet = configuration.tunnel_subnet();
+    let listen_address = configuration.listen_address
+                                      .unwrap_or_else(|| "0.0.0.0:8964".parse::<SocketAddr>().unwrap());
+
+    let mut tun_configuration = tun::Configuration::from_settings(settings)?;
+    tun_configuration.address(configuration.tunnel_address())
+                     .netmask(subnet.netmask())
+                     .up();
+    if let Some(subnet_v6) = configuration.subnet_v6 {
+        tun_configuration.address_v6(subnet_v6);
+    }
+    if tun_configuration.mtu.is_none() {
+        let path_mtu = interface_mtu(&listen_address.ip()).unwrap_or_else(|e| {
+            warn!("Failed to get the MTU of the interface of {}, assuming {}: {}", listen_address, DEFAULT_PATH_MTU, e);
+            DEFAULT_PATH_MTU
+        });
+        let mtu = tun_mtu(path_mtu, configuration.supported_ciphers(), &Endpoint::Udp(listen_address));
+        info!("MTU of the tun: {}", mtu);
+        tun_configuration.mtu(mtu as i32);
+    }
+
+    let core = Core::new()?;
+    let handle = core.handle();
+    let tun = Device::new(tun::create(&tun_configuration)?, &handle)?;
+    let udp = UdpSocket::bind(&listen_address, &handle)?;
+    // Datagrams too large for the path to a client fail to send, so that their packets are rejected with an ICMP
+    // error instead of being fragmented.
+    set_dont_fragment(udp.as_raw_fd(), listen_address.is_ipv6())?;
+    let udp = UdpTransport::new(udp);
+    info!("Listening on: {}", listen_address);
+    let tcp = if configuration.tcp.unwrap_or(false) {
+        // A connection idle for longer than the timeout of clients has no client left.
+        let idle_timeout = Duration::from_secs(configuration.client_timeout.unwrap_or(DEFAULT_CLIENT_TIMEOUT) as u64);
+        let max_connections = configuration.tcp_max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS);
+        let tcp = TcpServerTransport::bind(&listen_address, max_connections, idle_timeout, &handle)?;
+        info!("Accepting TCP connections on: {}", listen_address);
+        Some(tcp)
+    } else {
+        None
+    };
+    let transport = ServerTransport::new(udp, tcp);
+
+    AkarinServer::new(tun, Box::new(transport), &configuration, &handle)?.serve(core, handle)
+}
+
+fn run_client(settings: &Settings) -> Result<()> {
+    let configuration = ClientConfiguration::from_settings(settings)?;
+    let password = settings.require::<String>("password")?;
+
+    let mut tun_configuration = tun::Configuration::from_settings(settings)?;
+    tun_configuration.up();
+
+    let crypto = HANDSHAKE_CIPHER.init(&password, &configuration.kdf()?);
+
+    let core = Core::new()?;
+    let handle = core.handle();
+
+    AkarinClient::new(&*crypto, &configuration, &tun_configuration).connect(core, handle)
 }
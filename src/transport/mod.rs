@@ -1 +1,180 @@
+//! The ways datagrams of the tunnel travel between a client and the server.
+//!
+//! UDP carries every datagram as it is. TCP, for networks which do not let UDP through, carries them over a
+//! connection as frames of a 2 bytes, big endian, length followed by the datagram.
+
+pub mod mtu;
 pub mod network;
+pub mod tcp;
+pub mod udp;
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use self::tcp::{FRAME_HEADER_LEN, TcpServerTransport};
+use self::udp::UdpTransport;
+use common::error::*;
+
+/// Where a datagram comes from or goes to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+}
+
+impl Endpoint {
+    pub fn address(&self) -> SocketAddr {
+        match *self {
+            Endpoint::Udp(address) | Endpoint::Tcp(address) => address,
+        }
+    }
+
+    /// Bytes the headers of IP and of the transport add to a datagram sent to the endpoint, assuming TCP segments
+    /// without options.
+    pub fn overhead(&self) -> usize {
+        let ip = match self.address() {
+            SocketAddr::V4(_) => 20,
+            SocketAddr::V6(_) => 40,
+        };
+        match *self {
+            Endpoint::Udp(_) => ip + 8,
+            Endpoint::Tcp(_) => ip + 20 + FRAME_HEADER_LEN,
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Endpoint::Udp(address) => write!(f, "udp://{}", address),
+            Endpoint::Tcp(address) => write!(f, "tcp://{}", address),
+        }
+    }
+}
+
+/// Sends and receives the datagrams of the tunnel.
+///
+/// Like the sockets of `tokio_core`, a transport never blocks: a call which can not make progress fails with
+/// `WouldBlock` and the current task is notified once it can.
+pub trait Transport: fmt::Debug {
+    /// Receive a datagram into `buf`, returning its length and where it comes from.
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, Endpoint)>;
+
+    /// Send a datagram to `endpoint`.
+    fn send_to(&mut self, buf: &[u8], endpoint: &Endpoint) -> io::Result<()>;
+
+    /// Write out the datagrams `send_to` has not been able to send right away.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Close the connection to `endpoint`, once the datagrams sent to it are written out, if the transport has one.
+    fn disconnect(&mut self, _endpoint: &Endpoint) {}
+}
+
+/// Which transport a client reaches its server with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportMode {
+    Udp,
+    Tcp,
+    /// UDP, then TCP if the server does not answer over UDP.
+    Auto,
+}
+
+impl FromStr for TransportMode {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "udp" => Ok(TransportMode::Udp),
+            "tcp" => Ok(TransportMode::Tcp),
+            "auto" => Ok(TransportMode::Auto),
+            _ => Err(ErrorKind::UnknownTransport(name.to_string()).into()),
+        }
+    }
+}
+
+/// The transports of a server, which accepts clients over UDP and, if enabled, TCP at once.
+#[derive(Debug)]
+pub struct ServerTransport {
+    udp: UdpTransport,
+    tcp: Option<TcpServerTransport>,
+    // Which transport is received from first, they take turns so that neither of them starves the other.
+    tcp_first: bool,
+}
+
+impl ServerTransport {
+    pub fn new(udp: UdpTransport, tcp: Option<TcpServerTransport>) -> Self {
+        ServerTransport {
+            udp,
+            tcp,
+            tcp_first: false,
+        }
+    }
+}
+
+impl Transport for ServerTransport {
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, Endpoint)> {
+        let tcp = match self.tcp {
+            Some(ref mut tcp) => tcp,
+            None => return self.udp.recv_from(buf),
+        };
+        self.tcp_first = !self.tcp_first;
+        let (first, second): (&mut Transport, &mut Transport) = if self.tcp_first {
+            (tcp, &mut self.udp)
+        } else {
+            (&mut self.udp, tcp)
+        };
+        match first.recv_from(buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => second.recv_from(buf),
+            received => received,
+        }
+    }
+
+    fn send_to(&mut self, buf: &[u8], endpoint: &Endpoint) -> io::Result<()> {
+        match (*endpoint, self.tcp.as_mut()) {
+            (Endpoint::Udp(_), _) => self.udp.send_to(buf, endpoint),
+            (Endpoint::Tcp(_), Some(tcp)) => tcp.send_to(buf, endpoint),
+            // Only clients which have connected over TCP have a TCP endpoint, so there is nowhere to send this.
+            (Endpoint::Tcp(_), None) => {
+                debug!("Dropping datagram to {}, TCP is disabled", endpoint);
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.tcp {
+            Some(ref mut tcp) => tcp.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn disconnect(&mut self, endpoint: &Endpoint) {
+        if let (&Endpoint::Tcp(_), Some(tcp)) = (endpoint, self.tcp.as_mut()) {
+            tcp.disconnect(endpoint);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transport_mode() {
+        assert_eq!("udp".parse::<TransportMode>().unwrap(), TransportMode::Udp);
+        assert_eq!("tcp".parse::<TransportMode>().unwrap(), TransportMode::Tcp);
+        assert_eq!("auto".parse::<TransportMode>().unwrap(), TransportMode::Auto);
+        assert!("quic".parse::<TransportMode>().is_err());
+
+        let address = "127.0.0.1:8964".parse().unwrap();
+        assert_eq!(Endpoint::Tcp(address).to_string(), "tcp://127.0.0.1:8964");
+        assert!(Endpoint::Udp(address) != Endpoint::Tcp(address));
+        assert_eq!(Endpoint::Udp(address).overhead(), 28);
+        assert_eq!(Endpoint::Tcp("[::1]:8964".parse().unwrap()).overhead(), 62);
+    }
+}
@@ -0,0 +1,153 @@
+//! What the host knows of the largest datagrams the network carries.
+//!
+//! Only Linux tells the MTU of a path and lets a socket forbid fragmentation, elsewhere asking for an MTU fails and
+//! the MTU of Ethernet is assumed.
+
+pub use self::os::{interface_mtu, is_too_large, path_mtu, set_dont_fragment};
+
+/// MTU of Ethernet, assumed when the MTU of a path is unknown.
+pub const DEFAULT_PATH_MTU: usize = 1500;
+
+#[cfg(target_os = "linux")]
+mod os {
+    use std::{fs, io, mem, ptr};
+    use std::ffi::CStr;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+    use std::os::unix::io::{AsRawFd, RawFd};
+
+    use libc::{self, c_int, c_void, socklen_t};
+
+    /// The MTU of the path to `destination` as the kernel knows it: the MTU of the interface it is routed through,
+    /// or less once the network has told so.
+    pub fn path_mtu(destination: &SocketAddr) -> io::Result<usize> {
+        let (local, level, name) = match *destination {
+            SocketAddr::V4(_) => ("0.0.0.0:0", libc::IPPROTO_IP, libc::IP_MTU),
+            SocketAddr::V6(_) => ("[::]:0", libc::IPPROTO_IPV6, libc::IPV6_MTU),
+        };
+        // Connecting a UDP socket routes it without sending anything.
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(destination)?;
+        let mut mtu: c_int = 0;
+        let mut len = mem::size_of::<c_int>() as socklen_t;
+        let value = &mut mtu as *mut _ as *mut c_void;
+        if unsafe { libc::getsockopt(socket.as_raw_fd(), level, name, value, &mut len) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(mtu as usize)
+    }
+
+    /// The MTU of the interface holding `address`, or the smallest MTU of the interfaces which are up if it is
+    /// unspecified.
+    pub fn interface_mtu(address: &IpAddr) -> io::Result<usize> {
+        let mut names = Vec::new();
+        unsafe {
+            let mut interfaces = ptr::null_mut();
+            if libc::getifaddrs(&mut interfaces) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut next = interfaces;
+            while let Some(interface) = next.as_ref() {
+                next = interface.ifa_next;
+                let flags = interface.ifa_flags as c_int;
+                let matches = match socket_address(interface.ifa_addr) {
+                    Some(found) if address.is_unspecified() => {
+                        found.is_ipv4() == address.is_ipv4() && flags & libc::IFF_UP != 0 &&
+                        flags & libc::IFF_LOOPBACK == 0
+                    }
+                    Some(found) => found == *address,
+                    None => false,
+                };
+                if matches {
+                    names.push(CStr::from_ptr(interface.ifa_name).to_string_lossy().into_owned());
+                }
+            }
+            libc::freeifaddrs(interfaces);
+        }
+
+        let mut mtu = None;
+        for name in names {
+            let value = fs::read_to_string(format!("/sys/class/net/{}/mtu", name))?;
+            let value = value.trim().parse::<usize>().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            mtu = Some(mtu.map_or(value, |mtu: usize| mtu.min(value)));
+        }
+        mtu.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no interface with address {}", address)))
+    }
+
+    unsafe fn socket_address(address: *const libc::sockaddr) -> Option<IpAddr> {
+        match address.as_ref().map(|address| address.sa_family as c_int) {
+            Some(libc::AF_INET) => {
+                let address = &*(address as *const libc::sockaddr_in);
+                Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr))))
+            }
+            Some(libc::AF_INET6) => {
+                let address = &*(address as *const libc::sockaddr_in6);
+                Some(IpAddr::V6(Ipv6Addr::from(address.sin6_addr.s6_addr)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Forbid the fragmentation of the datagrams sent by the UDP socket `fd`: those larger than the MTU of their
+    /// path are dropped on the way, or fail with `EMSGSIZE` once the kernel knows the MTU.
+    pub fn set_dont_fragment(fd: RawFd, ipv6: bool) -> io::Result<()> {
+        let (level, name, value) = if ipv6 {
+            (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_DO)
+        } else {
+            (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_DO)
+        };
+        let len = mem::size_of::<c_int>() as socklen_t;
+        if unsafe { libc::setsockopt(fd, level, name, &value as *const _ as *const c_void, len) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Whether sending has failed because the datagram is larger than the MTU of its path.
+    pub fn is_too_large(e: &io::Error) -> bool {
+        e.raw_os_error() == Some(libc::EMSGSIZE)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod os {
+    use std::io;
+    use std::net::{IpAddr, SocketAddr};
+    use std::os::unix::io::RawFd;
+
+    pub fn path_mtu(_destination: &SocketAddr) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "the MTU of a path is unknown on this system"))
+    }
+
+    pub fn interface_mtu(_address: &IpAddr) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "the MTU of an interface is unknown on this system"))
+    }
+
+    pub fn set_dont_fragment(_fd: RawFd, _ipv6: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn is_too_large(_e: &io::Error) -> bool {
+        false
+    }
+}
+
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    use super::*;
+
+    #[test]
+    fn test_loopback_mtu() {
+        let loopback = interface_mtu(&"127.0.0.1".parse().unwrap()).unwrap();
+        // No IPv4 packet is longer than 65535 bytes, however large the MTU of its interface.
+        assert_eq!(path_mtu(&"127.0.0.1:8964".parse().unwrap()).unwrap(), loopback.min(65535));
+        assert!(interface_mtu(&"192.0.2.1".parse().unwrap()).is_err());
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        set_dont_fragment(socket.as_raw_fd(), false).unwrap();
+        assert!(is_too_large(&socket.send_to(&vec![0; loopback], "127.0.0.1:9").unwrap_err()));
+    }
+}
@@ -1,14 +1,44 @@
 use std::io::Cursor;
 use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-use byteorder::{BigEndian, ReadBytesExt};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 
 use common::error::*;
 
+// Lengths of the fixed parts of the headers, an IPv4 or TCP header may be followed by options.
 lazy_static!{
    pub static ref IPV4_HEADER_LEN: usize = mem::size_of::<IPv4Header>();
+   pub static ref IPV6_HEADER_LEN: usize = mem::size_of::<IPv6Header>();
+   pub static ref TCP_HEADER_LEN: usize = mem::size_of::<TcpHeader>();
 }
 
+const PROTOCOL_ICMP: u8 = 1;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_ICMPV6: u8 = 58;
+const IPV4_DONT_FRAGMENT: u16 = 0x4000;
+const IPV4_MORE_FRAGMENTS: u16 = 0x2000;
+const IPV4_FRAGMENT_OFFSET: u16 = 0x1fff;
+/// Hop limit of the ICMP errors built here.
+const ICMP_TTL: u8 = 64;
+const ICMP_HEADER_LEN: usize = 8;
+const ICMP_DESTINATION_UNREACHABLE: u8 = 3;
+const ICMP_FRAGMENTATION_NEEDED: u8 = 4;
+/// ICMP types which report an error, the errors of which are never reported.
+const ICMP_ERRORS: &[u8] = &[3, 4, 5, 11, 12];
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+/// ICMPv6 types below this one report an error.
+const ICMPV6_INFORMATIONAL: u8 = 128;
+const TCP_SYN: u16 = 0x0002;
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+const TCP_OPTION_MSS_LEN: usize = 4;
+/// Longest ICMP errors, as much of the packet in error is quoted as fits in them: RFC 1812 for IPv4, the minimum MTU
+/// of IPv6 for IPv6.
+const ICMP_MAX_LEN: usize = 576;
+const ICMPV6_MAX_LEN: usize = 1280;
+
 #[repr(C, packed)]
 #[derive(Default, Clone, Copy)]
 pub struct IPv4Header {
@@ -25,23 +55,464 @@ pub struct IPv4Header {
 }
 
 impl From<[u8; 20]> for IPv4Header {
+    fn from(bytes: [u8; 20]) -> Self {
+        IPv4Header {
+            version_ihl: bytes[0],
+            type_of_service: bytes[1],
+            total_length: BigEndian::read_u16(&bytes[2..4]),
+            identification: BigEndian::read_u16(&bytes[4..6]),
+            flags_fragment_offset: BigEndian::read_u16(&bytes[6..8]),
+            time_to_live: bytes[8],
+            protocol: bytes[9],
+            header_checksum: BigEndian::read_u16(&bytes[10..12]),
+            source_address: BigEndian::read_u32(&bytes[12..16]),
+            destination_address: BigEndian::read_u32(&bytes[16..20]),
+        }
+    }
+}
+
+/// An IPv4 packet read where it is in `buffer`, a `&[u8]` or a `&mut [u8]` to change it in place.
+///
+/// `Ipv4Packet::new` checks that the buffer holds the whole header, options included, and the `total_length` the
+/// header claims, so that no accessor panics whatever the packet holds. The checksum is only checked by
+/// `verify_checksum`.
+#[derive(Clone, Copy, Debug)]
+pub struct Ipv4Packet<T> {
+    buffer: T,
+}
+
+fn invalid_ipv4(reason: String) -> Error {
+    ErrorKind::InvalidIpv4Packet(reason).into()
+}
+
+impl<T: AsRef<[u8]>> Ipv4Packet<T> {
+    pub fn new(buffer: T) -> Result<Self> {
+        {
+            let bytes = buffer.as_ref();
+            if bytes.len() < *IPV4_HEADER_LEN {
+                return Err(invalid_ipv4(format!("{} bytes, shorter than a header", bytes.len())));
+            }
+            if bytes[0] >> 4 != 4 {
+                return Err(invalid_ipv4(format!("version {}", bytes[0] >> 4)));
+            }
+            let header_len = (bytes[0] & 0x0f) as usize * 4;
+            if header_len < *IPV4_HEADER_LEN || header_len > bytes.len() {
+                return Err(invalid_ipv4(format!("header of {} bytes in {} bytes", header_len, bytes.len())));
+            }
+            let total_len = BigEndian::read_u16(&bytes[2..4]) as usize;
+            if total_len < header_len || total_len > bytes.len() {
+                return Err(invalid_ipv4(format!("total length of {} bytes in {} bytes", total_len, bytes.len())));
+            }
+        }
+        Ok(Ipv4Packet { buffer })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    /// The packet, without what follows its total length in the buffer.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buffer.as_ref()[..self.total_len()]
+    }
+
+    /// Length of the header with its options, in bytes.
+    pub fn header_len(&self) -> usize {
+        (self.buffer.as_ref()[0] & 0x0f) as usize * 4
+    }
+
+    pub fn type_of_service(&self) -> u8 {
+        self.buffer.as_ref()[1]
+    }
+
+    pub fn total_len(&self) -> usize {
+        BigEndian::read_u16(&self.buffer.as_ref()[2..4]) as usize
+    }
+
+    pub fn identification(&self) -> u16 {
+        BigEndian::read_u16(&self.buffer.as_ref()[4..6])
+    }
+
+    fn flags_fragment_offset(&self) -> u16 {
+        BigEndian::read_u16(&self.buffer.as_ref()[6..8])
+    }
+
+    pub fn dont_fragment(&self) -> bool {
+        self.flags_fragment_offset() & IPV4_DONT_FRAGMENT != 0
+    }
+
+    pub fn more_fragments(&self) -> bool {
+        self.flags_fragment_offset() & IPV4_MORE_FRAGMENTS != 0
+    }
+
+    /// Where the payload of the fragment starts in the payload of the original packet, in bytes.
+    pub fn fragment_offset(&self) -> usize {
+        (self.flags_fragment_offset() & IPV4_FRAGMENT_OFFSET) as usize * 8
+    }
+
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.fragment_offset() != 0
+    }
+
+    pub fn time_to_live(&self) -> u8 {
+        self.buffer.as_ref()[8]
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.buffer.as_ref()[9]
+    }
+
+    pub fn checksum(&self) -> u16 {
+        BigEndian::read_u16(&self.buffer.as_ref()[10..12])
+    }
+
+    pub fn source_address(&self) -> Ipv4Addr {
+        Ipv4Addr::from(BigEndian::read_u32(&self.buffer.as_ref()[12..16]))
+    }
+
+    pub fn destination_address(&self) -> Ipv4Addr {
+        Ipv4Addr::from(BigEndian::read_u32(&self.buffer.as_ref()[16..20]))
+    }
+
+    pub fn header(&self) -> &[u8] {
+        &self.buffer.as_ref()[..self.header_len()]
+    }
+
+    pub fn options(&self) -> &[u8] {
+        &self.buffer.as_ref()[*IPV4_HEADER_LEN..self.header_len()]
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[self.header_len()..self.total_len()]
+    }
+
+    pub fn verify_checksum(&self) -> bool {
+        checksum(self.header()) == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Ipv4Packet<T> {
+    pub fn set_time_to_live(&mut self, value: u8) {
+        self.buffer.as_mut()[8] = value;
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let (header_len, total_len) = (self.header_len(), self.total_len());
+        &mut self.buffer.as_mut()[header_len..total_len]
+    }
+
+    /// Compute the checksum of the header again, once it has been changed.
+    pub fn fill_checksum(&mut self) {
+        self.buffer.as_mut()[10..12].copy_from_slice(&[0, 0]);
+        let checksum = checksum(self.header());
+        BigEndian::write_u16(&mut self.buffer.as_mut()[10..12], checksum);
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy)]
+pub struct IPv6Header {
+    pub version_class_flow: u32, // IP version (= 6) + Traffic class + Flow label
+    pub payload_length: u16, // Payload length in octets
+    pub next_header: u8, // Next header
+    pub hop_limit: u8, // Hop limit
+    pub source_address: [u8; 16], // Source Address
+    pub destination_address: [u8; 16], // Destination Address
+}
+
+impl From<[u8; 40]> for IPv6Header {
+    fn from(bytes: [u8; 40]) -> Self {
+        let mut header = IPv6Header::default();
+        {
+            let mut cursor = Cursor::new(&bytes[..8]);
+            header.version_class_flow = cursor.read_u32::<BigEndian>().unwrap();
+            header.payload_length = cursor.read_u16::<BigEndian>().unwrap();
+            header.next_header = cursor.read_u8().unwrap();
+            header.hop_limit = cursor.read_u8().unwrap();
+        }
+        header.source_address.copy_from_slice(&bytes[8..24]);
+        header.destination_address.copy_from_slice(&bytes[24..40]);
+        header
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy)]
+pub struct TcpHeader {
+    pub source_port: u16, // Source port
+    pub destination_port: u16, // Destination port
+    pub sequence_number: u32, // Sequence number
+    pub acknowledgment_number: u32, // Acknowledgment number
+    pub offset_flags: u16, // 4-bits Data offset + Reserved + Flags
+    pub window: u16, // Window
+    pub checksum: u16, // Checksum
+    pub urgent_pointer: u16, // Urgent pointer
+}
+
+impl From<[u8; 20]> for TcpHeader {
     fn from(bytes: [u8; 20]) -> Self {
         let mut bytes = Cursor::new(bytes);
-        let mut header = IPv4Header::default();
-        header.version_ihl = bytes.read_u8().unwrap();
-        header.type_of_service = bytes.read_u8().unwrap();
-        header.total_length = bytes.read_u16::<BigEndian>().unwrap();
-        header.identification = bytes.read_u16::<BigEndian>().unwrap();
-        header.flags_fragment_offset = bytes.read_u16::<BigEndian>().unwrap();
-        header.time_to_live = bytes.read_u8().unwrap();
-        header.protocol = bytes.read_u8().unwrap();
-        header.header_checksum = bytes.read_u16::<BigEndian>().unwrap();
-        header.source_address = bytes.read_u32::<BigEndian>().unwrap();
-        header.destination_address = bytes.read_u32::<BigEndian>().unwrap();
+        let mut header = TcpHeader::default();
+        header.source_port = bytes.read_u16::<BigEndian>().unwrap();
+        header.destination_port = bytes.read_u16::<BigEndian>().unwrap();
+        header.sequence_number = bytes.read_u32::<BigEndian>().unwrap();
+        header.acknowledgment_number = bytes.read_u32::<BigEndian>().unwrap();
+        header.offset_flags = bytes.read_u16::<BigEndian>().unwrap();
+        header.window = bytes.read_u16::<BigEndian>().unwrap();
+        header.checksum = bytes.read_u16::<BigEndian>().unwrap();
+        header.urgent_pointer = bytes.read_u16::<BigEndian>().unwrap();
         header
     }
 }
 
+impl TcpHeader {
+    /// Length of the header with its options, in bytes.
+    pub fn header_len(&self) -> usize {
+        (self.offset_flags >> 12) as usize * 4
+    }
+
+    pub fn is_syn(&self) -> bool {
+        self.offset_flags & TCP_SYN != 0
+    }
+}
+
+/// The header of an IP packet, dispatched on the version in its first nibble.
+#[derive(Clone, Copy)]
+pub enum IPHeader {
+    V4(IPv4Header),
+    V6(IPv6Header),
+}
+
+impl IPHeader {
+    pub fn parse(packet: &[u8]) -> Result<Self> {
+        match packet.first().map(|b| b >> 4) {
+            Some(4) => {
+                Ipv4Packet::new(packet)?;
+                let mut header_bytes = [0u8; 20];
+                header_bytes.copy_from_slice(&packet[..*IPV4_HEADER_LEN]);
+                Ok(IPHeader::V4(IPv4Header::from(header_bytes)))
+            }
+            Some(6) if packet.len() >= *IPV6_HEADER_LEN => {
+                let mut header_bytes = [0u8; 40];
+                header_bytes.copy_from_slice(&packet[..*IPV6_HEADER_LEN]);
+                Ok(IPHeader::V6(IPv6Header::from(header_bytes)))
+            }
+            _ => Err(ErrorKind::InvalidByteSource.into()),
+        }
+    }
+
+    pub fn source_address(&self) -> IpAddr {
+        match *self {
+            IPHeader::V4(ref header) => IpAddr::V4(Ipv4Addr::from(header.source_address)),
+            IPHeader::V6(ref header) => IpAddr::V6(Ipv6Addr::from(header.source_address)),
+        }
+    }
+
+    pub fn destination_address(&self) -> IpAddr {
+        match *self {
+            IPHeader::V4(ref header) => IpAddr::V4(Ipv4Addr::from(header.destination_address)),
+            IPHeader::V6(ref header) => IpAddr::V6(Ipv6Addr::from(header.destination_address)),
+        }
+    }
+}
+
+/// The Internet checksum of RFC 1071: the one's complement of the one's complement sum of the 16 bits words of
+/// `data`. A header holding its own checksum sums to 0.
+pub fn checksum(data: &[u8]) -> u16 {
+    fold(sum(data))
+}
+
+/// The checksum of an upper layer `data` of IPv6, which covers the pseudo header of RFC 8200 as well.
+pub fn checksum_v6(source: &[u8; 16], destination: &[u8; 16], next_header: u8, data: &[u8]) -> u16 {
+    let len = data.len() as u32;
+    fold(sum(source) + sum(destination) + (len >> 16) + (len & 0xffff) + next_header as u32 + sum(data))
+}
+
+/// The checksum `checksum` once a 16 bits word of what it covers has changed from `old` to `new`, as RFC 1624 updates
+/// it without summing everything again.
+pub fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    fold(!checksum as u32 + !old as u32 + new as u32)
+}
+
+fn sum(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|word| (word[0] as u32) << 8 | word.get(1).cloned().unwrap_or(0) as u32)
+        .sum()
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !sum as u16
+}
+
+/// The ICMP error telling the sender of `packet` that it does not fit in a link of `mtu`: a "fragmentation needed"
+/// for IPv4, a "packet too big" for IPv6.
+///
+/// The error comes from the destination of the packet, which the tun routes to, so that reverse path filtering lets
+/// it in when it is written to the tun. Returns `None` if no error may be sent: for IPv4 packets which may be
+/// fragmented, fragments but the first and errors, and packets of no single sender.
+pub fn packet_too_big(packet: &[u8], mtu: usize) -> Option<Vec<u8>> {
+    if let Ok(packet) = Ipv4Packet::new(packet) {
+        return fragmentation_needed_v4(&packet, mtu);
+    }
+    match IPHeader::parse(packet) {
+        Ok(IPHeader::V6(header)) => packet_too_big_v6(&header, packet, mtu),
+        _ => None,
+    }
+}
+
+fn fragmentation_needed_v4(packet: &Ipv4Packet<&[u8]>, mtu: usize) -> Option<Vec<u8>> {
+    if !packet.dont_fragment() || packet.fragment_offset() != 0 {
+        return None;
+    }
+    if packet.protocol() == PROTOCOL_ICMP {
+        match packet.payload().first() {
+            Some(icmp_type) if !ICMP_ERRORS.contains(icmp_type) => {}
+            _ => return None,
+        }
+    }
+    let source = packet.source_address();
+    if source.is_unspecified() || source.is_broadcast() || source.is_multicast() || source.is_loopback() {
+        return None;
+    }
+
+    let packet_bytes = packet.as_slice();
+    let quoted = &packet_bytes[..packet_bytes.len().min(ICMP_MAX_LEN - *IPV4_HEADER_LEN - ICMP_HEADER_LEN)];
+    let len = *IPV4_HEADER_LEN + ICMP_HEADER_LEN + quoted.len();
+    let mut reply = Vec::with_capacity(len);
+    reply.write_u8(0x45).unwrap();
+    reply.write_u8(0).unwrap();
+    reply.write_u16::<BigEndian>(len as u16).unwrap();
+    reply.write_u16::<BigEndian>(0).unwrap();
+    reply.write_u16::<BigEndian>(0).unwrap();
+    reply.write_u8(ICMP_TTL).unwrap();
+    reply.write_u8(PROTOCOL_ICMP).unwrap();
+    reply.write_u16::<BigEndian>(0).unwrap();
+    reply.extend_from_slice(&packet.destination_address().octets());
+    reply.extend_from_slice(&source.octets());
+
+    reply.write_u8(ICMP_DESTINATION_UNREACHABLE).unwrap();
+    reply.write_u8(ICMP_FRAGMENTATION_NEEDED).unwrap();
+    reply.write_u16::<BigEndian>(0).unwrap();
+    reply.write_u16::<BigEndian>(0).unwrap();
+    reply.write_u16::<BigEndian>(mtu.min(u16::max_value() as usize) as u16).unwrap();
+    reply.extend_from_slice(quoted);
+
+    let header_checksum = checksum(&reply[..*IPV4_HEADER_LEN]);
+    BigEndian::write_u16(&mut reply[10..12], header_checksum);
+    let icmp_checksum = checksum(&reply[*IPV4_HEADER_LEN..]);
+    BigEndian::write_u16(&mut reply[*IPV4_HEADER_LEN + 2..*IPV4_HEADER_LEN + 4], icmp_checksum);
+    Some(reply)
+}
+
+fn packet_too_big_v6(header: &IPv6Header, packet: &[u8], mtu: usize) -> Option<Vec<u8>> {
+    if header.next_header == PROTOCOL_ICMPV6 {
+        match packet.get(*IPV6_HEADER_LEN) {
+            Some(&icmp_type) if icmp_type >= ICMPV6_INFORMATIONAL => {}
+            _ => return None,
+        }
+    }
+    let source = Ipv6Addr::from(header.source_address);
+    if source.is_unspecified() || source.is_multicast() || source.is_loopback() {
+        return None;
+    }
+
+    let quoted = &packet[..packet.len().min(ICMPV6_MAX_LEN - *IPV6_HEADER_LEN - ICMP_HEADER_LEN)];
+    let payload_len = ICMP_HEADER_LEN + quoted.len();
+    let mut reply = Vec::with_capacity(*IPV6_HEADER_LEN + payload_len);
+    reply.write_u32::<BigEndian>(6 << 28).unwrap();
+    reply.write_u16::<BigEndian>(payload_len as u16).unwrap();
+    reply.write_u8(PROTOCOL_ICMPV6).unwrap();
+    reply.write_u8(ICMP_TTL).unwrap();
+    reply.extend_from_slice(&header.destination_address);
+    reply.extend_from_slice(&header.source_address);
+
+    reply.write_u8(ICMPV6_PACKET_TOO_BIG).unwrap();
+    reply.write_u8(0).unwrap();
+    reply.write_u16::<BigEndian>(0).unwrap();
+    reply.write_u32::<BigEndian>(mtu as u32).unwrap();
+    reply.extend_from_slice(quoted);
+
+    let icmp_checksum = checksum_v6(&header.destination_address, &header.source_address, PROTOCOL_ICMPV6,
+                                    &reply[*IPV6_HEADER_LEN..]);
+    BigEndian::write_u16(&mut reply[*IPV6_HEADER_LEN + 2..*IPV6_HEADER_LEN + 4], icmp_checksum);
+    Some(reply)
+}
+
+/// Lower the MSS option of a TCP SYN, or SYN-ACK, `packet` so that the segments of its connection fit in a link of
+/// `mtu`, updating the checksum. Returns whether the packet has been changed.
+pub fn clamp_mss(packet: &mut [u8], mtu: usize) -> bool {
+    let (offset, ip_header_len) = match Ipv4Packet::new(&packet[..]) {
+        Ok(ipv4) => {
+            if ipv4.protocol() != PROTOCOL_TCP || ipv4.fragment_offset() != 0 {
+                return false;
+            }
+            (ipv4.header_len(), *IPV4_HEADER_LEN)
+        }
+        Err(_) => {
+            match IPHeader::parse(packet) {
+                Ok(IPHeader::V6(header)) if header.next_header == PROTOCOL_TCP => (*IPV6_HEADER_LEN, *IPV6_HEADER_LEN),
+                _ => return false,
+            }
+        }
+    };
+    if packet.len() < offset + *TCP_HEADER_LEN {
+        return false;
+    }
+    let mut header_bytes = [0u8; 20];
+    header_bytes.copy_from_slice(&packet[offset..offset + *TCP_HEADER_LEN]);
+    let header = TcpHeader::from(header_bytes);
+    let header_len = header.header_len();
+    if !header.is_syn() || header_len < *TCP_HEADER_LEN || packet.len() < offset + header_len {
+        return false;
+    }
+    let option = match find_mss(&packet[offset + *TCP_HEADER_LEN..offset + header_len]) {
+        Some(option) => offset + *TCP_HEADER_LEN + option,
+        None => return false,
+    };
+
+    // The MSS leaves out the headers of IP and TCP, but not their options.
+    let max_mss = mtu.saturating_sub(ip_header_len + *TCP_HEADER_LEN);
+    let mss = BigEndian::read_u16(&packet[option..option + 2]);
+    if mss as usize <= max_mss {
+        return false;
+    }
+    let max_mss = max_mss as u16;
+    BigEndian::write_u16(&mut packet[option..option + 2], max_mss);
+    // An option at an odd offset straddles two of the words the checksum sums, with its bytes swapped.
+    let checksum = if (option - offset) % 2 == 0 {
+        update_checksum(header.checksum, mss, max_mss)
+    } else {
+        update_checksum(header.checksum, mss.swap_bytes(), max_mss.swap_bytes())
+    };
+    BigEndian::write_u16(&mut packet[offset + 16..offset + 18], checksum);
+    true
+}
+
+/// Where the value of the MSS option is in the TCP `options`, if they have one.
+fn find_mss(options: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            TCP_OPTION_END => return None,
+            TCP_OPTION_NOP => i += 1,
+            kind => {
+                let len = match options.get(i + 1) {
+                    Some(&len) if len >= 2 && i + len as usize <= options.len() => len as usize,
+                    _ => return None,
+                };
+                if kind == TCP_OPTION_MSS && len == TCP_OPTION_MSS_LEN {
+                    return Some(i + 2);
+                }
+                i += len;
+            }
+        }
+    }
+    None
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -79,4 +550,170 @@ mod test {
         assert_eq!(h.protocol, 0x01);
         assert_eq!(Ipv4Addr::from(h.source_address), Ipv4Addr::from_str("10.0.0.2").unwrap());
     }
+
+    #[test]
+    fn test_ipv4_packet() {
+        // An echo request from 10.0.0.2 to 192.168.1.2, with the option list ending right away and 2 bytes after it.
+        let mut data = vec![70, 0, 0, 28, 0x12, 0x34, 0x20, 0x03, 64, 1, 0, 0, 10, 0, 0, 2, 192, 168, 1, 2, 0, 0, 0, 0];
+        data.extend_from_slice(&[8, 0, 0xf7, 0xff, 0, 0, 0, 0, 0xaa, 0xbb]);
+        {
+            let mut packet = Ipv4Packet::new(&mut data[..]).unwrap();
+            assert!(!packet.verify_checksum());
+            packet.fill_checksum();
+            assert!(packet.verify_checksum());
+            packet.set_time_to_live(63);
+            assert!(!packet.verify_checksum());
+            packet.fill_checksum();
+        }
+
+        let packet = Ipv4Packet::new(&data[..]).unwrap();
+        assert!(packet.verify_checksum());
+        assert_eq!(packet.header_len(), 24);
+        assert_eq!(packet.total_len(), 28);
+        assert_eq!(packet.identification(), 0x1234);
+        assert!(!packet.dont_fragment());
+        assert!(packet.more_fragments());
+        assert_eq!(packet.fragment_offset(), 24);
+        assert!(packet.is_fragment());
+        assert_eq!(packet.time_to_live(), 63);
+        assert_eq!(packet.protocol(), 1);
+        assert_eq!(packet.source_address(), Ipv4Addr::from_str("10.0.0.2").unwrap());
+        assert_eq!(packet.destination_address(), Ipv4Addr::from_str("192.168.1.2").unwrap());
+        assert_eq!(packet.options(), &[0, 0, 0, 0]);
+        assert_eq!(packet.payload(), &[8, 0, 0xf7, 0xff]);
+        assert_eq!(packet.as_slice().len(), 28);
+        assert_eq!(Ipv4Packet::new(&data[..27]).unwrap_err().to_string(),
+                   "invalid IPv4 packet: total length of 28 bytes in 27 bytes");
+
+        // Broken headers fail instead of reading past the buffer.
+        let mut broken = data.clone();
+        broken[0] = 0x44;
+        assert!(Ipv4Packet::new(&broken[..]).is_err());
+        broken[0] = 0x4f;
+        assert!(Ipv4Packet::new(&broken[..]).is_err());
+        broken[0] = 0x65;
+        assert!(Ipv4Packet::new(&broken[..]).is_err());
+        broken[0] = 0x46;
+        broken[3] = 20;
+        assert!(Ipv4Packet::new(&broken[..]).is_err());
+        assert!(Ipv4Packet::new(&data[..19]).is_err());
+        assert!(IPHeader::parse(&broken).is_err());
+    }
+
+    #[test]
+    fn test_ipv6_header() {
+        assert_eq!(mem::size_of::<IPv6Header>(), 40);
+
+        let mut data = [0u8; 40];
+        data[..8].copy_from_slice(&[0x60, 0, 0, 0, 0, 64, 58, 255]);
+        data[8..24].copy_from_slice(&Ipv6Addr::from_str("fd00::2").unwrap().octets());
+        data[24..40].copy_from_slice(&Ipv6Addr::from_str("fd00::1").unwrap().octets());
+
+        let h = IPv6Header::from(data);
+        assert_eq!({ h.payload_length }, 64);
+        assert_eq!(h.next_header, 58);
+        assert_eq!(Ipv6Addr::from(h.source_address), Ipv6Addr::from_str("fd00::2").unwrap());
+
+        match IPHeader::parse(&data) {
+            Ok(header @ IPHeader::V6(_)) => {
+                assert_eq!(header.destination_address(), IpAddr::from_str("fd00::1").unwrap())
+            }
+            _ => panic!("expected an IPv6 header"),
+        }
+        assert!(IPHeader::parse(&data[..39]).is_err());
+        assert!(IPHeader::parse(&[0x50; 40]).is_err());
+    }
+
+    #[test]
+    fn test_checksum() {
+        // The example of RFC 1071.
+        assert_eq!(checksum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]), !0xddf2);
+        assert_eq!(checksum(&[0x00, 0x01, 0xf2]), !0xf201);
+        let header = [69, 0, 0, 84, 223, 18, 64, 0, 64, 1, 143, 234, 10, 0, 0, 2, 192, 168, 1, 2];
+        assert_eq!(checksum(&header), 0);
+    }
+
+    #[test]
+    fn test_packet_too_big() {
+        // An echo request of 1500 bytes from 10.0.0.2 to 192.168.1.2, which may not be fragmented.
+        let mut packet = vec![0u8; 1500];
+        packet[..20].copy_from_slice(&[69, 0, 5, 220, 223, 18, 64, 0, 64, 1, 0, 0, 10, 0, 0, 2, 192, 168, 1, 2]);
+        packet[20] = 8;
+        let reply = packet_too_big(&packet, 1400).unwrap();
+        assert_eq!(reply.len(), 576);
+        assert_eq!(checksum(&reply[..20]), 0);
+        assert_eq!(checksum(&reply[20..]), 0);
+        assert_eq!(&reply[20..22], &[3, 4]);
+        assert_eq!(BigEndian::read_u16(&reply[26..28]), 1400);
+        assert_eq!(&reply[28..48], &packet[..20]);
+        match IPHeader::parse(&reply) {
+            Ok(header @ IPHeader::V4(_)) => {
+                assert_eq!(header.source_address(), IpAddr::from_str("192.168.1.2").unwrap());
+                assert_eq!(header.destination_address(), IpAddr::from_str("10.0.0.2").unwrap());
+            }
+            _ => panic!("expected an IPv4 header"),
+        }
+
+        // Errors are never sent about errors, nor about packets which may be fragmented.
+        assert!(packet_too_big(&reply, 1280).is_none());
+        packet[6] = 0;
+        assert!(packet_too_big(&packet, 1400).is_none());
+
+        let mut packet = vec![0u8; 1500];
+        packet[..8].copy_from_slice(&[0x60, 0, 0, 0, 0x05, 0xb4, 17, 64]);
+        packet[8..24].copy_from_slice(&Ipv6Addr::from_str("fd00::2").unwrap().octets());
+        packet[24..40].copy_from_slice(&Ipv6Addr::from_str("2001:db8::1").unwrap().octets());
+        let reply = packet_too_big(&packet, 1400).unwrap();
+        assert_eq!(reply.len(), 1280);
+        assert_eq!(BigEndian::read_u16(&reply[4..6]), 1240);
+        assert_eq!(&reply[8..24], &packet[24..40]);
+        assert_eq!(&reply[24..40], &packet[8..24]);
+        assert_eq!(&reply[40..42], &[2, 0]);
+        assert_eq!(BigEndian::read_u32(&reply[44..48]), 1400);
+        let (mut source, mut destination) = ([0u8; 16], [0u8; 16]);
+        source.copy_from_slice(&reply[8..24]);
+        destination.copy_from_slice(&reply[24..40]);
+        assert_eq!(checksum_v6(&source, &destination, 58, &reply[40..]), 0);
+        assert!(packet_too_big(&reply, 1280).is_none());
+    }
+
+    /// The checksum of the TCP segment in an IPv4 `packet`, 0 if it is right.
+    fn tcp_checksum(packet: &[u8]) -> u16 {
+        let mut pseudo = packet[12..20].to_vec();
+        pseudo.extend_from_slice(&[0, 6, 0, (packet.len() - 20) as u8]);
+        pseudo.extend_from_slice(&packet[20..]);
+        checksum(&pseudo)
+    }
+
+    #[test]
+    fn test_clamp_mss() {
+        // A SYN from 10.0.0.2:40000 to 192.168.1.2:80 offering an MSS of 1460, then a NOP and the window scale.
+        let mut packet = vec![69, 0, 0, 48, 0, 0, 64, 0, 64, 6, 0, 0, 10, 0, 0, 2, 192, 168, 1, 2];
+        packet.extend_from_slice(&[0x9c, 0x40, 0, 80, 0, 0, 0, 1, 0, 0, 0, 0, 0x70, 0x02, 0xff, 0xff, 0, 0, 0, 0]);
+        packet.extend_from_slice(&[2, 4, 0x05, 0xb4, 1, 3, 3, 7]);
+        let sum = tcp_checksum(&packet);
+        BigEndian::write_u16(&mut packet[36..38], sum);
+        assert_eq!(tcp_checksum(&packet), 0);
+
+        assert!(clamp_mss(&mut packet, 1400));
+        assert_eq!(BigEndian::read_u16(&packet[42..44]), 1360);
+        assert_eq!(tcp_checksum(&packet), 0);
+        assert!(!clamp_mss(&mut packet, 1500));
+
+        // An MSS at an odd offset, after a single NOP.
+        packet[40..48].copy_from_slice(&[1, 2, 4, 0x05, 0xb4, 1, 1, 0]);
+        BigEndian::write_u16(&mut packet[36..38], 0);
+        let sum = tcp_checksum(&packet);
+        BigEndian::write_u16(&mut packet[36..38], sum);
+        assert!(clamp_mss(&mut packet, 1300));
+        assert_eq!(BigEndian::read_u16(&packet[43..45]), 1260);
+        assert_eq!(tcp_checksum(&packet), 0);
+
+        // Only SYNs are clamped.
+        packet[33] = 0x10;
+        assert!(!clamp_mss(&mut packet, 1280));
+        assert_eq!(find_mss(&[1, 1, 0, 2, 4, 5, 180]), None);
+        assert_eq!(find_mss(&[3, 3, 7, 2, 4, 5, 180]), Some(5));
+        assert_eq!(find_mss(&[3, 9, 7, 2, 4, 5, 180]), None);
+    }
 }
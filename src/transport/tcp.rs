@@ -0,0 +1,615 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{self, SocketAddr};
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use futures::{Async, Future};
+use tokio_core::net::{TcpListener, TcpStream, TcpStreamNew};
+use tokio_core::reactor::Handle;
+
+use super::{Endpoint, Transport};
+
+pub const FRAME_HEADER_LEN: usize = 2;
+/// Connections a server accepts when none is configured.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+/// Seconds between two checks for idle connections.
+const IDLE_CHECK_INTERVAL: u64 = 1;
+/// Bytes read from a connection at once.
+const READ_LEN: usize = 16 * 1024;
+/// Bytes of frames a connection may have waiting to be written, datagrams sent to a connection which does not keep
+/// up are dropped, as a congested network would.
+const MAX_PENDING_LEN: usize = 64 * 1024;
+
+/// Append the frame of `datagram` to `output`.
+pub fn encode_frame(output: &mut Vec<u8>, datagram: &[u8]) -> io::Result<()> {
+    if datagram.len() > u16::max_value() as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "datagram too long for a frame"));
+    }
+    output.write_u16::<BigEndian>(datagram.len() as u16)?;
+    output.extend_from_slice(datagram);
+    Ok(())
+}
+
+/// Move the first complete frame of `input` into `buf`, returning the length of its datagram.
+pub fn decode_frame(input: &mut Vec<u8>, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    if input.len() < FRAME_HEADER_LEN {
+        return Ok(None);
+    }
+    let len = BigEndian::read_u16(&input[..FRAME_HEADER_LEN]) as usize;
+    if len > buf.len() {
+        let reason = format!("frame of {} bytes, expected at most {}", len, buf.len());
+        return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+    }
+    if input.len() < FRAME_HEADER_LEN + len {
+        return Ok(None);
+    }
+    buf[..len].copy_from_slice(&input[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len]);
+    input.drain(..FRAME_HEADER_LEN + len);
+    Ok(Some(len))
+}
+
+/// Whether `e` tells that the connection has closed, after which a client must connect again.
+pub fn is_closed(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::NotConnected |
+        io::ErrorKind::UnexpectedEof |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::BrokenPipe => true,
+        _ => false,
+    }
+}
+
+/// Queue the frame of `datagram` in `output`, returns `false` if the datagram is dropped because too many bytes are
+/// waiting already.
+fn queue_frame(output: &mut Vec<u8>, datagram: &[u8]) -> io::Result<bool> {
+    if output.len() + FRAME_HEADER_LEN + datagram.len() > MAX_PENDING_LEN {
+        return Ok(false);
+    }
+    encode_frame(output, datagram)?;
+    Ok(true)
+}
+
+/// A TCP connection carrying frames, with the bytes read from it but not yet framed and the frames not yet written.
+struct Connection {
+    stream: TcpStream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    // When the last frame has been received.
+    active: Instant,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+               stream,
+               input: Vec::new(),
+               output: Vec::new(),
+               active: Instant::now(),
+           })
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(n) = decode_frame(&mut self.input, buf)? {
+                self.active = Instant::now();
+                return Ok(n);
+            }
+
+            let len = self.input.len();
+            self.input.resize(len + READ_LEN, 0);
+            let n = match self.stream.read(&mut self.input[len..]) {
+                Ok(n) => n,
+                Err(e) => {
+                    self.input.truncate(len);
+                    return Err(e);
+                }
+            };
+            self.input.truncate(len + n);
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+            }
+        }
+    }
+
+    /// Queue the frame of `datagram` and write out as much as possible, returns `false` if the datagram is dropped.
+    fn send(&mut self, datagram: &[u8]) -> io::Result<bool> {
+        if !queue_frame(&mut self.output, datagram)? {
+            return Ok(false);
+        }
+        self.flush()?;
+        Ok(true)
+    }
+
+    /// Whether every frame has been written out.
+    fn is_flushed(&self) -> bool {
+        self.output.is_empty()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.output.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Connection")
+         .field("stream", &self.stream)
+         .field("input", &self.input.len())
+         .field("output", &self.output.len())
+         .finish()
+    }
+}
+
+/// The TCP listener of a server and the connections of its clients, a client is known by the address of its
+/// connection.
+///
+/// Connections beyond `max_connections` are closed as soon as they are accepted, and connections which have not
+/// received a frame for `idle_timeout` are closed.
+#[derive(Debug)]
+pub struct TcpServerTransport {
+    listener: TcpListener,
+    connections: HashMap<SocketAddr, Connection>,
+    // Connections to close once their frames are written out.
+    closing: HashSet<SocketAddr>,
+    // Addresses of the connections, in the order they are received from, so that every one of them gets its turn.
+    turns: VecDeque<SocketAddr>,
+    max_connections: usize,
+    idle_timeout: Duration,
+    idle_checked: Instant,
+}
+
+impl TcpServerTransport {
+    pub fn bind(address: &SocketAddr, max_connections: usize, idle_timeout: Duration, handle: &Handle)
+                -> io::Result<Self> {
+        let listener = net::TcpListener::bind(address)?;
+        Ok(TcpServerTransport {
+               listener: TcpListener::from_listener(listener, address, handle)?,
+               connections: HashMap::new(),
+               closing: HashSet::new(),
+               turns: VecDeque::new(),
+               max_connections,
+               idle_timeout,
+               idle_checked: Instant::now(),
+           })
+    }
+
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept().and_then(|(stream, address)| Ok((Connection::new(stream)?, address))) {
+                Ok((_, address)) if self.connections.len() >= self.max_connections => {
+                    warn!("Refusing TCP connection from {}, {} connections already", address, self.max_connections);
+                }
+                Ok((connection, address)) => {
+                    debug!("Accepted TCP connection from {}", address);
+                    if self.connections.insert(address, connection).is_none() {
+                        self.turns.push_back(address);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                // Running out of file descriptors must not bring the server down.
+                Err(e) => {
+                    warn!("Failed to accept TCP connection: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn close(&mut self, address: &SocketAddr, e: &io::Error) {
+        debug!("Closing TCP connection from {}: {}", address, e);
+        self.remove(address);
+    }
+
+    fn remove(&mut self, address: &SocketAddr) {
+        self.connections.remove(address);
+        self.closing.remove(address);
+        self.turns.retain(|turn| turn != address);
+    }
+
+    fn close_idle(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.idle_checked) < Duration::from_secs(IDLE_CHECK_INTERVAL) {
+            return;
+        }
+        self.idle_checked = now;
+
+        let idle_timeout = self.idle_timeout;
+        let idle: Vec<SocketAddr> = self.connections
+                                        .iter()
+                                        .filter(|&(_, connection)| now.duration_since(connection.active) > idle_timeout)
+                                        .map(|(address, _)| *address)
+                                        .collect();
+        for address in idle {
+            self.close(&address, &io::Error::new(io::ErrorKind::TimedOut, "idle"));
+        }
+    }
+}
+
+impl Transport for TcpServerTransport {
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, Endpoint)> {
+        self.close_idle();
+        self.accept();
+
+        let mut closed = Vec::new();
+        let mut received = None;
+        // Start from the connection after the one received from last.
+        for _ in 0..self.turns.len() {
+            let address = match self.turns.pop_front() {
+                Some(address) => address,
+                None => break,
+            };
+            self.turns.push_back(address);
+            if self.closing.contains(&address) {
+                continue;
+            }
+            let connection = match self.connections.get_mut(&address) {
+                Some(connection) => connection,
+                None => continue,
+            };
+            match connection.recv(buf) {
+                Ok(n) => {
+                    received = Some((n, Endpoint::Tcp(address)));
+                    break;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => closed.push((address, e)),
+            }
+        }
+        for (address, e) in closed {
+            self.close(&address, &e);
+        }
+        received.ok_or_else(|| io::ErrorKind::WouldBlock.into())
+    }
+
+    fn send_to(&mut self, buf: &[u8], endpoint: &Endpoint) -> io::Result<()> {
+        let address = endpoint.address();
+        let sent = match self.connections.get_mut(&address) {
+            Some(connection) => connection.send(buf),
+            None => {
+                debug!("Dropping datagram to {}, not connected", endpoint);
+                return Ok(());
+            }
+        };
+        match sent {
+            Ok(true) => {}
+            Ok(false) => debug!("Dropping datagram to {}, the connection is congested", endpoint),
+            Err(e) => self.close(&address, &e),
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let closed: Vec<(SocketAddr, io::Error)> = self.connections
+                                                       .iter_mut()
+                                                       .filter_map(|(address, connection)| {
+                                                                       connection.flush().err().map(|e| (*address, e))
+                                                                   })
+                                                       .collect();
+        for (address, e) in closed {
+            self.close(&address, &e);
+        }
+
+        let connections = &self.connections;
+        let is_flushed = |address: &&SocketAddr| connections.get(address).map_or(true, Connection::is_flushed);
+        let flushed: Vec<SocketAddr> = self.closing.iter().filter(is_flushed).cloned().collect();
+        for address in flushed {
+            debug!("Closing TCP connection from {}", address);
+            self.remove(&address);
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self, endpoint: &Endpoint) {
+        let address = endpoint.address();
+        if self.connections.contains_key(&address) {
+            self.closing.insert(address);
+        }
+    }
+}
+
+/// The TCP connection of a client to its server, a closed connection fails every call.
+pub struct TcpClientTransport {
+    server: SocketAddr,
+    state: ClientState,
+}
+
+enum ClientState {
+    /// Frames sent before the connection is established wait in the output.
+    Connecting(TcpStreamNew, Vec<u8>),
+    Connected(Connection),
+    Closed,
+}
+
+impl TcpClientTransport {
+    /// Start connecting to `server`, datagrams sent meanwhile are written once the server accepts the connection.
+    pub fn connect(server: &SocketAddr, handle: &Handle) -> Self {
+        TcpClientTransport {
+            server: *server,
+            state: ClientState::Connecting(TcpStream::connect(server, handle), Vec::new()),
+        }
+    }
+
+    /// Move on to `Connected` once the connection is established, the current task is notified when it is.
+    fn poll_connect(&mut self) -> io::Result<()> {
+        let connected = match self.state {
+            ClientState::Connecting(ref mut connecting, ref mut output) => {
+                match connecting.poll() {
+                    Ok(Async::Ready(stream)) => {
+                        Connection::new(stream).and_then(|mut connection| {
+                                                             connection.output = mem::replace(output, Vec::new());
+                                                             connection.flush()?;
+                                                             Ok(connection)
+                                                         })
+                    }
+                    Ok(Async::NotReady) => return Ok(()),
+                    Err(e) => Err(e),
+                }
+            }
+            _ => return Ok(()),
+        };
+        match connected {
+            Ok(connection) => {
+                debug!("Connected to {} over TCP", self.server);
+                self.state = ClientState::Connected(connection);
+                Ok(())
+            }
+            Err(e) => {
+                self.state = ClientState::Closed;
+                Err(e)
+            }
+        }
+    }
+
+    fn connection(&mut self) -> io::Result<&mut Connection> {
+        self.poll_connect()?;
+        match self.state {
+            ClientState::Connecting(..) => Err(io::ErrorKind::WouldBlock.into()),
+            ClientState::Connected(ref mut connection) => Ok(connection),
+            ClientState::Closed => Err(io::Error::new(io::ErrorKind::NotConnected, "connection closed")),
+        }
+    }
+}
+
+impl Transport for TcpClientTransport {
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, Endpoint)> {
+        let server = self.server;
+        let received = self.connection().and_then(|connection| connection.recv(buf));
+        if let Err(ref e) = received {
+            if e.kind() != io::ErrorKind::WouldBlock {
+                self.state = ClientState::Closed;
+            }
+        }
+        received.map(|n| (n, Endpoint::Tcp(server)))
+    }
+
+    fn send_to(&mut self, buf: &[u8], _endpoint: &Endpoint) -> io::Result<()> {
+        self.poll_connect()?;
+        let sent = match self.state {
+            ClientState::Connecting(_, ref mut output) => queue_frame(output, buf),
+            ClientState::Connected(ref mut connection) => connection.send(buf),
+            ClientState::Closed => return Err(io::Error::new(io::ErrorKind::NotConnected, "connection closed")),
+        };
+        match sent {
+            Ok(true) => Ok(()),
+            Ok(false) => {
+                debug!("Dropping datagram to {}, the connection is congested", self.server);
+                Ok(())
+            }
+            Err(e) => {
+                self.state = ClientState::Closed;
+                Err(e)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.poll_connect()?;
+        match self.state {
+            ClientState::Connected(ref mut connection) => connection.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for TcpClientTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self.state {
+            ClientState::Connecting(..) => "connecting",
+            ClientState::Connected(_) => "connected",
+            ClientState::Closed => "closed",
+        };
+        f.debug_struct("TcpClientTransport")
+         .field("server", &self.server)
+         .field("state", &state)
+         .finish()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_frames() {
+        let mut stream = Vec::new();
+        encode_frame(&mut stream, b"akarin").unwrap();
+        encode_frame(&mut stream, b"").unwrap();
+        encode_frame(&mut stream, &[7; 300]).unwrap();
+        assert_eq!(&stream[..8], &[0, 6, b'a', b'k', b'a', b'r', b'i', b'n']);
+        assert!(encode_frame(&mut Vec::new(), &[0; 70000]).is_err());
+
+        // Frames come out whole however the stream is split.
+        let mut input = Vec::new();
+        let mut buf = [0u8; 512];
+        let mut frames = Vec::new();
+        for chunk in stream.chunks(5) {
+            input.extend_from_slice(chunk);
+            while let Some(n) = decode_frame(&mut input, &mut buf).unwrap() {
+                frames.push(buf[..n].to_vec());
+            }
+        }
+        assert_eq!(frames, vec![b"akarin".to_vec(), vec![], vec![7; 300]]);
+        assert!(input.is_empty());
+
+        let mut input = vec![0x02, 0x01, 0];
+        assert!(decode_frame(&mut input, &mut buf).is_err());
+    }
+
+    #[test]
+    fn test_client_connect() {
+        let mut core = Core::new().unwrap();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap();
+        let mut client = TcpClientTransport::connect(&server, &core.handle());
+
+        let mut stream = None;
+        let mut buf = [0u8; 64];
+        let n = core.run(future::poll_fn(|| -> io::Result<Async<usize>> {
+                                             if stream.is_none() {
+                                                 // Sent before the connection is established.
+                                                 client.send_to(b"akarin", &Endpoint::Tcp(server))?;
+                                                 let (mut accepted, _) = listener.accept()?;
+                                                 accepted.write_all(&[0, 2, b'o', b'k'])?;
+                                                 stream = Some(accepted);
+                                             }
+                                             match client.recv_from(&mut buf) {
+                                                 Ok((n, endpoint)) => {
+                                                     assert_eq!(endpoint, Endpoint::Tcp(server));
+                                                     Ok(Async::Ready(n))
+                                                 }
+                                                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                                                     Ok(Async::NotReady)
+                                                 }
+                                                 Err(e) => Err(e),
+                                             }
+                                         }))
+                    .unwrap();
+        assert_eq!(&buf[..n], b"ok");
+
+        let mut frame = [0u8; 8];
+        stream.unwrap().read_exact(&mut frame).unwrap();
+        assert_eq!(&frame, &[0, 6, b'a', b'k', b'a', b'r', b'i', b'n']);
+    }
+
+    #[test]
+    fn test_server_connections() {
+        let mut core = Core::new().unwrap();
+        let address = "127.0.0.1:0".parse().unwrap();
+        let mut server = TcpServerTransport::bind(&address, 1, Duration::from_secs(60), &core.handle()).unwrap();
+        let address = server.listener.local_addr().unwrap();
+        let mut buf = [0u8; 64];
+
+        let mut first = net::TcpStream::connect(address).unwrap();
+        first.write_all(&[0, 6, b'a', b'k', b'a', b'r', b'i', b'n']).unwrap();
+        let (n, endpoint) = core.run(future::poll_fn(|| match server.recv_from(&mut buf) {
+                                                         Ok(received) => Ok(Async::Ready(received)),
+                                                         Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                                                             Ok(Async::NotReady)
+                                                         }
+                                                         Err(e) => Err(e),
+                                                     }))
+                                .unwrap();
+        assert_eq!(&buf[..n], b"akarin");
+        assert_eq!(endpoint, Endpoint::Tcp(first.local_addr().unwrap()));
+
+        // Connections beyond the maximum are closed right away.
+        let mut second = net::TcpStream::connect(address).unwrap();
+        second.set_nonblocking(true).unwrap();
+        core.run(future::poll_fn(|| -> io::Result<Async<()>> {
+                                     let _ = server.recv_from(&mut buf);
+                                     match second.read(&mut buf) {
+                                         Ok(0) => Ok(Async::Ready(())),
+                                         _ => Ok(Async::NotReady),
+                                     }
+                                 }))
+            .unwrap();
+        assert_eq!(server.connections.len(), 1);
+
+        // A disconnected connection is closed once its frames are written out.
+        let mut sent = false;
+        core.run(future::poll_fn(|| -> io::Result<Async<()>> {
+                                     if !sent {
+                                         server.send_to(b"bye", &endpoint)?;
+                                         server.disconnect(&endpoint);
+                                         sent = true;
+                                     }
+                                     server.flush()?;
+                                     Ok(if server.connections.is_empty() { Async::Ready(()) } else { Async::NotReady })
+                                 }))
+            .unwrap();
+        let mut rest = Vec::new();
+        first.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, vec![0, 3, b'b', b'y', b'e']);
+
+        // So is an idle one.
+        let _third = net::TcpStream::connect(address).unwrap();
+        core.run(future::poll_fn(|| -> io::Result<Async<()>> {
+                                     let _ = server.recv_from(&mut buf);
+                                     Ok(if server.connections.is_empty() { Async::NotReady } else { Async::Ready(()) })
+                                 }))
+            .unwrap();
+        server.idle_timeout = Duration::from_secs(0);
+        server.idle_checked -= Duration::from_secs(IDLE_CHECK_INTERVAL);
+        assert!(!core.run(future::lazy(|| Ok::<_, ()>(server.recv_from(&mut buf).is_ok()))).unwrap());
+        assert!(server.connections.is_empty());
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut core = Core::new().unwrap();
+        let address = "127.0.0.1:0".parse().unwrap();
+        let mut server = TcpServerTransport::bind(&address, 2, Duration::from_secs(60), &core.handle()).unwrap();
+        let address = server.listener.local_addr().unwrap();
+
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let mut client = net::TcpStream::connect(address).unwrap();
+            client.write_all(&[0, 1, b'a', 0, 1, b'b', 0, 1, b'c']).unwrap();
+            clients.push(client);
+        }
+        core.run(future::poll_fn(|| -> io::Result<Async<()>> {
+                                     server.accept();
+                                     Ok(if server.connections.len() < 2 { Async::NotReady } else { Async::Ready(()) })
+                                 }))
+            .unwrap();
+
+        let mut buf = [0u8; 64];
+        let mut endpoints = Vec::new();
+        core.run(future::poll_fn(|| -> io::Result<Async<()>> {
+                                     while endpoints.len() < 4 {
+                                         match server.recv_from(&mut buf) {
+                                             Ok((_, endpoint)) => endpoints.push(endpoint),
+                                             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                                                 return Ok(Async::NotReady)
+                                             }
+                                             Err(e) => return Err(e),
+                                         }
+                                     }
+                                     Ok(Async::Ready(()))
+                                 }))
+            .unwrap();
+
+        // Connections take turns, however many frames the first one has.
+        assert!(endpoints[0] != endpoints[1]);
+        assert_eq!(endpoints[0], endpoints[2]);
+        assert_eq!(endpoints[1], endpoints[3]);
+    }
+}
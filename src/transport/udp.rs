@@ -0,0 +1,27 @@
+use std::io;
+
+use tokio_core::net::UdpSocket;
+
+use super::{Endpoint, Transport};
+
+/// Datagrams sent as they are over a UDP socket.
+#[derive(Debug)]
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn new(socket: UdpSocket) -> Self {
+        UdpTransport { socket }
+    }
+}
+
+impl Transport for UdpTransport {
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, Endpoint)> {
+        self.socket.recv_from(buf).map(|(n, address)| (n, Endpoint::Udp(address)))
+    }
+
+    fn send_to(&mut self, buf: &[u8], endpoint: &Endpoint) -> io::Result<()> {
+        self.socket.send_to(buf, &endpoint.address()).map(|_| ())
+    }
+}
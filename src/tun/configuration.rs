@@ -1,6 +1,11 @@
 use std::net::Ipv4Addr;
 
 use common::error::*;
+use common::settings::Settings;
+use common::subnet::Subnet6;
+
+pub const MIN_MTU: i32 = 68;
+pub const MAX_MTU: i32 = 65535;
 
 #[derive(Clone, Default, Debug)]
 pub struct Configuration {
@@ -11,9 +16,26 @@ pub struct Configuration {
     pub netmask: Option<Ipv4Addr>,
     pub mtu: Option<i32>,
     pub enabled: bool,
+    pub addresses_v6: Vec<Subnet6>,
+    pub routes_v6: Vec<Subnet6>,
 }
 
 impl Configuration {
+    /// Read the name and the MTU of the device, addresses depend on the role of the tunnel end.
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let mut configuration = Configuration::default();
+
+        if let Some(value) = settings.get::<String>("tun")? {
+            configuration.name(&value);
+        }
+        if let Some(value) = settings.get("mtu")? {
+            check_mtu(settings, value)?;
+            configuration.mtu(value);
+        }
+
+        Ok(configuration)
+    }
+
     pub fn name(&mut self, name: &str) -> &mut Self {
         self.name = Some(name.to_string());
         self
@@ -44,6 +66,16 @@ impl Configuration {
         self
     }
 
+    pub fn address_v6(&mut self, value: Subnet6) -> &mut Self {
+        self.addresses_v6.push(value);
+        self
+    }
+
+    pub fn route_v6(&mut self, value: Subnet6) -> &mut Self {
+        self.routes_v6.push(value);
+        self
+    }
+
     pub fn up(&mut self) -> &mut Self {
         self.enabled = true;
         self
@@ -55,6 +87,13 @@ impl Configuration {
     }
 }
 
+pub fn check_mtu(settings: &Settings, mtu: i32) -> Result<()> {
+    if mtu < MIN_MTU || mtu > MAX_MTU {
+        return Err(settings.invalid("mtu", &format!("`{}` is out of range {}..{}", mtu, MIN_MTU, MAX_MTU)));
+    }
+    Ok(())
+}
+
 pub trait Configurable {
     fn from_configuration(configuration: &Configuration) -> Result<Self>
     where
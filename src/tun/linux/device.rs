@@ -1,13 +1,14 @@
 use std::{mem, ptr};
 use std::ffi::{CStr, CString};
 use std::fs::File;
-use std::io::{self, Read, Write};
-use std::net::Ipv4Addr;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
-use libc::{AF_INET, O_RDWR, SOCK_DGRAM, c_char, close, open, socket};
+use libc::{AF_INET, AF_INET6, O_NONBLOCK, O_RDWR, SOCK_DGRAM, c_char, c_int, close, open, socket};
 
 use common::error::*;
+use common::subnet::Subnet6;
 use tun::Tun;
 use tun::configuration::{Configurable, Configuration};
 use tun::sockaddr::SockAddr;
@@ -34,6 +35,37 @@ impl Device {
 
         req
     }
+
+    pub fn index(&self) -> Result<c_int> {
+        unsafe {
+            let mut req = self.request();
+
+            if siocgifindex(self.ctl.as_raw_fd(), &mut req) < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            Ok(req.ifr_ifru.ifru_ivalue)
+        }
+    }
+
+    pub unsafe fn request_v6(&self, value: Subnet6) -> Result<in6_ifreq> {
+        let mut req: in6_ifreq = mem::zeroed();
+        req.ifr6_addr.s6_addr = value.address().octets();
+        req.ifr6_prefixlen = value.prefix() as _;
+        req.ifr6_ifindex = self.index()?;
+
+        Ok(req)
+    }
+
+    /// IPv6 addresses and routes are configured through an `AF_INET6` socket.
+    fn ctl_v6() -> Result<File> {
+        let ctl = unsafe { socket(AF_INET6, SOCK_DGRAM, 0) };
+        if ctl < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(unsafe { File::from_raw_fd(ctl) })
+    }
 }
 
 impl Read for Device {
@@ -223,6 +255,67 @@ impl Tun for Device {
 
         self.set_flags(-IFF_UP)
     }
+
+    fn addresses_v6(&self) -> Result<Vec<Subnet6>> {
+        // Linux has no ioctl to list IPv6 addresses, they are exposed in `/proc/net/if_inet6` as:
+        // `<address> <index> <prefix length> <scope> <flags> <name>`, all numbers in hex.
+        let mut addresses = Vec::new();
+        for line in BufReader::new(File::open("/proc/net/if_inet6")?).lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 6 || fields[5] != self.name {
+                continue;
+            }
+
+            let address = u128::from_str_radix(fields[0], 16)?;
+            let prefix = u8::from_str_radix(fields[2], 16)?;
+            addresses.push(Subnet6::new(Ipv6Addr::from(address), prefix)?);
+        }
+
+        Ok(addresses)
+    }
+    fn add_address_v6(&mut self, value: Subnet6) -> Result<()> {
+        let ctl = Self::ctl_v6()?;
+        unsafe {
+            let req = self.request_v6(value)?;
+
+            if siocsifaddr6(ctl.as_raw_fd(), &req) < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            Ok(())
+        }
+    }
+    fn remove_address_v6(&mut self, value: Subnet6) -> Result<()> {
+        let ctl = Self::ctl_v6()?;
+        unsafe {
+            let req = self.request_v6(value)?;
+
+            if siocdifaddr6(ctl.as_raw_fd(), &req) < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            Ok(())
+        }
+    }
+
+    fn add_route_v6(&mut self, destination: Subnet6) -> Result<()> {
+        let ctl = Self::ctl_v6()?;
+        unsafe {
+            let mut rt: in6_rtmsg = mem::zeroed();
+            rt.rtmsg_dst.s6_addr = destination.network().octets();
+            rt.rtmsg_dst_len = destination.prefix() as _;
+            rt.rtmsg_metric = 1;
+            rt.rtmsg_flags = RTF_UP;
+            rt.rtmsg_ifindex = self.index()?;
+
+            if siocaddrt6(ctl.as_raw_fd(), &rt) < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            Ok(())
+        }
+    }
 }
 
 impl Configurable for Device {
@@ -241,7 +334,8 @@ impl Configurable for Device {
             None => None,
         };
 
-        let tun = unsafe { open(b"/dev/net/tun\0".as_ptr() as *const _, O_RDWR) };
+        // The device is driven by the event loop, reads and writes must never block.
+        let tun = unsafe { open(b"/dev/net/tun\0".as_ptr() as *const _, O_RDWR | O_NONBLOCK) };
         if tun < 0 {
             return Err(io::Error::last_os_error().into());
         }
@@ -304,6 +398,14 @@ impl Configurable for Device {
 
         self.set_enabled(configuration.enabled)?;
 
+        for address in configuration.addresses_v6.iter() {
+            self.add_address_v6(*address)?;
+        }
+
+        for route in configuration.routes_v6.iter() {
+            self.add_route_v6(*route)?;
+        }
+
         Ok(())
     }
 }
@@ -1,4 +1,4 @@
-use libc::{c_char, c_int, c_short, c_uchar, c_uint, c_ulong, c_ushort, c_void, sockaddr};
+use libc::{c_char, c_int, c_short, c_uchar, c_uint, c_ulong, c_ushort, c_void, in6_addr, sockaddr};
 
 pub const IFNAMSIZ: usize = 16;
 
@@ -8,6 +8,8 @@ pub const IFF_RUNNING: c_short = 0x40;
 pub const IFF_TUN: c_short = 0x0001;
 pub const IFF_NO_PI: c_short = 0x1000;
 
+pub const RTF_UP: c_uint = 0x0001;
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct ifmap {
@@ -127,6 +129,29 @@ pub struct ifreq {
     pub ifr_ifru: _ifr_ifru,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct in6_ifreq {
+    pub ifr6_addr: in6_addr,
+    pub ifr6_prefixlen: c_uint,
+    pub ifr6_ifindex: c_int,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct in6_rtmsg {
+    pub rtmsg_dst: in6_addr,
+    pub rtmsg_src: in6_addr,
+    pub rtmsg_gateway: in6_addr,
+    pub rtmsg_type: c_uint,
+    pub rtmsg_dst_len: c_ushort,
+    pub rtmsg_src_len: c_ushort,
+    pub rtmsg_metric: c_uint,
+    pub rtmsg_info: c_ulong,
+    pub rtmsg_flags: c_uint,
+    pub rtmsg_ifindex: c_int,
+}
+
 ioctl!(bad read siocgifflags with 0x8913; ifreq);
 ioctl!(bad write siocsifflags with 0x8914; ifreq);
 
@@ -147,6 +172,12 @@ ioctl!(bad write siocsifmtu with 0x8922; ifreq);
 
 ioctl!(bad write siocsifname with 0x8923; ifreq);
 
+ioctl!(bad read siocgifindex with 0x8933; ifreq);
+
+ioctl!(bad write siocsifaddr6 with 0x8916; in6_ifreq);
+ioctl!(bad write siocdifaddr6 with 0x8936; in6_ifreq);
+ioctl!(bad write siocaddrt6 with 0x890b; in6_rtmsg);
+
 ioctl!(write tunsetiff with b'T', 202; c_int);
 ioctl!(write tunsetpersist with b'T', 203; c_int);
 ioctl!(write tunsetowner with b'T', 204; c_int);
@@ -6,9 +6,11 @@ use std::net::Ipv4Addr;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::str::FromStr;
 
-use libc::{AF_INET, SOCK_DGRAM, c_char, c_void, close, connect, getsockopt, sockaddr, socket, socklen_t};
+use libc::{AF_INET, F_SETFL, O_NONBLOCK, SOCK_DGRAM, c_char, c_void, close, connect, fcntl, getsockopt, sockaddr,
+           socket, socklen_t};
 
 use common::error::*;
+use common::subnet::Subnet6;
 use tun::Tun;
 use tun::configuration::{Configurable, Configuration};
 use tun::sockaddr::SockAddr;
@@ -97,7 +99,7 @@ impl Write for Device {
         };
         let mut wbuf = Vec::with_capacity(buf.len() + IP_HEADER_LEN);
 
-        match buf[0] & 0xF {
+        match buf[0] >> 4 {
             IPV4 => wbuf.extend_from_slice(&IPV4_HEADER),
             IPV6 => wbuf.extend_from_slice(&IPV6_HEADER),
             _ => {}
@@ -288,6 +290,21 @@ impl Tun for Device {
 
         self.set_flags(-IFF_UP)
     }
+
+    // IPv6 on utun needs `SIOCAIFADDR_IN6` and a routing socket, neither of them is wired up yet.
+    fn addresses_v6(&self) -> Result<Vec<Subnet6>> {
+        Err(ErrorKind::NotSupported.into())
+    }
+    fn add_address_v6(&mut self, _value: Subnet6) -> Result<()> {
+        Err(ErrorKind::NotSupported.into())
+    }
+    fn remove_address_v6(&mut self, _value: Subnet6) -> Result<()> {
+        Err(ErrorKind::NotSupported.into())
+    }
+
+    fn add_route_v6(&mut self, _destination: Subnet6) -> Result<()> {
+        Err(ErrorKind::NotSupported.into())
+    }
 }
 
 impl Configurable for Device {
@@ -360,6 +377,11 @@ impl Configurable for Device {
             return Err(io::Error::last_os_error().into());
         }
 
+        // The device is driven by the event loop, reads and writes must never block.
+        if unsafe { fcntl(tun, F_SETFL, O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
         let ctl = unsafe { socket(AF_INET, SOCK_DGRAM, 0) };
         if ctl < 0 {
             return Err(io::Error::last_os_error().into());
@@ -399,6 +421,14 @@ impl Configurable for Device {
 
         self.set_enabled(configuration.enabled)?;
 
+        for address in configuration.addresses_v6.iter() {
+            self.add_address_v6(*address)?;
+        }
+
+        for route in configuration.routes_v6.iter() {
+            self.add_route_v6(*route)?;
+        }
+
         Ok(())
     }
 }
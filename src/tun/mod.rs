@@ -29,6 +29,7 @@ use std::net::Ipv4Addr;
 use mio::event::Evented;
 
 use common::error::*;
+use common::subnet::Subnet6;
 
 pub trait Tun: Read + Write + Debug + Evented {
     fn name(&self) -> &str;
@@ -52,4 +53,10 @@ pub trait Tun: Read + Write + Debug + Evented {
     fn set_flags(&mut self, value: i16) -> Result<()>;
 
     fn set_enabled(&mut self, value: bool) -> Result<()>;
+
+    fn addresses_v6(&self) -> Result<Vec<Subnet6>>;
+    fn add_address_v6(&mut self, value: Subnet6) -> Result<()>;
+    fn remove_address_v6(&mut self, value: Subnet6) -> Result<()>;
+
+    fn add_route_v6(&mut self, destination: Subnet6) -> Result<()>;
 }